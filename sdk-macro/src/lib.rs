@@ -6,9 +6,11 @@ use syn::{Attribute, Ident, ItemMod, parse_macro_input, parse_quote, visit_mut::
 #[derive(Clone)]
 struct CanisterVisitor {
     actions: BTreeMap<String, (String, bool)>,
+    default_action: Option<(String, bool)>,
     pools: Option<Ident>,
     hook_present: bool,
     upgrade_declared: bool,
+    upgrade_auto: bool,
     storages: BTreeMap<u8, (proc_macro2::TokenStream, proc_macro2::TokenStream)>,
 }
 
@@ -21,6 +23,8 @@ mod keywords {
     syn::custom_keyword!(action);
     syn::custom_keyword!(memory);
     syn::custom_keyword!(name);
+    syn::custom_keyword!(version);
+    syn::custom_keyword!(auto);
 }
 
 struct StorageDeclAttr {
@@ -62,8 +66,32 @@ impl syn::parse::Parse for StorageDeclAttr {
     }
 }
 
+#[derive(Default)]
+struct ExchangeAttr {
+    version: Option<syn::LitStr>,
+}
+
+impl syn::parse::Parse for ExchangeAttr {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        if input.is_empty() {
+            return Ok(Self::default());
+        }
+        let _ = input.parse::<keywords::version>()?;
+        let _ = input.parse::<syn::Token![=]>()?;
+        let version = input.parse()?;
+        let _ = input.parse::<Option<syn::Token![,]>>()?;
+        if !input.is_empty() {
+            return Err(input.error("Unexpected argument, only `version` is supported"));
+        }
+        Ok(Self {
+            version: Some(version),
+        })
+    }
+}
+
 enum ActionDeclAttr {
     Named { value: syn::LitStr },
+    Default,
     Unnamed,
 }
 
@@ -89,6 +117,9 @@ impl syn::parse::Parse for ActionDeclAttr {
             Ok(Self::Named {
                 value: inside.parse()?,
             })
+        } else if lookahead.peek(syn::Token![default]) {
+            let _ = inside.parse::<syn::Token![default]>()?;
+            Ok(Self::Default)
         } else {
             Err(lookahead.error())
         }
@@ -99,9 +130,11 @@ impl CanisterVisitor {
     fn new() -> Self {
         CanisterVisitor {
             actions: BTreeMap::new(),
+            default_action: None,
             pools: None,
             hook_present: false,
             upgrade_declared: false,
+            upgrade_auto: false,
             storages: BTreeMap::new(),
         }
     }
@@ -139,6 +172,13 @@ impl CanisterVisitor {
                     (func.sig.ident.to_string(), func.sig.asyncness.is_some()),
                 );
             }
+            ActionDeclAttr::Default => {
+                if self.default_action.is_some() {
+                    panic!("Only one function can have the #[action(default)] attribute");
+                }
+                self.default_action =
+                    Some((func.sig.ident.to_string(), func.sig.asyncness.is_some()));
+            }
         }
     }
 
@@ -151,6 +191,12 @@ impl CanisterVisitor {
         let storage_decl =
             syn::parse2::<StorageDeclAttr>(tokens).expect("Failed to parse storage attribute");
         let id = storage_decl.memory_id;
+        if !ty.generics.params.is_empty() {
+            panic!(
+                "#[storage] type {} can't be generic, spell out the types of the storage instead",
+                ty.ident
+            );
+        }
         let storage_name = to_upper_snake_case(&ty.ident.to_string());
         let storage_name = format_ident!("__{}", storage_name);
         let storage_ty = format_ident!("{}", ty.ident);
@@ -225,8 +271,14 @@ impl VisitMut for CanisterVisitor {
         if let Some(_attr) = item.attrs.iter().find(|a| a.path().is_ident("hook")) {
             self.hook_present = true;
         }
-        if let Some(_attr) = item.attrs.iter().find(|a| a.path().is_ident("upgrade")) {
+        if let Some(attr) = item.attrs.iter().find(|a| a.path().is_ident("upgrade")) {
             self.upgrade_declared = true;
+            if let syn::Meta::List(_) = attr.meta {
+                attr.parse_args::<keywords::auto>().expect(
+                    "Failed to parse upgrade attribute, expected #[upgrade] or #[upgrade(auto)]",
+                );
+                self.upgrade_auto = true;
+            }
         }
         syn::visit_mut::visit_item_impl_mut(self, item);
     }
@@ -240,8 +292,15 @@ impl VisitMut for CanisterVisitor {
 }
 
 /// REE exchange entrypoint.
+///
+/// The version of the exchange returned by the generated `exchange_version` query can be given
+/// as `#[exchange(version = "1.2.3")]`, defaulting to the version of the crate.
+///
+/// The generated `restore_after_upgrade` of the `Pools` struct shall be called in the `post_upgrade`
+/// of the exchange, unless it's generated by `#[upgrade(auto)]`.
 #[proc_macro_attribute]
-pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn exchange(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let exchange_attr = parse_macro_input!(attr as ExchangeAttr);
     let mut input_mod = parse_macro_input!(item as ItemMod);
     let mut visitor = CanisterVisitor::new();
     visitor.visit_item_mod_mut(&mut input_mod);
@@ -260,12 +319,25 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
             .map(|(action, (func, is_async))| {
                 let call = format_ident!("{}", func);
                 if *is_async {
-                    quote! { #action => #call(&psbt, args).await, }
+                    quote! { #action => #call(psbt, args).await, }
                 } else {
-                    quote! { #action => #call(&psbt, args), }
+                    quote! { #action => #call(psbt, args), }
                 }
             })
             .collect::<Vec<_>>();
+        let fallback = match visitor.default_action {
+            Some((ref func, is_async)) => {
+                let call = format_ident!("{}", func);
+                if is_async {
+                    quote! { _ => #call(psbt, action, args).await, }
+                } else {
+                    quote! { _ => #call(psbt, action, args), }
+                }
+            }
+            None => quote! {
+                _ => ::ree_exchange_sdk::ActionResult::<<#pools as ::ree_exchange_sdk::Pools>::PoolState>::Err(::ree_exchange_sdk::error::Error::UnknownAction),
+            },
+        };
 
         if !visitor.hook_present {
             items.push(parse_quote! {
@@ -291,7 +363,28 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
                 }
 
                 fn get(address: &::std::string::String) -> ::std::option::Option<::ree_exchange_sdk::Pool<<#pools as ::ree_exchange_sdk::Pools>::PoolState>> {
-                    self::__CURRENT_POOLS.with_borrow(|p| p.get(address))
+                    ::ree_exchange_sdk::replayed_pool(address).or_else(|| self::__CURRENT_POOLS.with_borrow(|p| p.get(address)))
+                }
+
+                fn get_many(addresses: &[::std::string::String]) -> ::std::vec::Vec<::std::option::Option<::ree_exchange_sdk::Pool<<#pools as ::ree_exchange_sdk::Pools>::PoolState>>> {
+                    self::__CURRENT_POOLS.with_borrow(|p| {
+                        addresses
+                            .iter()
+                            .map(|address| ::ree_exchange_sdk::replayed_pool(address).or_else(|| p.get(address)))
+                            .collect()
+                    })
+                }
+
+                fn confirmations_of(txid: &::ree_exchange_sdk::types::Txid) -> ::std::option::Option<u32> {
+                    self::__BLOCKS.with_borrow(|blocks| ::ree_exchange_sdk::queries::confirmations(blocks, txid))
+                }
+
+                fn find_by_utxo(outpoint: &str) -> ::std::option::Option<(::std::string::String, ::ree_exchange_sdk::Pool<<#pools as ::ree_exchange_sdk::Pools>::PoolState>)> {
+                    self::__CURRENT_POOLS.with_borrow(|pools| ::ree_exchange_sdk::queries::pool_by_utxo::<#pools>(pools, outpoint))
+                }
+
+                fn detect_reorg(new_block: &::ree_exchange_sdk::types::NewBlockInfo) -> ::ree_exchange_sdk::ReorgOutcome {
+                    self::__BLOCKS.with_borrow(|blocks| ::ree_exchange_sdk::states::detect_reorg::<#pools>(blocks, new_block))
                 }
 
                 fn insert(pool: ::ree_exchange_sdk::Pool<<#pools as ::ree_exchange_sdk::Pools>::PoolState>) {
@@ -312,60 +405,126 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
                     )));
                     ::ree_exchange_sdk::iterator::<#pools>(memory)
                 }
+
+                fn iter_range(start: ::std::string::String, end: ::std::string::String) -> ::ree_exchange_sdk::iter::PoolIterator<#pools> {
+                    let memory = __MEMORY_MANAGER.with(|m| m.borrow().get(::ic_stable_structures::memory_manager::MemoryId::new(
+                        <#pools as ::ree_exchange_sdk::Pools>::POOL_STATE_MEMORY
+                    )));
+                    ::ree_exchange_sdk::range_iterator::<#pools>(memory, start, end)
+                }
+            }
+        });
+
+        items.push(parse_quote! {
+            async fn __dispatch_action(
+                action: &str,
+                psbt: &::ree_exchange_sdk::types::bitcoin::Psbt,
+                args: ::ree_exchange_sdk::ActionArgs,
+            ) -> ::ree_exchange_sdk::ActionResult::<<#pools as ::ree_exchange_sdk::Pools>::PoolState> {
+                match action {
+                    #(#branch)*
+                    #fallback
+                }
+            }
+        });
+
+        items.push(parse_quote! {
+            impl #pools {
+                /// Runs the action against a pool holding only `base_state`, e.g. to reproduce a failed transaction.
+                /// The stored pool is left untouched, while the action runs `PoolStorageAccess::get` returns the
+                /// replayed pool for its address and `execute_tx` on it is rejected as if it was being executed.
+                pub async fn replay_action(
+                    action: &str,
+                    psbt: &::ree_exchange_sdk::types::bitcoin::Psbt,
+                    args: ::ree_exchange_sdk::ActionArgs,
+                    base_state: <#pools as ::ree_exchange_sdk::Pools>::PoolState,
+                ) -> ::ree_exchange_sdk::ActionResult::<<#pools as ::ree_exchange_sdk::Pools>::PoolState> {
+                    if self::__GUARDS.with_borrow(|guards| guards.contains_key(&args.intention.pool_address)) {
+                        return ::core::result::Result::Err(::ree_exchange_sdk::error::Error::PoolBeingExecuted);
+                    }
+                    let metadata = self::__CURRENT_POOLS
+                        .with_borrow(|pools| pools.get(&args.intention.pool_address))
+                        .ok_or(::ree_exchange_sdk::error::Error::PoolNotFound)?
+                        .metadata()
+                        .clone();
+                    let mut replayed = ::ree_exchange_sdk::Pool::new(metadata);
+                    replayed.states_mut().push(base_state);
+                    let _replaying = ::ree_exchange_sdk::replay_pool(replayed)?;
+                    self::__dispatch_action(action, psbt, args).await
+                }
             }
         });
 
         items.push(parse_quote! {
             #[::ic_cdk::update]
             pub async fn execute_tx(args: ::ree_exchange_sdk::types::exchange_interfaces::ExecuteTxArgs) -> ::core::result::Result<String, String> {
-                ::ree_exchange_sdk::ensure_access::<#pools>()?;
+                ::ree_exchange_sdk::ensure_access::<#pools>(self::__ORCHESTRATOR.with_borrow(|o| *o.get()))?;
+                self::__arm_maintenance();
                 let mut psbt = args.psbt()?;
                 let args = <::ree_exchange_sdk::ActionArgs as ::std::convert::TryFrom<_>>::try_from(args).map_err(|e| e.to_string())?;
+                args.ensure_network(&psbt, <#pools as ::ree_exchange_sdk::Pools>::network()).map_err(|e| e.to_string())?;
+                self::__BLOCKS.with_borrow(|blocks| {
+                    ::ree_exchange_sdk::states::ensure_fresh_chain::<#pools>(blocks, ::ic_cdk::api::time() / 1_000_000_000)
+                }).map_err(|e| e.to_string())?;
                 let pool_address = args.intention.pool_address.clone();
-                let _guard = self::__ExecuteTxGuard::new(pool_address.clone())
+                let guard = self::__ExecuteTxGuard::new(args.affected_pools(), ::ic_cdk::api::time() / 1_000_000_000)
                     .ok_or(::ree_exchange_sdk::error::Error::PoolBeingExecuted.to_string())?;
                 let txid = args.txid.clone();
                 let inputs = args.intention.pool_outpoints()
                     .map_err(|_| ::ree_exchange_sdk::error::Error::IllegalPsbt.to_string())?;
                 let action = args.intention.action.clone();
                 let mut pool = self::__CURRENT_POOLS.with_borrow(|pools| pools.get(&pool_address)).ok_or(::ree_exchange_sdk::error::Error::PoolNotFound.to_string())?;
+                self::__TX_RECORDS.with_borrow(|unconfirmed| {
+                    ::ree_exchange_sdk::states::ensure_unexecuted(unconfirmed, &txid, &pool_address, args.is_reapply)?;
+                    ::ree_exchange_sdk::states::ensure_unconfirmed_capacity::<#pools>(unconfirmed, &pool_address, args.is_reapply)
+                }).map_err(|e| e.to_string())?;
+                if <#pools as ::ree_exchange_sdk::Pools>::reject_dust_pool_outputs() {
+                    ::ree_exchange_sdk::ensure_no_dust_pool_outputs::<#pools>(&psbt, &pool_address)?;
+                }
                 let new_nonce = args.intention.nonce;
                 if args.is_reapply {
-                    pool.truncate(new_nonce)?;
+                    let committed = pool.reapply(txid, new_nonce)?;
                     self::__CURRENT_POOLS.with_borrow_mut(|pools| {
                         pools.insert(pool_address.clone(), pool.clone());
                     });
+                    if committed {
+                        // the state is already committed, only the signatures are required
+                        ::ree_exchange_sdk::schnorr::sign_p2tr_inputs(
+                            &mut psbt,
+                            &inputs,
+                            <#pools as ::ree_exchange_sdk::Pools>::network(),
+                            pool.metadata().key_derivation_path.clone(),
+                        ).await?;
+                        guard.ensure_held()?;
+                        self::__TX_RECORDS.with_borrow_mut(|unconfirmed| {
+                            ::ree_exchange_sdk::states::record_tx(unconfirmed, txid, pool_address.clone());
+                        });
+                        return ::core::result::Result::<String, String>::Ok(psbt.serialize_hex());
+                    }
                 }
-                let pool_info = pool.get_pool_info();
-                if pool_info.nonce + 1 != new_nonce {
-                    return ::core::result::Result::<String, String>::Err(::ree_exchange_sdk::error::Error::NonceExpired.to_string());
-                }
-                let result: ::ree_exchange_sdk::ActionResult::<<#pools as ::ree_exchange_sdk::Pools>::PoolState> = match action.as_str() {
-                    #(#branch)*
-                    _ => ::ree_exchange_sdk::ActionResult::<<#pools as ::ree_exchange_sdk::Pools>::PoolState>::Err(::ree_exchange_sdk::error::Error::UnknownAction),
-                };
+                pool.check_nonce(new_nonce)?;
+                let result = self::__dispatch_action(action.as_str(), &psbt, args).await;
                 match result {
                     ::ree_exchange_sdk::ActionResult::<<#pools as ::ree_exchange_sdk::Pools>::PoolState>::Ok(mut r) => {
+                        r.set_nonce(new_nonce);
+                        pool.states_mut().push(r);
+                        if let ::core::result::Result::Err(e) = pool.try_to_bytes() {
+                            <#pools as ::ree_exchange_sdk::Hook>::on_serialize_error(pool_address.clone(), txid, e.clone());
+                            return ::core::result::Result::<String, String>::Err(::ree_exchange_sdk::error::Error::SerializationFailed(e).to_string());
+                        }
                         ::ree_exchange_sdk::schnorr::sign_p2tr_inputs(
                             &mut psbt,
                             &inputs,
                             <#pools as ::ree_exchange_sdk::Pools>::network(),
                             pool.metadata().key_derivation_path.clone(),
                         ).await?;
-                        r.set_nonce(new_nonce);
-                        pool.states_mut().push(r);
+                        // the state read before the awaits is stale if the lock was lost meanwhile
+                        guard.ensure_held()?;
                         self::__CURRENT_POOLS.with_borrow_mut(|pools| {
                             pools.insert(pool_address.clone(), pool);
                         });
                         self::__TX_RECORDS.with_borrow_mut(|unconfirmed| {
-                            let mut record = unconfirmed.get(&txid).unwrap_or(::ree_exchange_sdk::types::TxRecord {
-                                txid: txid.clone(),
-                                pools: vec![pool_address.clone()],
-                            });
-                            if !record.pools.contains(&pool_address) {
-                                record.pools.push(pool_address.clone());
-                            }
-                            unconfirmed.insert(txid, record);
+                            ::ree_exchange_sdk::states::record_tx(unconfirmed, txid, pool_address.clone());
                         });
                         ::core::result::Result::<String, String>::Ok(psbt.serialize_hex())
                     }
@@ -380,14 +539,18 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
             #[::ic_cdk::query]
             pub fn get_pool_list() -> ::ree_exchange_sdk::types::exchange_interfaces::GetPoolListResponse {
                 self::__CURRENT_POOLS.with_borrow(|pools| {
-                    pools.iter()
-                        .map(|e| e.into_pair())
-                        .map(|(_, p)| p.get_pool_basic())
-                        .collect::<Vec<_>>()
+                    ::ree_exchange_sdk::queries::pool_list::<#pools>(pools)
                 })
             }
         });
 
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn get_pool_addresses() -> ::std::vec::Vec<::std::string::String> {
+                self::__CURRENT_POOLS.with_borrow(|pools| pools.keys().collect::<Vec<_>>())
+            }
+        });
+
         items.push(parse_quote! {
             #[::ic_cdk::query]
             pub fn get_pool_info(
@@ -399,15 +562,162 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
             }
         });
 
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn get_pool_info_by_key(
+                key: ::ree_exchange_sdk::types::Pubkey,
+            ) -> ::ree_exchange_sdk::types::exchange_interfaces::GetPoolInfoResponse {
+                self::__CURRENT_POOLS.with_borrow(|pools| {
+                    ::ree_exchange_sdk::queries::pool_info_by_key::<#pools>(pools, &key)
+                })
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn get_pool_states(
+                address: ::std::string::String,
+                limit: u32,
+            ) -> ::std::vec::Vec<::ree_exchange_sdk::StateInfo> {
+                self::__CURRENT_POOLS.with_borrow(|pools| {
+                    ::ree_exchange_sdk::queries::pool_states::<#pools>(pools, &address, limit)
+                })
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn get_top_pools(
+                by: ::ree_exchange_sdk::types::CoinId,
+                limit: u32,
+            ) -> ::std::vec::Vec<(::ree_exchange_sdk::types::exchange_interfaces::PoolBasic, u128)> {
+                self::__CURRENT_POOLS.with_borrow(|pools| {
+                    ::ree_exchange_sdk::queries::top_pools::<#pools>(pools, by, limit)
+                })
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn get_total_reserves() -> ::std::vec::Vec<::ree_exchange_sdk::types::CoinBalance> {
+                self::__CURRENT_POOLS.with_borrow(|pools| {
+                    ::ree_exchange_sdk::queries::total_reserves::<#pools>(pools)
+                })
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn get_all_utxos(
+                after: ::std::option::Option<::std::string::String>,
+                limit: u32,
+            ) -> ::std::vec::Vec<(::std::string::String, ::ree_exchange_sdk::types::Utxo)> {
+                self::__CURRENT_POOLS.with_borrow(|pools| {
+                    ::ree_exchange_sdk::queries::all_utxos::<#pools>(pools, after, limit)
+                })
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn audit_pool_addresses() -> ::std::vec::Vec<::std::string::String> {
+                self::__CURRENT_POOLS.with_borrow(|pools| {
+                    ::ree_exchange_sdk::queries::audit_pool_addresses::<#pools>(pools)
+                })
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn status() -> ::ree_exchange_sdk::ExchangeStatus {
+                self::__CURRENT_POOLS.with_borrow(|pools| {
+                    self::__BLOCKS.with_borrow(|blocks| {
+                        self::__TX_RECORDS.with_borrow(|unconfirmed| {
+                            ::ree_exchange_sdk::queries::exchange_status::<#pools>(
+                                pools,
+                                blocks,
+                                unconfirmed,
+                                ::ic_cdk::api::time() / 1_000_000_000,
+                            )
+                        })
+                    })
+                })
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn get_network() -> ::ree_exchange_sdk::Network {
+                <#pools as ::ree_exchange_sdk::Pools>::network()
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn sdk_version() -> ::std::string::String {
+                ::ree_exchange_sdk::VERSION.to_string()
+            }
+        });
+
+        let exchange_version = match exchange_attr.version {
+            Some(ref version) => quote! { #version },
+            None => quote! { env!("CARGO_PKG_VERSION") },
+        };
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn exchange_version() -> ::std::string::String {
+                #exchange_version.to_string()
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn get_reorg_diagnostics() -> ::ree_exchange_sdk::ReorgDiagnostics {
+                self::__BLOCKS.with_borrow(|blocks| {
+                    self::__TX_RECORDS.with_borrow(|unconfirmed| {
+                        ::ree_exchange_sdk::queries::reorg_diagnostics::<#pools>(blocks, unconfirmed)
+                    })
+                })
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn get_tx_status(txid: ::ree_exchange_sdk::types::Txid) -> ::ree_exchange_sdk::TxStatus {
+                self::__TX_RECORDS.with_borrow(|unconfirmed| {
+                    self::__BLOCKS.with_borrow(|blocks| {
+                        self::__EVENTS.with_borrow(|events| {
+                            ::ree_exchange_sdk::queries::tx_status(unconfirmed, blocks, events, &txid)
+                        })
+                    })
+                })
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn get_events(
+                after_seq: u64,
+                limit: u32,
+            ) -> ::std::vec::Vec<::ree_exchange_sdk::ExchangeEvent> {
+                self::__EVENTS.with_borrow(|events| {
+                    ::ree_exchange_sdk::queries::events(events, after_seq, limit)
+                })
+            }
+        });
+
         items.push(parse_quote! {
             #[::ic_cdk::update]
             pub fn rollback_tx(
                 args: ::ree_exchange_sdk::types::exchange_interfaces::RollbackTxArgs,
             ) -> ::ree_exchange_sdk::types::exchange_interfaces::RollbackTxResponse {
-                ::ree_exchange_sdk::ensure_access::<#pools>()?;
+                ::ree_exchange_sdk::ensure_access::<#pools>(self::__ORCHESTRATOR.with_borrow(|o| *o.get()))?;
+                self::__arm_maintenance();
                 self::__TX_RECORDS.with_borrow_mut(|transactions| {
                     self::__CURRENT_POOLS.with_borrow_mut(|pools| {
-                        ::ree_exchange_sdk::states::reject_tx::<#pools>(transactions, pools, args)
+                        self::__EVENTS.with_borrow_mut(|events| {
+                            ::ree_exchange_sdk::states::reject_tx::<#pools>(transactions, pools, events, args)
+                        })
                     })
                 })
             }
@@ -418,16 +728,44 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
             pub fn new_block(
                 args: ::ree_exchange_sdk::types::exchange_interfaces::NewBlockArgs,
             ) -> ::ree_exchange_sdk::types::exchange_interfaces::NewBlockResponse {
-                ::ree_exchange_sdk::ensure_access::<#pools>()?;
+                ::ree_exchange_sdk::ensure_access::<#pools>(self::__ORCHESTRATOR.with_borrow(|o| *o.get()))?;
+                self::__arm_maintenance();
+                self::__accept_new_block(args)
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::update]
+            pub fn new_blocks(
+                args: ::std::vec::Vec<::ree_exchange_sdk::types::exchange_interfaces::NewBlockArgs>,
+            ) -> ::ree_exchange_sdk::types::exchange_interfaces::NewBlockResponse {
+                ::ree_exchange_sdk::ensure_access::<#pools>(self::__ORCHESTRATOR.with_borrow(|o| *o.get()))?;
+                self::__arm_maintenance();
+                // in order as separate `new_block` calls, stopping at the first rejected block
+                // while the blocks before it are kept
+                for block in args {
+                    self::__accept_new_block(block)?;
+                }
+                Ok(())
+            }
+        });
+
+        items.push(parse_quote! {
+            fn __accept_new_block(
+                args: ::ree_exchange_sdk::types::exchange_interfaces::NewBlockArgs,
+            ) -> ::ree_exchange_sdk::types::exchange_interfaces::NewBlockResponse {
                 let block = self::__TX_RECORDS.with_borrow_mut(|unconfirmed| {
                     self::__BLOCKS.with_borrow_mut(|blocks| {
                         self::__GLOBAL_STATE.with_borrow_mut(|state| {
-                            ::ree_exchange_sdk::states::confirm_txs::<#pools>(
-                                state,
-                                blocks,
-                                unconfirmed,
-                                args,
-                            )
+                            self::__EVENTS.with_borrow_mut(|events| {
+                                ::ree_exchange_sdk::states::confirm_txs::<#pools>(
+                                    state,
+                                    blocks,
+                                    unconfirmed,
+                                    events,
+                                    args,
+                                )
+                            })
                         })
                     })
                 })?;
@@ -435,12 +773,15 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
                     self::__CURRENT_POOLS.with_borrow_mut(|pools| {
                         self::__BLOCKS.with_borrow_mut(|blocks| {
                             self::__GLOBAL_STATE.with_borrow_mut(|state| {
-                                ::ree_exchange_sdk::states::accept_block::<#pools>(
-                                    state,
-                                    blocks,
-                                    pools,
-                                    block.clone(),
-                                )
+                                self::__EVENTS.with_borrow_mut(|events| {
+                                    ::ree_exchange_sdk::states::accept_block::<#pools>(
+                                        state,
+                                        blocks,
+                                        pools,
+                                        events,
+                                        block.clone(),
+                                    )
+                                })
                             })
                         })
                     })?;
@@ -451,19 +792,221 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
         });
 
         items.push(parse_quote! {
-            struct __ExecuteTxGuard(::std::string::String);
+            #[::ic_cdk::update]
+            pub fn set_log_level(level: ::ree_exchange_sdk::log::LogLevel) -> ::core::result::Result<(), String> {
+                ::ree_exchange_sdk::ensure_admin()?;
+                ::ree_exchange_sdk::log::set_log_level(level);
+                Ok(())
+            }
         });
 
         items.push(parse_quote! {
-            impl __ExecuteTxGuard {
-                pub fn new(pool_address: ::std::string::String) -> ::std::option::Option<Self> {
-                    __GUARDS.with(|guards| {
-                        if guards.borrow().contains(&pool_address) {
-                            return None;
+            #[::ic_cdk::query]
+            pub fn get_schnorr_config() -> ::ree_exchange_sdk::schnorr::SchnorrConfig {
+                self::__SCHNORR_CONFIG.with_borrow(|c| *c.get())
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::update]
+            pub fn set_schnorr_config(config: ::ree_exchange_sdk::schnorr::SchnorrConfig) -> ::core::result::Result<(), String> {
+                ::ree_exchange_sdk::ensure_admin()?;
+                self::__SCHNORR_CONFIG.with_borrow_mut(|c| c.set(config));
+                ::ree_exchange_sdk::schnorr::set_schnorr_config(config);
+                Ok(())
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::update]
+            pub fn set_orchestrator(orchestrator: ::core::option::Option<::candid::Principal>) -> ::core::result::Result<(), String> {
+                ::ree_exchange_sdk::ensure_admin()?;
+                if <#pools as ::ree_exchange_sdk::Pools>::network() == ::ree_exchange_sdk::Network::Bitcoin {
+                    return ::core::result::Result::Err("set_orchestrator is not allowed on the Bitcoin mainnet".to_string());
+                }
+                self::__ORCHESTRATOR.with_borrow_mut(|o| o.set(orchestrator));
+                Ok(())
+            }
+        });
+
+        items.push(parse_quote! {
+            fn __arm_maintenance() {
+                if !<#pools as ::ree_exchange_sdk::Pools>::enable_periodic_maintenance() {
+                    return;
+                }
+                // timers don't survive upgrades, so arm it lazily from the update calls
+                if self::__MAINTENANCE_ARMED.with(|armed| armed.replace(true)) {
+                    return;
+                }
+                ::ree_exchange_sdk::timers::set_timer_interval(
+                    ::core::time::Duration::from_secs(
+                        <#pools as ::ree_exchange_sdk::Pools>::maintenance_interval_secs(),
+                    ),
+                    || {
+                        let now = ::ic_cdk::api::time() / 1_000_000_000;
+                        let r = self::__CURRENT_POOLS.with_borrow_mut(|pools| {
+                            self::__BLOCKS.with_borrow_mut(|blocks| {
+                                self::__GLOBAL_STATE.with_borrow_mut(|state| {
+                                    self::__EVENTS.with_borrow_mut(|events| {
+                                        self::__GUARDS.with_borrow_mut(|guards| {
+                                            ::ree_exchange_sdk::states::run_maintenance::<#pools>(
+                                                state,
+                                                blocks,
+                                                pools,
+                                                events,
+                                                guards,
+                                                now,
+                                            )
+                                        })
+                                    })
+                                })
+                            })
+                        });
+                        match r {
+                            ::core::result::Result::Ok(released) => {
+                                if !released.is_empty() {
+                                    self::__persist_locks();
+                                }
+                            }
+                            ::core::result::Result::Err(e) => {
+                                ::ree_exchange_sdk::log!(::ree_exchange_sdk::log::LogLevel::Error, "periodic maintenance failed: {}", e);
+                            }
                         }
-                        guards.borrow_mut().insert(pool_address.clone());
-                        return Some(__ExecuteTxGuard(pool_address));
+                    },
+                );
+            }
+        });
+
+        items.push(parse_quote! {
+            impl #pools {
+                pub fn arm_maintenance() {
+                    self::__arm_maintenance();
+                }
+
+                /// Restores the heap state lost by upgrades, i.e. the signing configuration from the stable memory,
+                /// and arms the periodic maintenance. It's called by the `post_upgrade` hook of `#[upgrade(auto)]`,
+                /// otherwise it shall be called in the `post_upgrade` of the exchange.
+                pub fn restore_after_upgrade() {
+                    ::ree_exchange_sdk::schnorr::set_schnorr_config(self::__SCHNORR_CONFIG.with_borrow(|c| *c.get()));
+                    self::__arm_maintenance();
+                }
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::update]
+            pub fn rollback_pool_to_nonce(address: ::std::string::String, nonce: u64) -> ::core::result::Result<(), String> {
+                ::ree_exchange_sdk::ensure_admin()?;
+                self::__CURRENT_POOLS.with_borrow_mut(|pools| {
+                    self::__TX_RECORDS.with_borrow_mut(|unconfirmed| {
+                        self::__BLOCKS.with_borrow_mut(|blocks| {
+                            self::__EVENTS.with_borrow_mut(|events| {
+                                ::ree_exchange_sdk::states::rollback_pool_to_nonce::<#pools>(
+                                    pools,
+                                    unconfirmed,
+                                    blocks,
+                                    events,
+                                    address,
+                                    nonce,
+                                )
+                            })
+                        })
                     })
+                })
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::update]
+            pub fn restore_pool(address: ::std::string::String, states_json: ::std::string::String) -> ::core::result::Result<(), String> {
+                ::ree_exchange_sdk::ensure_admin()?;
+                if <#pools as ::ree_exchange_sdk::Pools>::network() == ::ree_exchange_sdk::Network::Bitcoin {
+                    return ::core::result::Result::Err("restore_pool is not allowed on the Bitcoin mainnet".to_string());
+                }
+                if self::__GUARDS.with_borrow(|guards| guards.contains_key(&address)) {
+                    return ::core::result::Result::Err(format!("Pool {} is being executed", address));
+                }
+                // the pool states aren't candid types, so they're given as a JSON array
+                let states = ::ree_exchange_sdk::serde_json::from_str::<::std::vec::Vec<<#pools as ::ree_exchange_sdk::Pools>::PoolState>>(&states_json)
+                    .map_err(|e| format!("Invalid states: {}", e))?;
+                self::__CURRENT_POOLS.with_borrow_mut(|pools| {
+                    self::__TX_RECORDS.with_borrow(|unconfirmed| {
+                        ::ree_exchange_sdk::states::restore_pool::<#pools>(pools, unconfirmed, address, states)
+                    })
+                })
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::update]
+            pub fn force_unlock_pool(address: ::std::string::String) -> ::core::result::Result<(), String> {
+                ::ree_exchange_sdk::ensure_admin()?;
+                let result = self::__GUARDS.with_borrow_mut(|guards| {
+                    guards
+                        .remove(&address)
+                        .map(|locked_at| ::ree_exchange_sdk::log!(::ree_exchange_sdk::log::LogLevel::Info, "force unlocked pool {} locked at {}", address, locked_at))
+                        .ok_or(format!("Pool {} is not locked", address))
+                });
+                self::__persist_locks();
+                result
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::update]
+            pub fn reset_exchange() -> ::core::result::Result<(), String> {
+                ::ree_exchange_sdk::ensure_admin()?;
+                if <#pools as ::ree_exchange_sdk::Pools>::network() == ::ree_exchange_sdk::Network::Bitcoin {
+                    return ::core::result::Result::Err("reset_exchange is not allowed on the Bitcoin mainnet".to_string());
+                }
+                self::__CURRENT_POOLS.with_borrow_mut(|pools| pools.clear_new());
+                self::__BLOCKS.with_borrow_mut(|blocks| blocks.clear_new());
+                self::__TX_RECORDS.with_borrow_mut(|unconfirmed| unconfirmed.clear_new());
+                self::__GLOBAL_STATE.with_borrow_mut(|state| state.clear_new());
+                self::__EVENTS.with_borrow_mut(|events| events.clear_new());
+                self::__GUARDS.with_borrow_mut(|guards| guards.clear());
+                self::__persist_locks();
+                ::ree_exchange_sdk::log!(::ree_exchange_sdk::log::LogLevel::Warn, "exchange reset, all pools, blocks and transactions are cleared");
+                Ok(())
+            }
+        });
+
+        items.push(parse_quote! {
+            #[::ic_cdk::query]
+            pub fn get_locked_pools() -> ::std::vec::Vec<(::std::string::String, u64)> {
+                self::__GUARDS.with_borrow(|guards| guards.iter().map(|(k, v)| (k.clone(), *v)).collect())
+            }
+        });
+
+        items.push(parse_quote! {
+            fn __persist_locks() {
+                self::__GUARDS.with_borrow(|guards| {
+                    self::__LOCKS.with_borrow_mut(|locks| {
+                        ::ree_exchange_sdk::persist_locks::<#pools>(locks, guards)
+                    })
+                });
+            }
+        });
+
+        items.push(parse_quote! {
+            struct __ExecuteTxGuard(::std::vec::Vec<::std::string::String>, u64);
+        });
+
+        items.push(parse_quote! {
+            impl __ExecuteTxGuard {
+                pub fn new(pool_addresses: ::std::vec::Vec<::std::string::String>, now: u64) -> ::std::option::Option<Self> {
+                    let guard = __GUARDS.with_borrow_mut(|guards| {
+                        ::ree_exchange_sdk::try_lock_pools::<#pools>(guards, &pool_addresses, now)
+                            .then(|| __ExecuteTxGuard(pool_addresses, now))
+                    });
+                    __persist_locks();
+                    guard
+                }
+
+                pub fn ensure_held(&self) -> ::core::result::Result<(), String> {
+                    __GUARDS.with_borrow(|guards| ::ree_exchange_sdk::holds_locks(guards, &self.0, self.1))
+                        .then_some(())
+                        .ok_or(::ree_exchange_sdk::error::Error::PoolLockLost.to_string())
                 }
             }
         });
@@ -472,8 +1015,14 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
             impl ::std::ops::Drop for __ExecuteTxGuard {
                 fn drop(&mut self) {
                     __GUARDS.with_borrow_mut(|guards| {
-                        guards.remove(&self.0);
+                        for pool_address in self.0.iter() {
+                            // the lock might be taken over after it became stale
+                            if guards.get(pool_address) == ::std::option::Option::Some(&self.1) {
+                                guards.remove(pool_address);
+                            }
+                        }
                     });
+                    __persist_locks();
                 }
             }
         });
@@ -490,8 +1039,24 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
                     )
                 );
 
-                static __GUARDS: ::core::cell::RefCell<::std::collections::HashSet<::std::string::String>> =
-                    ::core::cell::RefCell::new(::std::collections::HashSet::new());
+                static __GUARDS: ::core::cell::RefCell<::std::collections::HashMap<::std::string::String, u64>> =
+                    ::core::cell::RefCell::new(__LOCKS.with_borrow(|locks| ::ree_exchange_sdk::restore_locks::<#pools>(locks)));
+
+                static __LOCKS: ::core::cell::RefCell<
+                    ::ic_stable_structures::StableBTreeMap<
+                        ::std::string::String,
+                        u64,
+                        ::ic_stable_structures::memory_manager::VirtualMemory<::ic_stable_structures::DefaultMemoryImpl>
+                    >
+                > = ::core::cell::RefCell::new(
+                    ::ic_stable_structures::StableBTreeMap::init(
+                        __MEMORY_MANAGER.with(|m| m.borrow().get(::ic_stable_structures::memory_manager::MemoryId::new(
+                            104
+                        ))),
+                    )
+                );
+
+                static __MAINTENANCE_ARMED: ::core::cell::Cell<bool> = const { ::core::cell::Cell::new(false) };
 
                 static __BLOCKS: ::core::cell::RefCell<
                     ::ic_stable_structures::StableBTreeMap<
@@ -519,21 +1084,63 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
                         ))),
                     )
                 );
-                static __CURRENT_POOLS: ::core::cell::RefCell<
+                static __ORCHESTRATOR: ::core::cell::RefCell<
+                    ::ic_stable_structures::Cell<
+                        ::std::option::Option<::candid::Principal>,
+                        ::ic_stable_structures::memory_manager::VirtualMemory<::ic_stable_structures::DefaultMemoryImpl>
+                    >
+                > = ::core::cell::RefCell::new(
+                    ::ic_stable_structures::Cell::init(
+                        __MEMORY_MANAGER.with(|m| m.borrow().get(::ic_stable_structures::memory_manager::MemoryId::new(
+                            102
+                        ))),
+                        ::std::option::Option::None,
+                    )
+                );
+                static __SCHNORR_CONFIG: ::core::cell::RefCell<
+                    ::ic_stable_structures::Cell<
+                        ::ree_exchange_sdk::schnorr::SchnorrConfig,
+                        ::ic_stable_structures::memory_manager::VirtualMemory<::ic_stable_structures::DefaultMemoryImpl>
+                    >
+                > = ::core::cell::RefCell::new(
+                    ::ic_stable_structures::Cell::init(
+                        __MEMORY_MANAGER.with(|m| m.borrow().get(::ic_stable_structures::memory_manager::MemoryId::new(
+                            105
+                        ))),
+                        ::core::default::Default::default(),
+                    )
+                );
+                static __EVENTS: ::core::cell::RefCell<
                     ::ic_stable_structures::StableBTreeMap<
-                        ::std::string::String,
-                        ::ree_exchange_sdk::Pool<
-                            <#pools as ::ree_exchange_sdk::Pools>::PoolState
-                        >,
+                        u64,
+                        ::ree_exchange_sdk::ExchangeEvent,
                         ::ic_stable_structures::memory_manager::VirtualMemory<::ic_stable_structures::DefaultMemoryImpl>
                     >
                 > = ::core::cell::RefCell::new(
                     ::ic_stable_structures::StableBTreeMap::init(
                         __MEMORY_MANAGER.with(|m| m.borrow().get(::ic_stable_structures::memory_manager::MemoryId::new(
-                            <#pools as ::ree_exchange_sdk::Pools>::POOL_STATE_MEMORY
+                            103
                         ))),
                     )
                 );
+                static __CURRENT_POOLS: ::core::cell::RefCell<
+                    ::ic_stable_structures::StableBTreeMap<
+                        ::std::string::String,
+                        ::ree_exchange_sdk::Pool<
+                            <#pools as ::ree_exchange_sdk::Pools>::PoolState
+                        >,
+                        ::ic_stable_structures::memory_manager::VirtualMemory<::ic_stable_structures::DefaultMemoryImpl>
+                    >
+                > = {
+                    ::ree_exchange_sdk::set_pool_codec(<#pools as ::ree_exchange_sdk::Pools>::POOL_CODEC);
+                    ::core::cell::RefCell::new(
+                        ::ic_stable_structures::StableBTreeMap::init(
+                            __MEMORY_MANAGER.with(|m| m.borrow().get(::ic_stable_structures::memory_manager::MemoryId::new(
+                                <#pools as ::ree_exchange_sdk::Pools>::POOL_STATE_MEMORY
+                            ))),
+                        )
+                    )
+                };
                 static __GLOBAL_STATE: ::core::cell::RefCell<
                     ::ic_stable_structures::StableBTreeMap<
                         u32,
@@ -563,6 +1170,16 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
                         if block_id >= 100 {
                             panic!("Memory id for block state upgrade must be between 0 and 99");
                         }
+                        if pool_id == block_id {
+                            panic!("Memory ids for pool state and block state upgrade must be different");
+                        }
+                        let current = [
+                            <#pools as ::ree_exchange_sdk::Pools>::POOL_STATE_MEMORY,
+                            <#pools as ::ree_exchange_sdk::Pools>::BLOCK_STATE_MEMORY,
+                        ];
+                        if current.contains(&pool_id) || current.contains(&block_id) {
+                            panic!("Memory ids for upgrade must be different from the ones of the current states");
+                        }
                         let memory_id = ::ic_stable_structures::memory_manager::MemoryId::new(pool_id);
                         let memory = __MEMORY_MANAGER.with(|m| m.borrow().get(memory_id));
                         let mut pool_storage = ::ic_stable_structures::StableBTreeMap::<
@@ -604,8 +1221,38 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
                         pool_storage.clear_new();
                         block_storage.clear_new();
                     }
+
+                    pub fn validate_upgrade() -> ::ree_exchange_sdk::UpgradeValidation {
+                        let (pool_memory, block_memory) = __MEMORY_MANAGER.with(|m| {
+                            let m = m.borrow();
+                            (
+                                m.get(::ic_stable_structures::memory_manager::MemoryId::new(
+                                    <#pools as ::ree_exchange_sdk::Upgrade<#pools>>::POOL_STATE_MEMORY
+                                )),
+                                m.get(::ic_stable_structures::memory_manager::MemoryId::new(
+                                    <#pools as ::ree_exchange_sdk::Upgrade<#pools>>::BLOCK_STATE_MEMORY
+                                )),
+                            )
+                        });
+                        ::ree_exchange_sdk::validate_upgrade::<#pools, #pools>(pool_memory, block_memory)
+                    }
+                }
+            });
+            items.push(parse_quote! {
+                #[::ic_cdk::query]
+                pub fn validate_upgrade() -> ::ree_exchange_sdk::UpgradeValidation {
+                    #pools::validate_upgrade()
                 }
             });
+            if visitor.upgrade_auto {
+                items.push(parse_quote! {
+                    #[::ic_cdk::post_upgrade]
+                    fn __post_upgrade() {
+                        #pools::upgrade();
+                        #pools::restore_after_upgrade();
+                    }
+                });
+            }
         }
 
         items.push(parse_quote! {
@@ -616,6 +1263,30 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
                 fn with_mut<F, R>(f: F) -> R
                 where
                     F: FnOnce(&mut S::Type) -> R;
+
+                fn range_with<K, V, B, F, R>(range: B, f: F) -> R
+                where
+                    S: ::ree_exchange_sdk::store::StorageType<
+                        Type = ::ic_stable_structures::BTreeMap<K, V, ::ree_exchange_sdk::Memory>,
+                    >,
+                    K: ::ic_stable_structures::Storable + ::core::cmp::Ord + ::core::clone::Clone,
+                    V: ::ic_stable_structures::Storable,
+                    B: ::core::ops::RangeBounds<K>,
+                    F: FnOnce(&mut dyn ::core::iter::Iterator<Item = (K, V)>) -> R,
+                {
+                    Self::with(|map| ::ree_exchange_sdk::store::range_with(map, range, f))
+                }
+
+                fn drain_while<T, P>(pred: P) -> ::std::vec::Vec<T>
+                where
+                    S: ::ree_exchange_sdk::store::StorageType<
+                        Type = ::ic_stable_structures::MinHeap<T, ::ree_exchange_sdk::Memory>,
+                    >,
+                    T: ::ic_stable_structures::Storable + ::core::cmp::Ord + ::core::clone::Clone,
+                    P: FnMut(&T) -> bool,
+                {
+                    Self::with_mut(|heap| ::ree_exchange_sdk::store::drain_while(heap, pred))
+                }
             }
         });
         for access in storage_access {
@@ -633,6 +1304,10 @@ pub fn exchange(_attr: TokenStream, item: TokenStream) -> TokenStream {
 /// Action entrypoint. The macro could be
 /// `#[action(name = "my_action")]` or `#[action("my_action")]` or `#[action]`.
 /// The functions shall have signature `fn(&bitcoin::Psbt, ActionArgs) -> ActionResult<Pools::PoolState>`
+///
+/// At most one function could be marked as `#[action(default)]`, which is invoked for any unmatched action
+/// instead of rejecting it with `UnknownAction`. It shall have signature
+/// `fn(&bitcoin::Psbt, &str, ActionArgs) -> ActionResult<Pools::PoolState>` receiving the action name.
 #[proc_macro_attribute]
 pub fn action(_attr: TokenStream, item: TokenStream) -> TokenStream {
     item
@@ -649,6 +1324,10 @@ pub fn pools(_attr: TokenStream, item: TokenStream) -> TokenStream {
 /// #[storage(memory = 3)]
 /// pub type MyStorage = ree_exchange_sdk::store::StableBTreeMap<String, String>;
 /// ```
+///
+/// The alias shall be a non-generic type with a unique name in the exchange mod, resolving to one
+/// of the wrappers in `ree_exchange_sdk::store`. The wrapped types could be paths with nested
+/// generics, e.g. `StableCell<types::CoinBalances>` or `StableBTreeMap<String, Vec<u8>>`.
 #[proc_macro_attribute]
 pub fn storage(_attr: TokenStream, item: TokenStream) -> TokenStream {
     item
@@ -677,7 +1356,123 @@ pub fn hook(_attr: TokenStream, item: TokenStream) -> TokenStream {
     item
 }
 
-/// Upgrade attribute for pool state migration.
+/// The fields read by `#[derive(StateView)]`, in the order of `StateInfo`.
+const STATE_VIEW_FIELDS: [&str; 6] = [
+    "txid",
+    "nonce",
+    "coin_reserved",
+    "btc_reserved",
+    "utxos",
+    "attributes",
+];
+
+/// Implements `StateView` for a struct with the fields `txid`, `nonce`, `coin_reserved`,
+/// `btc_reserved`, `utxos` and `attributes`, of the same types as in `StateInfo`.
+/// A field with another name could be marked as one of them, e.g. `#[state_view(nonce)]`.
+/// ```ignore
+/// #[derive(Clone, Debug, Deserialize, Serialize, StateView)]
+/// pub struct MyPoolState {
+///     pub txid: Txid,
+///     #[state_view(nonce)]
+///     pub seq: u64,
+///     pub coin_reserved: Vec<CoinBalance>,
+///     pub btc_reserved: u64,
+///     pub utxos: Vec<Utxo>,
+///     pub attributes: String,
+///     pub fee_rate: u64,
+/// }
+/// ```
+#[proc_macro_derive(StateView, attributes(state_view))]
+pub fn derive_state_view(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as syn::DeriveInput);
+    expand_state_view(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand_state_view(input: &syn::DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let syn::Data::Struct(syn::DataStruct {
+        fields: syn::Fields::Named(fields),
+        ..
+    }) = &input.data
+    else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "StateView can only be derived for structs with named fields",
+        ));
+    };
+    let mut mapped = BTreeMap::<String, Ident>::new();
+    for field in fields.named.iter() {
+        let ident = field.ident.clone().expect("named field");
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|a| a.path().is_ident("state_view"))
+        {
+            let name = attr.parse_args::<Ident>()?;
+            if !STATE_VIEW_FIELDS.contains(&name.to_string().as_str()) {
+                return Err(syn::Error::new_spanned(
+                    &name,
+                    format!("expected one of {}", STATE_VIEW_FIELDS.join(", ")),
+                ));
+            }
+            if mapped.insert(name.to_string(), ident.clone()).is_some() {
+                return Err(syn::Error::new_spanned(
+                    &name,
+                    format!("`{}` is marked more than once", name),
+                ));
+            }
+        }
+    }
+    for field in fields.named.iter() {
+        let ident = field.ident.clone().expect("named field");
+        let name = ident.to_string();
+        if STATE_VIEW_FIELDS.contains(&name.as_str()) && !mapped.contains_key(&name) {
+            mapped.insert(name, ident);
+        }
+    }
+    let missing = STATE_VIEW_FIELDS
+        .iter()
+        .filter(|name| !mapped.contains_key(**name))
+        .copied()
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            format!(
+                "missing the field(s) {}, add them or mark the existing ones with `#[state_view(..)]`",
+                missing.join(", ")
+            ),
+        ));
+    }
+    let [txid, nonce, coin_reserved, btc_reserved, utxos, attributes] =
+        STATE_VIEW_FIELDS.map(|name| mapped[name].clone());
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::ree_exchange_sdk::StateView for #name #ty_generics #where_clause {
+            fn inspect_state(&self) -> ::ree_exchange_sdk::StateInfo {
+                ::ree_exchange_sdk::StateInfo {
+                    txid: ::core::clone::Clone::clone(&self.#txid),
+                    nonce: self.#nonce,
+                    coin_reserved: ::core::clone::Clone::clone(&self.#coin_reserved),
+                    btc_reserved: self.#btc_reserved,
+                    utxos: ::core::clone::Clone::clone(&self.#utxos),
+                    attributes: ::core::clone::Clone::clone(&self.#attributes),
+                }
+            }
+
+            fn set_nonce(&mut self, nonce: u64) {
+                self.#nonce = nonce;
+            }
+        }
+    })
+}
+
+/// Upgrade attribute for pool state migration, marked on the `Upgrade` impl block of the `Pools` struct.
+///
+/// With `#[upgrade(auto)]` a `post_upgrade` hook calling `upgrade()` and `restore_after_upgrade()` is generated,
+/// so the exchange must not define its own. Otherwise both shall be called in the `post_upgrade` of the exchange.
 #[proc_macro_attribute]
 pub fn upgrade(_attr: TokenStream, item: TokenStream) -> TokenStream {
     item
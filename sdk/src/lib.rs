@@ -70,7 +70,7 @@
 //!     ) -> ActionResult<DummyPoolState> {
 //!         let pool = DummyPools::get(&args.intention.pool_address)
 //!             .ok_or(Error::PoolNotFound)?;
-//!         let mut state = pool.last_state().cloned().unwrap_or_default();
+//!         let mut state = pool.last_state_or_default();
 //!         // do some checks...
 //!         state.nonce = state.nonce + 1;
 //!         state.txid = args.txid.clone();
@@ -95,18 +95,29 @@
 //! ic_cdk::export_candid!();
 //!```
 
+// the paths generated by the derive macros
+#[cfg(test)]
+extern crate self as ree_exchange_sdk;
+
+pub mod log;
+#[doc(hidden)]
+pub mod queries;
+pub mod runes;
 #[doc(hidden)]
 pub mod schnorr;
 #[doc(hidden)]
 pub mod states;
 pub mod store;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 pub mod prelude {
     pub use crate::*;
     pub use ree_exchange_sdk_macro::*;
 }
 
 use crate::types::{
-    CoinBalance, Intention, IntentionSet, Pubkey, TxRecord, Txid, Utxo, exchange_interfaces::*,
+    CoinBalance, CoinBalances, CoinId, Intention, IntentionSet, Pubkey, TxRecord, Txid, Utxo,
+    exchange_interfaces::*,
 };
 use candid::{CandidType, Principal};
 use ic_stable_structures::{
@@ -117,6 +128,18 @@ use serde::{Deserialize, Serialize};
 /// essential types of REE
 pub use ree_types as types;
 
+#[doc(hidden)]
+pub use ic_cdk_timers as timers;
+
+#[doc(hidden)]
+pub use serde_json;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use states::ReorgEngine;
+
+/// The version of the SDK the exchange is built with, returned by the generated `sdk_version` query.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 pub mod error {
     pub const POOL_NOT_FOUND: u16 = 101;
     pub const NONCE_EXPIRED: u16 = 102;
@@ -126,6 +149,17 @@ pub mod error {
     pub const TXID_NOT_FOUND: u16 = 106;
     pub const NONCE_NOT_FOUND: u16 = 107;
     pub const MISSING_CALLER: u16 = 108;
+    pub const NETWORK_MISMATCH: u16 = 109;
+    pub const STALE_CHAIN_STATE: u16 = 110;
+    pub const INSUFFICIENT_RESERVES: u16 = 111;
+    pub const DUPLICATE_TXID: u16 = 112;
+    pub const SERIALIZATION_FAILED: u16 = 113;
+    pub const SIGNING_FAILED: u16 = 114;
+    pub const ACCESS_DENIED: u16 = 115;
+    pub const REORG_UNRECOVERABLE: u16 = 116;
+    pub const FEE_TOO_HIGH: u16 = 117;
+    pub const UNCONFIRMED_LIMIT_REACHED: u16 = 118;
+    pub const POOL_LOCK_LOST: u16 = 119;
 
     #[derive(Clone, Debug, PartialEq, Eq)]
     pub enum Error {
@@ -137,9 +171,57 @@ pub mod error {
         TxidNotFound,
         NonceNotFound,
         MissingCallerPrincipal,
+        NetworkMismatch(String),
+        StaleChainState,
+        InsufficientReserves {
+            coin: crate::types::CoinId,
+            required: u128,
+            available: u128,
+        },
+        DuplicateTxid,
+        SerializationFailed(String),
+        SigningFailed(String),
+        AccessDenied,
+        ReorgUnrecoverable,
+        FeeTooHigh {
+            fee: u64,
+            max: u64,
+        },
+        UnconfirmedLimitReached {
+            max: usize,
+        },
+        PoolLockLost,
         Custom(u16, String),
     }
 
+    impl Error {
+        /// Returns the numeric code prefixed to the message, e.g. `101` for `PoolNotFound`.
+        pub fn code(&self) -> u16 {
+            match self {
+                Error::PoolNotFound => POOL_NOT_FOUND,
+                Error::NonceExpired => NONCE_EXPIRED,
+                Error::UnknownAction => UNKNOWN_ACTION,
+                Error::IllegalPsbt => ILLEGAL_PSBT,
+                Error::PoolBeingExecuted => POOL_BEING_EXECUTED,
+                Error::TxidNotFound => TXID_NOT_FOUND,
+                Error::NonceNotFound => NONCE_NOT_FOUND,
+                Error::MissingCallerPrincipal => MISSING_CALLER,
+                Error::NetworkMismatch(_) => NETWORK_MISMATCH,
+                Error::StaleChainState => STALE_CHAIN_STATE,
+                Error::InsufficientReserves { .. } => INSUFFICIENT_RESERVES,
+                Error::DuplicateTxid => DUPLICATE_TXID,
+                Error::SerializationFailed(_) => SERIALIZATION_FAILED,
+                Error::SigningFailed(_) => SIGNING_FAILED,
+                Error::AccessDenied => ACCESS_DENIED,
+                Error::ReorgUnrecoverable => REORG_UNRECOVERABLE,
+                Error::FeeTooHigh { .. } => FEE_TOO_HIGH,
+                Error::UnconfirmedLimitReached { .. } => UNCONFIRMED_LIMIT_REACHED,
+                Error::PoolLockLost => POOL_LOCK_LOST,
+                Error::Custom(code, _) => code % 100 + 200,
+            }
+        }
+    }
+
     impl std::fmt::Display for Error {
         fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
             match self {
@@ -155,12 +237,70 @@ pub mod error {
                 Error::MissingCallerPrincipal => {
                     write!(f, "{}:Missing caller principal", MISSING_CALLER)
                 }
+                Error::NetworkMismatch(addr) => {
+                    write!(
+                        f,
+                        "{}:Address {} is not on the network",
+                        NETWORK_MISMATCH, addr
+                    )
+                }
+                Error::StaleChainState => write!(f, "{}:Stale chain state", STALE_CHAIN_STATE),
+                Error::InsufficientReserves {
+                    coin,
+                    required,
+                    available,
+                } => write!(
+                    f,
+                    "{}:Insufficient reserves of {}: required {}, available {}, shortfall {}",
+                    INSUFFICIENT_RESERVES,
+                    coin,
+                    required,
+                    available,
+                    required.saturating_sub(*available)
+                ),
+                Error::DuplicateTxid => {
+                    write!(f, "{}:Transaction already executed", DUPLICATE_TXID)
+                }
+                Error::SerializationFailed(e) => {
+                    write!(f, "{}:Serialization failed: {}", SERIALIZATION_FAILED, e)
+                }
+                Error::SigningFailed(e) => write!(f, "{}:Signing failed: {}", SIGNING_FAILED, e),
+                Error::AccessDenied => write!(f, "{}:Access denied", ACCESS_DENIED),
+                Error::ReorgUnrecoverable => {
+                    write!(f, "{}:Unrecoverable reorg detected", REORG_UNRECOVERABLE)
+                }
+                Error::FeeTooHigh { fee, max } => write!(
+                    f,
+                    "{}:Fee of {} sats exceeds the limit of {} sats",
+                    FEE_TOO_HIGH, fee, max
+                ),
+                Error::UnconfirmedLimitReached { max } => write!(
+                    f,
+                    "{}:The pool already has {} unconfirmed transactions",
+                    UNCONFIRMED_LIMIT_REACHED, max
+                ),
+                Error::PoolLockLost => write!(
+                    f,
+                    "{}:Pool lock was released or taken over during the execution",
+                    POOL_LOCK_LOST
+                ),
                 Error::Custom(code, msg) => write!(f, "{}:{}", code % 100 + 200, msg),
             }
         }
     }
+
+    impl std::error::Error for Error {}
+
+    impl From<Error> for String {
+        fn from(e: Error) -> Self {
+            e.to_string()
+        }
+    }
 }
 
+/// The typed errors of the SDK, converted into `String` by the methods returning `Result<_, String>`.
+pub use error::Error as SdkError;
+
 #[doc(hidden)]
 pub type BlockStateStorage<S> = BTreeMap<u32, GlobalStateWrapper<S>, Memory>;
 #[doc(hidden)]
@@ -171,6 +311,10 @@ pub type BlockStorage = BTreeMap<u32, Block, Memory>;
 pub type UnconfirmedTxStorage = BTreeMap<Txid, TxRecord, Memory>;
 #[doc(hidden)]
 pub type PoolStorage<S> = BTreeMap<String, Pool<S>, Memory>;
+#[doc(hidden)]
+pub type EventStorage = BTreeMap<u64, ExchangeEvent, Memory>;
+#[doc(hidden)]
+pub type LockStorage = BTreeMap<String, u64, Memory>;
 
 /// The network enum defines the networks supported by the exchange.
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Copy)]
@@ -190,8 +334,61 @@ impl Into<crate::types::bitcoin::Network> for Network {
     }
 }
 
+impl Network {
+    /// Returns `true` if the given address is a valid address on this network.
+    pub fn is_valid_address(&self, address: &str) -> bool {
+        parse_address(address, *self).is_ok()
+    }
+}
+
+/// Parses the address and checks it belongs to `network`, e.g. before paying to an `OutputCoin.to`.
+/// A mainnet address is rejected on `Testnet4` and vice versa, `Devnet` accepts testnet and regtest addresses.
+pub fn parse_address(
+    address: &str,
+    network: Network,
+) -> Result<crate::types::bitcoin::Address, String> {
+    use std::str::FromStr;
+    let unchecked = crate::types::bitcoin::Address::from_str(address)
+        .map_err(|e| format!("Invalid address {}: {}", address, e))?;
+    if network == Network::Devnet
+        && unchecked.is_valid_for_network(crate::types::bitcoin::Network::Regtest)
+    {
+        return Ok(unchecked.assume_checked());
+    }
+    unchecked
+        .require_network(network.into())
+        .map_err(|_| error::Error::NetworkMismatch(address.to_string()).to_string())
+}
+
+/// Checks the outputs of the PSBT paying to `pool_address` hold at least `P::dust_threshold()` sats,
+/// e.g. the pool change, since a transaction with a dust output is non-standard and won't be relayed.
+pub fn ensure_no_dust_pool_outputs<P: Pools>(
+    psbt: &crate::types::bitcoin::Psbt,
+    pool_address: &str,
+) -> Result<(), String> {
+    let script_pubkey = parse_address(pool_address, P::network())?.script_pubkey();
+    let threshold = P::dust_threshold();
+    for (vout, output) in psbt.unsigned_tx.output.iter().enumerate() {
+        if output.script_pubkey == script_pubkey && output.value.to_sat() < threshold {
+            return Err(format!(
+                "{}: the output {} of {} sats to the pool is below the dust threshold {}",
+                error::Error::IllegalPsbt,
+                vout,
+                output.value.to_sat(),
+                threshold
+            ));
+        }
+    }
+    Ok(())
+}
+
 #[doc(hidden)]
-pub fn ensure_access<P: Pools>() -> Result<(), String> {
+pub fn ensure_access<P: Pools>(orchestrator: Option<Principal>) -> Result<(), String> {
+    if let Some(orchestrator) = orchestrator {
+        return (orchestrator == ic_cdk::api::msg_caller())
+            .then_some(())
+            .ok_or(error::Error::AccessDenied.into());
+    }
     match P::network() {
         Network::Bitcoin => crate::types::orchestrator_interfaces::ensure_orchestrator(),
         Network::Testnet4 => crate::types::orchestrator_interfaces::ensure_testnet4_orchestrator(),
@@ -199,6 +396,244 @@ pub fn ensure_access<P: Pools>() -> Result<(), String> {
     }
 }
 
+/// Returns the candid service definition of the REE standard methods generated by `#[exchange]`,
+/// i.e. `execute_tx`, `new_block`, `rollback_tx`, `get_pool_list` and `get_pool_info`.
+///
+/// The types are derived from the same Rust types the generated methods use, so the tooling
+/// could merge it into the service definition exported by `ic_cdk::export_candid!`.
+pub fn candid_service_fragment() -> String {
+    use candid::types::{FuncMode, Function, Type, TypeInner, internal::TypeContainer};
+
+    let mut env = TypeContainer::new();
+    let method = |name: &str, args: Vec<Type>, rets: Vec<Type>, modes: Vec<FuncMode>| {
+        (
+            name.to_string(),
+            Type::from(TypeInner::Func(Function { args, rets, modes })),
+        )
+    };
+    let mut service = vec![
+        method(
+            "execute_tx",
+            vec![env.add::<ExecuteTxArgs>()],
+            vec![env.add::<Result<String, String>>()],
+            vec![],
+        ),
+        method(
+            "new_block",
+            vec![env.add::<NewBlockArgs>()],
+            vec![env.add::<NewBlockResponse>()],
+            vec![],
+        ),
+        method(
+            "rollback_tx",
+            vec![env.add::<RollbackTxArgs>()],
+            vec![env.add::<RollbackTxResponse>()],
+            vec![],
+        ),
+        method(
+            "get_pool_list",
+            vec![],
+            vec![env.add::<GetPoolListResponse>()],
+            vec![FuncMode::Query],
+        ),
+        method(
+            "get_pool_info",
+            vec![env.add::<GetPoolInfoArgs>()],
+            vec![env.add::<GetPoolInfoResponse>()],
+            vec![FuncMode::Query],
+        ),
+    ];
+    service.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let actor = Some(TypeInner::Service(service).into());
+    candid::pretty::candid::compile(&env.env, &actor)
+}
+
+#[doc(hidden)]
+pub fn ensure_admin() -> Result<(), String> {
+    ic_cdk::api::is_controller(&ic_cdk::api::msg_caller())
+        .then_some(())
+        .ok_or(error::Error::AccessDenied.into())
+}
+
+#[doc(hidden)]
+pub fn try_lock_pool<P: Pools>(
+    locks: &mut std::collections::HashMap<String, u64>,
+    address: &String,
+    now: u64,
+) -> bool {
+    if let Some(locked_at) = locks.get(address) {
+        match P::execute_lock_timeout_secs() {
+            Some(timeout) if now.saturating_sub(*locked_at) > timeout => {
+                crate::log!(
+                    log::LogLevel::Warn,
+                    "pool {} locked since {} is stale, taking over the lock",
+                    address,
+                    locked_at
+                );
+            }
+            _ => return false,
+        }
+    }
+    locks.insert(address.clone(), now);
+    true
+}
+
+/// Releases the pool locks older than `Pools::execute_lock_timeout_secs`, e.g. those left by
+/// trapped executions, returns the addresses of the released pools.
+#[doc(hidden)]
+pub fn release_stale_locks<P: Pools>(
+    locks: &mut std::collections::HashMap<String, u64>,
+    now: u64,
+) -> Vec<String> {
+    let Some(timeout) = P::execute_lock_timeout_secs() else {
+        return vec![];
+    };
+    let mut stale = locks
+        .iter()
+        .filter(|(_, locked_at)| now.saturating_sub(**locked_at) > timeout)
+        .map(|(address, _)| address.clone())
+        .collect::<Vec<_>>();
+    stale.sort();
+    for address in stale.iter() {
+        if let Some(locked_at) = locks.remove(address) {
+            crate::log!(
+                log::LogLevel::Warn,
+                "pool {} locked since {} is stale, releasing the lock",
+                address,
+                locked_at
+            );
+        }
+    }
+    stale
+}
+
+/// Loads the persisted pool locks, or none if `Pools::persistent_locks` is disabled.
+#[doc(hidden)]
+pub fn restore_locks<P: Pools>(stable: &LockStorage) -> std::collections::HashMap<String, u64> {
+    if P::persistent_locks() {
+        stable.iter().map(|entry| entry.into_pair()).collect()
+    } else {
+        std::collections::HashMap::new()
+    }
+}
+
+/// Mirrors the pool locks into the stable memory if `Pools::persistent_locks` is enabled.
+#[doc(hidden)]
+pub fn persist_locks<P: Pools>(
+    stable: &mut LockStorage,
+    locks: &std::collections::HashMap<String, u64>,
+) {
+    if !P::persistent_locks() {
+        // drop the locks persisted before the option was disabled
+        if !stable.is_empty() {
+            stable.clear_new();
+        }
+        return;
+    }
+    let stale = stable
+        .keys()
+        .filter(|address| !locks.contains_key(address))
+        .collect::<Vec<_>>();
+    for address in stale {
+        stable.remove(&address);
+    }
+    for (address, locked_at) in locks.iter() {
+        if stable.get(address) != Some(*locked_at) {
+            stable.insert(address.clone(), *locked_at);
+        }
+    }
+}
+
+/// Locks all the distinct `addresses` or none of them.
+#[doc(hidden)]
+pub fn try_lock_pools<P: Pools>(
+    locks: &mut std::collections::HashMap<String, u64>,
+    addresses: &[String],
+    now: u64,
+) -> bool {
+    // the pool being replayed is locked too, its executions would see the replayed states
+    if addresses.iter().any(|address| is_replaying(address)) {
+        return false;
+    }
+    let previous = addresses
+        .iter()
+        .map(|address| locks.get(address).copied())
+        .collect::<Vec<_>>();
+    for (i, address) in addresses.iter().enumerate() {
+        if !try_lock_pool::<P>(locks, address, now) {
+            // restore the locks taken or taken over by this call
+            for (address, locked_at) in addresses[..i].iter().zip(previous.iter()) {
+                match locked_at {
+                    Some(locked_at) => locks.insert(address.clone(), *locked_at),
+                    None => locks.remove(address),
+                };
+            }
+            return false;
+        }
+    }
+    true
+}
+
+/// Returns whether all the `addresses` are still locked by the lock taken at `locked_at`,
+/// i.e. neither taken over after it became stale nor released by `force_unlock_pool`.
+#[doc(hidden)]
+pub fn holds_locks(
+    locks: &std::collections::HashMap<String, u64>,
+    addresses: &[String],
+    locked_at: u64,
+) -> bool {
+    addresses
+        .iter()
+        .all(|address| locks.get(address) == Some(&locked_at))
+}
+
+thread_local! {
+    static REPLAYED_POOL: std::cell::RefCell<Option<(String, Box<dyn std::any::Any>)>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Makes `PoolStorageAccess::get` return `pool` instead of the stored one until the guard is
+/// dropped, while the generated `replay_action` runs. Only one pool could be replayed at a time.
+#[doc(hidden)]
+pub fn replay_pool<S: 'static>(pool: Pool<S>) -> Result<ReplayGuard, error::Error> {
+    REPLAYED_POOL.with_borrow_mut(|replayed| match replayed {
+        Some(_) => Err(error::Error::PoolBeingExecuted),
+        None => {
+            *replayed = Some((pool.metadata.address.clone(), Box::new(pool)));
+            Ok(ReplayGuard(()))
+        }
+    })
+}
+
+/// Returns the pool installed by `replay_pool` if it's the one at `address`.
+#[doc(hidden)]
+pub fn replayed_pool<S: Clone + 'static>(address: &str) -> Option<Pool<S>> {
+    REPLAYED_POOL.with_borrow(|replayed| {
+        replayed
+            .as_ref()
+            .filter(|(replayed, _)| replayed == address)
+            .and_then(|(_, pool)| pool.downcast_ref::<Pool<S>>().cloned())
+    })
+}
+
+fn is_replaying(address: &str) -> bool {
+    REPLAYED_POOL.with_borrow(|replayed| {
+        replayed
+            .as_ref()
+            .is_some_and(|(replayed, _)| replayed == address)
+    })
+}
+
+/// Uninstalls the pool of `replay_pool` when dropped, including by a trap after an await.
+#[doc(hidden)]
+pub struct ReplayGuard(());
+
+impl Drop for ReplayGuard {
+    fn drop(&mut self) {
+        REPLAYED_POOL.with_borrow_mut(|replayed| *replayed = None);
+    }
+}
+
 /// The parameters for the hook `on_block_confirmed` and `on_block_finalized`
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Block {
@@ -229,20 +664,273 @@ impl Storable for Block {
     const BOUND: Bound = Bound::Unbounded;
 }
 
+/// The reorg and finalization configuration together with the current block window, returned by `get_reorg_diagnostics`
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ReorgDiagnostics {
+    /// The maximum finalize threshold across pools, i.e. the depth of the reorg window
+    pub finalize_threshold: u32,
+    /// The height of the latest block received
+    pub tip_height: Option<u32>,
+    /// The height of the oldest block not finalized yet
+    pub oldest_block: Option<u32>,
+    /// Blocks at or below this height are considered beyond reorg risk
+    pub confirmed_boundary: Option<u32>,
+    /// The number of transactions executed but not confirmed yet
+    pub unconfirmed_count: u64,
+}
+
+/// The classification of a new block against the retained blocks, see `states::detect_reorg`.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ReorgOutcome {
+    /// The block extends the tip, or it's the first block of the exchange
+    Normal,
+    /// The block is already accepted, it would be ignored
+    Duplicate,
+    /// The blocks `from..=to` would be reverted before accepting the block
+    Recoverable { from: u32, to: u32 },
+    /// The block skips heights or reverts finalized blocks, it would be rejected
+    Unrecoverable,
+}
+
+impl std::fmt::Display for ReorgOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Normal => write!(f, "no reorg"),
+            Self::Duplicate => write!(f, "duplicate block"),
+            Self::Recoverable { from, to } => write!(f, "reorg blocks from {from} to {to}"),
+            Self::Unrecoverable => write!(f, "unrecoverable reorg"),
+        }
+    }
+}
+
+/// The order of the pools returned by `get_pool_list`, see `Pools::list_order`.
+#[derive(CandidType, Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ListOrder {
+    /// By the pool address, i.e. the storage order
+    #[default]
+    ByAddress,
+    /// By the pool name, ties ordered by address
+    ByName,
+    /// By the sats reserved in the latest state, descending, ties ordered by address
+    ByBtcReserved,
+}
+
+/// A cheap overview of the exchange liveness and backlog, returned by `status`
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ExchangeStatus {
+    pub pool_count: u64,
+    /// The height of the latest block received
+    pub current_block_height: Option<u32>,
+    /// The number of transactions executed but not confirmed yet
+    pub unconfirmed_tx_count: u64,
+    /// True if `execute_tx` is rejected because no block is received within `max_block_silence_secs`
+    pub paused: bool,
+    pub network: Network,
+}
+
+/// The lifecycle status of a transaction, returned by `get_tx_status`
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum TxStatus {
+    /// The transaction is never seen, rolled back, or it may be finalized already: a finalized
+    /// transaction is only known while its `Finalized` event is retained, see `Pools::max_events`
+    Unknown,
+    /// The transaction is executed but not confirmed yet
+    Unconfirmed,
+    /// The transaction is confirmed in the block but can still be rolled back by a reorg
+    Confirmed { height: u32 },
+    /// The transaction is finalized in all its pools
+    Finalized,
+}
+
+/// The kind of an `ExchangeEvent`
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum EventKind {
+    /// The transaction is confirmed in a block
+    Confirmed,
+    /// The transaction is rejected and the pool state is rolled back
+    Rollbacked,
+    /// The transaction is finalized and can't be rolled back anymore
+    Finalized,
+}
+
+/// The event appended when a transaction changes its status in a pool, returned by `get_events`
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ExchangeEvent {
+    /// The sequence number of the event, starting from 1 and strictly increasing
+    pub seq: u64,
+    pub kind: EventKind,
+    pub txid: Txid,
+    /// The address of the pool
+    pub pool: String,
+    /// The height of the block containing the transaction, `None` for rollbacks
+    pub height: Option<u32>,
+}
+
+impl Storable for ExchangeEvent {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let bytes = bincode::serialize(self).unwrap();
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<'_, [u8]>) -> Self {
+        bincode::deserialize(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 /// The metadata for the pool, which includes the key, name, and address.
 /// Typically, the key and address should be generated by the IC chain-key.
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Metadata {
     pub key: Pubkey,
-    pub key_derivation_path: Vec<Vec<u8>>,
+    pub key_derivation_path: DerivationPath,
     pub name: String,
     pub address: String,
 }
 
+/// The maximum number of components in a chain-key derivation path, as specified for the
+/// `derivation_path` of `schnorr_public_key` and `sign_with_schnorr` in the IC interface specification.
+pub const MAX_DERIVATION_PATH_LEN: usize = 255;
+
+/// Validates the derivation path of a pool key, it must not be empty, contain empty components
+/// nor exceed `MAX_DERIVATION_PATH_LEN` components, so that a pool never ends up with an unintended address.
+pub fn validate_derivation_path(path: &[Vec<u8>]) -> Result<(), String> {
+    if path.is_empty() {
+        return Err("Empty key derivation path".to_string());
+    }
+    if path.len() > MAX_DERIVATION_PATH_LEN {
+        return Err(format!(
+            "Key derivation path has {} components, exceeding the limit of {}",
+            path.len(),
+            MAX_DERIVATION_PATH_LEN
+        ));
+    }
+    if let Some(i) = path.iter().position(|component| component.is_empty()) {
+        return Err(format!("Empty key derivation path component at {}", i));
+    }
+    Ok(())
+}
+
+/// The chain-key derivation path of a pool key, encoded as the raw `Vec<Vec<u8>>` in both the stable
+/// memory and candid. A string becomes the single component path, as `Metadata::new` does with the name.
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct DerivationPath(Vec<Vec<u8>>);
+
+impl DerivationPath {
+    /// Creates a path of the given components, checked by `validate_derivation_path`.
+    pub fn from_components(components: Vec<Vec<u8>>) -> Result<Self, String> {
+        validate_derivation_path(&components)?;
+        Ok(Self(components))
+    }
+
+    pub fn as_vec(&self) -> &Vec<Vec<u8>> {
+        &self.0
+    }
+
+    pub fn into_vec(self) -> Vec<Vec<u8>> {
+        self.0
+    }
+}
+
+impl std::str::FromStr for DerivationPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_components(vec![s.as_bytes().to_vec()])
+    }
+}
+
+/// Wraps the raw path without validation, e.g. the paths of the existing pools.
+impl From<Vec<Vec<u8>>> for DerivationPath {
+    fn from(components: Vec<Vec<u8>>) -> Self {
+        Self(components)
+    }
+}
+
+impl From<DerivationPath> for Vec<Vec<u8>> {
+    fn from(path: DerivationPath) -> Self {
+        path.0
+    }
+}
+
 impl Metadata {
     /// Creates a new metadata instance with the given name. It will automatically generate the key and address.
     pub async fn new<P: Pools>(name: String) -> Result<Self, String> {
-        let key_derivation_path: Vec<Vec<u8>> = vec![name.clone().into_bytes()];
+        let key_derivation_path = name.parse::<DerivationPath>()?;
+        Self::generate_with_path::<P>(name, key_derivation_path).await
+    }
+
+    /// Creates a new metadata instance whose derivation path is the caller-supplied unique id,
+    /// so the same id always yields the same key and address regardless of the name.
+    /// Combined with `PoolStorageAccess::get_or_create`, retrying a creation is idempotent.
+    pub async fn generate_with_id<P: Pools>(
+        name: String,
+        unique_id: impl AsRef<[u8]>,
+    ) -> Result<Self, String> {
+        let key_derivation_path =
+            DerivationPath::from_components(vec![unique_id.as_ref().to_vec()])?;
+        Self::generate_with_path::<P>(name, key_derivation_path).await
+    }
+
+    /// Creates the metadata of many pools from `(name, derivation path)` pairs, where each path is
+    /// a single component as in `new`, e.g. seeding the pools of a testnet.
+    ///
+    /// All the paths are validated and must be unique before any address is requested, so two
+    /// pools can't share an address. The chain-key calls are awaited one by one, so it's only
+    /// ergonomic and not faster than calling `generate_with_path` for each pool. The first failed
+    /// call aborts the batch, retrying is safe since a path always yields the same address.
+    pub async fn generate_batch<P: Pools>(
+        names_and_paths: Vec<(String, String)>,
+    ) -> Result<Vec<Self>, String> {
+        let mut seen = std::collections::BTreeSet::new();
+        let mut batch = vec![];
+        for (name, path) in names_and_paths.into_iter() {
+            let path = path.parse::<DerivationPath>()?;
+            if !seen.insert(path.as_vec().clone()) {
+                return Err(format!(
+                    "Duplicate key derivation path for the pool {}",
+                    name
+                ));
+            }
+            batch.push((name, path));
+        }
+        let mut metadata = vec![];
+        for (name, path) in batch.into_iter() {
+            metadata.push(Self::generate_with_path::<P>(name, path).await?);
+        }
+        Ok(metadata)
+    }
+
+    /// Re-derives the P2TR address from the untweaked `key` by applying the empty TapTweak.
+    pub fn derive_address(&self, network: Network) -> Result<String, String> {
+        use crate::types::bitcoin::{self, key::TweakedPublicKey};
+        bitcoin::XOnlyPublicKey::from_slice(&self.key.as_bytes()[1..])
+            .map_err(|e| format!("Invalid pool key: {}", e))?;
+        let tweaked = crate::schnorr::tweak_pubkey_with_empty(self.key.clone());
+        let key = TweakedPublicKey::dangerous_assume_tweaked(tweaked.to_x_only_public_key());
+        let network: bitcoin::Network = network.into();
+        Ok(bitcoin::Address::p2tr_tweaked(key, network).to_string())
+    }
+
+    /// Returns true if the stored `address` still matches the one derivable from `key`.
+    pub fn verify_address(&self, network: Network) -> Result<bool, String> {
+        Ok(self.derive_address(network)? == self.address)
+    }
+
+    /// Creates a new metadata instance with the given name and raw derivation path.
+    /// It will automatically generate the key and address.
+    pub async fn generate_with_path<P: Pools>(
+        name: String,
+        key_derivation_path: impl Into<DerivationPath>,
+    ) -> Result<Self, String> {
+        let key_derivation_path = key_derivation_path.into();
+        validate_derivation_path(key_derivation_path.as_vec())?;
         let (key, _, address) =
             crate::schnorr::request_p2tr_address(key_derivation_path.clone(), P::network())
                 .await
@@ -267,15 +955,101 @@ pub struct StateInfo {
     pub attributes: String,
 }
 
+/// The difference between two `StateInfo`s, see `StateInfo::diff`.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+pub struct StateDelta {
+    /// The change of each coin reserve ordered by the coin id, unchanged coins are omitted
+    pub coin_changes: Vec<(CoinId, i128)>,
+    pub btc_delta: i128,
+    /// The UTXOs in the new state but not in the previous one, identified by the outpoint
+    pub added_utxos: Vec<Utxo>,
+    /// The UTXOs in the previous state but not in the new one, identified by the outpoint
+    pub removed_utxos: Vec<Utxo>,
+}
+
+impl StateInfo {
+    /// Returns the changes from `prev` to this state. The coin reserves are merged by the coin id
+    /// before comparing so the order and duplicated entries of `coin_reserved` don't matter.
+    pub fn diff(&self, prev: &StateInfo) -> StateDelta {
+        let signed = |value: u128| value.min(i128::MAX as u128) as i128;
+        let mut coins = std::collections::BTreeMap::<CoinId, i128>::new();
+        for coin in self.coin_reserved.iter() {
+            let v = coins.entry(coin.id).or_default();
+            *v = v.saturating_add(signed(coin.value));
+        }
+        for coin in prev.coin_reserved.iter() {
+            let v = coins.entry(coin.id).or_default();
+            *v = v.saturating_sub(signed(coin.value));
+        }
+        let outpoint = |utxo: &Utxo| (utxo.txid, utxo.vout);
+        let exclude = |utxos: &[Utxo], others: &[Utxo]| {
+            utxos
+                .iter()
+                .filter(|utxo| !others.iter().any(|other| outpoint(other) == outpoint(utxo)))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        StateDelta {
+            coin_changes: coins.into_iter().filter(|(_, v)| *v != 0).collect(),
+            btc_delta: self.btc_reserved as i128 - prev.btc_reserved as i128,
+            added_utxos: exclude(&self.utxos, &prev.utxos),
+            removed_utxos: exclude(&prev.utxos, &self.utxos),
+        }
+    }
+
+    fn attribute_map(&self) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+        if self.attributes.trim().is_empty() {
+            return Ok(serde_json::Map::new());
+        }
+        serde_json::from_str(&self.attributes)
+            .map_err(|e| format!("Attributes are not a JSON object: {}", e))
+    }
+
+    /// Reads the value of `key` from `attributes`, which is treated as a JSON object (an empty string is an empty object).
+    /// Returns `Ok(None)` if the key is absent, or an error if the attributes or the value are malformed.
+    pub fn attribute<T: serde::de::DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> Result<Option<T>, String> {
+        self.attribute_map()?
+            .remove(key)
+            .map(|value| {
+                serde_json::from_value(value)
+                    .map_err(|e| format!("Malformed attribute {}: {}", key, e))
+            })
+            .transpose()
+    }
+
+    /// Sets the value of `key` in `attributes`, keeping the other keys.
+    /// Returns an error and leaves the attributes unchanged if they are not a JSON object.
+    pub fn set_attribute<T: Serialize>(&mut self, key: &str, value: &T) -> Result<(), String> {
+        let mut map = self.attribute_map()?;
+        let value = serde_json::to_value(value).map_err(|e| e.to_string())?;
+        map.insert(key.to_string(), value);
+        self.attributes = serde_json::Value::Object(map).to_string();
+        Ok(())
+    }
+}
+
 /// The parameter for the action function, which is used to execute a transaction in the exchange.
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct ActionArgs {
+    /// The txid of the transaction being executed
     pub txid: Txid,
+    /// The address of the initiator of the transaction
     pub initiator_address: String,
+    /// The intention targeting the pool of this exchange
     pub intention: Intention,
+    /// The rest of the intentions in the same transaction
     pub other_intentions: Vec<Intention>,
+    /// The number of unconfirmed transactions in the orchestrator queue
     pub unconfirmed_tx_count: usize,
+    /// Whether the orchestrator is reapplying a transaction after a reorg.
+    /// If the state committed by this txid is still present the action won't be invoked at all,
+    /// otherwise the states from `intention.nonce` onwards have been truncated before the action
+    /// is invoked, actions with side effects outside the pool state should keep them idempotent.
     pub is_reapply: bool,
+    /// The principal who invoked the orchestrator
     pub invoke_caller_principal: Principal,
 }
 
@@ -311,10 +1085,151 @@ impl TryFrom<ExecuteTxArgs> for ActionArgs {
     }
 }
 
+impl ActionArgs {
+    /// Ensures the output addresses declared by `intention` belong to the given network and
+    /// are each paid by an output of the PSBT.
+    ///
+    /// An output script carries no network by itself, so the PSBT outputs are matched against
+    /// the declared addresses, skipping the scripts which are no address, e.g. `OP_RETURN`.
+    /// The `other_intentions` belong to other exchanges and are left to them.
+    pub fn ensure_network(
+        &self,
+        psbt: &crate::types::bitcoin::Psbt,
+        network: Network,
+    ) -> Result<(), error::Error> {
+        let params: crate::types::bitcoin::Network = network.into();
+        let paid = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .filter_map(|output| {
+                crate::types::bitcoin::Address::from_script(&output.script_pubkey, params).ok()
+            })
+            .map(|address| address.script_pubkey())
+            .collect::<Vec<_>>();
+        self.intention.output_coins.iter().try_for_each(|output| {
+            let address = parse_address(&output.to, network)
+                .map_err(|_| error::Error::NetworkMismatch(output.to.clone()))?;
+            paid.contains(&address.script_pubkey())
+                .then_some(())
+                .ok_or(error::Error::IllegalPsbt)
+        })
+    }
+
+    /// Collects the pool outpoints spent by `intention` and all `other_intentions`, including
+    /// those of the pools belonging to other exchanges, e.g. to validate a cross-pool route.
+    pub fn all_pool_inputs(&self) -> Result<Vec<crate::types::bitcoin::OutPoint>, String> {
+        let mut inputs = vec![];
+        for intention in std::iter::once(&self.intention).chain(self.other_intentions.iter()) {
+            inputs.extend(intention.pool_outpoints().map_err(|e| e.to_string())?);
+        }
+        Ok(inputs)
+    }
+
+    /// Returns the distinct addresses of this exchange's pools targeted by the transaction,
+    /// i.e. `intention.pool_address` followed by the sibling pools of the same exchange.
+    ///
+    /// All of them are locked while `execute_tx` runs, so an action can read the sibling pools
+    /// through `PoolStorageAccess::get` without other transactions modifying them in between.
+    /// The state of each sibling is still committed by the `execute_tx` of its own intention,
+    /// so the action should only return the new state of `intention.pool_address`.
+    pub fn affected_pools(&self) -> Vec<String> {
+        let mut pools = vec![self.intention.pool_address.clone()];
+        for intention in self.other_intentions.iter() {
+            if intention.exchange_id == self.intention.exchange_id
+                && !pools.contains(&intention.pool_address)
+            {
+                pools.push(intention.pool_address.clone());
+            }
+        }
+        pools
+    }
+}
+
+/// Ensures the `available` reserves cover every coin in `required`.
+/// Returns the first deficient coin along with the shortfall otherwise.
+pub fn ensure_reserves(
+    available: &CoinBalances,
+    required: &CoinBalances,
+) -> Result<(), error::Error> {
+    for coin in required.iter() {
+        let value = available.value_of(&coin.id);
+        if value < coin.value {
+            return Err(error::Error::InsufficientReserves {
+                coin: coin.id,
+                required: coin.value,
+                available: value,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the fee paid by the pool, i.e. the transaction fee `sum(inputs) - sum(outputs)` capped by
+/// the value of the spent `pool_inputs`, and rejects it if it exceeds `max_fee_sats`.
+///
+/// The PSBT is signed over by the SDK once the action succeeds, so an action should call this to
+/// prevent a malicious or buggy PSBT from draining the pool BTC to the miners.
+/// Every input requires `witness_utxo` or `non_witness_utxo` to know the spent value.
+pub fn verify_fee(
+    psbt: &crate::types::bitcoin::Psbt,
+    pool_inputs: &[crate::types::bitcoin::OutPoint],
+    max_fee_sats: u64,
+) -> Result<u64, String> {
+    let prevouts = crate::schnorr::collect_prevouts(psbt)?;
+    let mut total_in = 0u64;
+    let mut pool_in = 0u64;
+    for (input, prevout) in psbt.unsigned_tx.input.iter().zip(prevouts.iter()) {
+        let value = prevout.value.to_sat();
+        total_in = total_in
+            .checked_add(value)
+            .ok_or("Input values overflow".to_string())?;
+        if pool_inputs.contains(&input.previous_output) {
+            pool_in += value;
+        }
+    }
+    let total_out = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .try_fold(0u64, |sum, output| sum.checked_add(output.value.to_sat()))
+        .ok_or("Output values overflow".to_string())?;
+    let fee = total_in
+        .checked_sub(total_out)
+        .ok_or(format!(
+            "Outputs of {total_out} sats exceed inputs of {total_in} sats"
+        ))?
+        .min(pool_in);
+    if fee > max_fee_sats {
+        return Err(error::Error::FeeTooHigh {
+            fee,
+            max: max_fee_sats,
+        }
+        .into());
+    }
+    Ok(fee)
+}
+
+/// Extracts the final transaction of the PSBT and returns its raw hex, ready to be broadcast.
+/// Returns an error if any input isn't finalized, i.e. has neither `final_script_witness` nor
+/// `final_script_sig`. The fee rate isn't checked, see `verify_fee`.
+pub fn psbt_to_tx_hex(psbt: &crate::types::bitcoin::Psbt) -> Result<String, String> {
+    if let Some(i) = psbt
+        .inputs
+        .iter()
+        .position(|input| input.final_script_witness.is_none() && input.final_script_sig.is_none())
+    {
+        return Err(format!("Input {i} is not finalized"));
+    }
+    let tx = psbt.clone().extract_tx_unchecked_fee_rate();
+    Ok(crate::types::bitcoin::consensus::encode::serialize_hex(&tx))
+}
+
 /// The result type for actions in the exchange, which can either be successful with a state or an error message.
 pub type ActionResult<S> = Result<S, error::Error>;
 
 /// User must implement the `StateView` trait for customized state to provide this information.
+/// It could be derived by `#[derive(StateView)]` if the state has the fields of `StateInfo`.
 pub trait StateView {
     fn inspect_state(&self) -> StateInfo;
 
@@ -327,6 +1242,100 @@ pub trait StateView {
 pub struct Pool<S> {
     metadata: Metadata,
     states: Vec<S>,
+    /// Whether the first state is finalized, i.e. a state has been finalized since the states were
+    /// replaced. It's `false` for the pools written before version 2 of the bincode layout.
+    #[serde(default)]
+    finalized: bool,
+}
+
+/// The layout of the pools before the `finalized` flag.
+#[derive(Deserialize)]
+struct PoolV1<S> {
+    metadata: Metadata,
+    states: Vec<S>,
+}
+
+impl<S> From<PoolV1<S>> for Pool<S> {
+    fn from(pool: PoolV1<S>) -> Self {
+        Self {
+            metadata: pool.metadata,
+            states: pool.states,
+            finalized: false,
+        }
+    }
+}
+
+/// The encoding of the pools in the stable memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PoolCodec {
+    /// Compact, but the layout of the state must never change.
+    #[default]
+    Bincode,
+    /// Self-describing, so fields could be added (with `#[serde(default)]`) or removed from the state without a migration.
+    Cbor,
+}
+
+/// Every encoded pool is prefixed by a version byte telling its layout, so both encodings could be
+/// read no matter which one is configured and an unknown layout is reported instead of misread.
+/// Version 2 is the bincode layout with the `finalized` flag.
+const BINCODE_POOL_TAG: u8 = 0x02;
+
+/// The bincode layout without the `finalized` flag.
+const BINCODE_V1_POOL_TAG: u8 = 0x01;
+
+/// CBOR encoded pools are prefixed with this tag.
+const CBOR_POOL_TAG: u8 = 0xff;
+
+/// The pools written before the version byte are untagged bincode, starting with the length
+/// of the hex encoded pubkey as a little-endian `u64`, i.e. 64 for a x-only key and 66 otherwise.
+const LEGACY_POOL_TAGS: [u8; 2] = [64, 66];
+
+thread_local! {
+    static POOL_CODEC: std::cell::Cell<PoolCodec> = const { std::cell::Cell::new(PoolCodec::Bincode) };
+}
+
+#[doc(hidden)]
+pub fn set_pool_codec(codec: PoolCodec) {
+    POOL_CODEC.with(|c| c.set(codec));
+}
+
+#[doc(hidden)]
+pub fn encode_pool<S: Serialize>(pool: &Pool<S>, codec: PoolCodec) -> Result<Vec<u8>, String> {
+    match codec {
+        PoolCodec::Bincode => {
+            let mut bytes = vec![BINCODE_POOL_TAG];
+            bincode::serialize_into(&mut bytes, pool).map_err(|e| e.to_string())?;
+            Ok(bytes)
+        }
+        PoolCodec::Cbor => {
+            let mut bytes = vec![CBOR_POOL_TAG];
+            ciborium::into_writer(pool, &mut bytes).map_err(|e| e.to_string())?;
+            Ok(bytes)
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn decode_pool<S>(bytes: &[u8]) -> Result<Pool<S>, String>
+where
+    S: for<'de> Deserialize<'de>,
+{
+    match bytes.split_first() {
+        Some((&BINCODE_POOL_TAG, bincode)) => {
+            bincode::deserialize(bincode).map_err(|e| e.to_string())
+        }
+        Some((&BINCODE_V1_POOL_TAG, bincode)) => bincode::deserialize::<PoolV1<S>>(bincode)
+            .map(Pool::from)
+            .map_err(|e| e.to_string()),
+        Some((&CBOR_POOL_TAG, cbor)) => ciborium::from_reader(cbor).map_err(|e| e.to_string()),
+        Some((tag, _)) if LEGACY_POOL_TAGS.contains(tag) => {
+            bincode::deserialize::<PoolV1<S>>(bytes)
+                .map(Pool::from)
+                .map_err(|e| e.to_string())
+        }
+        Some((version, _)) => Err(format!("Unknown pool format version {}", version)),
+        None => Err("Empty pool bytes".to_string()),
+    }
 }
 
 impl<S> Storable for Pool<S>
@@ -336,28 +1345,48 @@ where
     const BOUND: Bound = Bound::Unbounded;
 
     fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
-        let bytes = bincode::serialize(self).unwrap();
-        std::borrow::Cow::Owned(bytes)
+        std::borrow::Cow::Owned(self.try_to_bytes().unwrap())
     }
 
     fn into_bytes(self) -> Vec<u8> {
-        bincode::serialize(&self).unwrap()
+        self.try_to_bytes().unwrap()
     }
 
     fn from_bytes(bytes: std::borrow::Cow<'_, [u8]>) -> Self {
-        bincode::deserialize(bytes.as_ref()).unwrap()
+        decode_pool(bytes.as_ref()).unwrap()
     }
 }
 
 impl<S> Pool<S>
 where
-    S: StateView,
+    S: Serialize,
 {
-    /// Creates a new pool with the given metadata.
-    pub fn new(metadata: Metadata) -> Self {
-        Self {
+    /// Serializes the pool in the stable memory format, returning an error instead of trapping.
+    pub fn try_to_bytes(&self) -> Result<Vec<u8>, String> {
+        encode_pool(self, POOL_CODEC.with(|c| c.get()))
+    }
+}
+
+impl<S> Pool<S>
+where
+    S: StateView + Clone + Default,
+{
+    /// Returns a copy of the last state of the pool, or the default state if the pool is empty.
+    pub fn last_state_or_default(&self) -> S {
+        self.last_state().cloned().unwrap_or_default()
+    }
+}
+
+impl<S> Pool<S>
+where
+    S: StateView,
+{
+    /// Creates a new pool with the given metadata.
+    pub fn new(metadata: Metadata) -> Self {
+        Self {
             metadata,
             states: Vec::new(),
+            finalized: false,
         }
     }
 
@@ -371,11 +1400,37 @@ where
         self.states.last()
     }
 
+    /// Checks that `intention_nonce` follows the nonce of the last state, i.e. the intention was
+    /// built against the latest state and isn't replayed. An empty pool expects the nonce 1.
+    /// `execute_tx` performs this check before dispatching the action.
+    pub fn check_nonce(&self, intention_nonce: u64) -> Result<(), String> {
+        let current = self
+            .last_state()
+            .map(|state| state.inspect_state().nonce)
+            .unwrap_or_default();
+        if current.checked_add(1) == Some(intention_nonce) {
+            Ok(())
+        } else {
+            Err(format!(
+                "{}: the pool is at nonce {}, expected {} but got {}",
+                error::Error::NonceExpired,
+                current,
+                current.saturating_add(1),
+                intention_nonce
+            ))
+        }
+    }
+
     /// Returns the states of the pool.
     pub fn states(&self) -> &Vec<S> {
         &self.states
     }
 
+    /// Returns up to the last `n` states of the pool, oldest first.
+    pub fn recent_states(&self, n: usize) -> &[S] {
+        &self.states[self.states.len().saturating_sub(n)..]
+    }
+
     /// Return the state matches the given txid.
     pub fn get(&self, txid: Txid) -> Option<&S> {
         self.states
@@ -387,6 +1442,116 @@ where
     pub fn states_mut(&mut self) -> &mut Vec<S> {
         &mut self.states
     }
+
+    /// Returns the sats and coins reserved by the latest state, including the unconfirmed
+    /// transactions, i.e. what the next action could spend. An empty pool has no reserve.
+    pub fn available_reserve(&self) -> (u64, CoinBalances) {
+        Self::reserve_of(self.states.last())
+    }
+
+    /// Returns the sats and coins reserved by the last finalized state, which is the first retained
+    /// one. A pool without any finalized state, e.g. a fresh one, has no confirmed reserve.
+    pub fn confirmed_reserve(&self) -> (u64, CoinBalances) {
+        Self::reserve_of(self.states.first().filter(|_| self.finalized))
+    }
+
+    fn reserve_of(state: Option<&S>) -> (u64, CoinBalances) {
+        let Some(state) = state.map(|state| state.inspect_state()) else {
+            return (0, CoinBalances::new());
+        };
+        let mut coins = CoinBalances::new();
+        for coin in state.coin_reserved.iter() {
+            coins.add_coin(coin);
+        }
+        (state.btc_reserved, coins)
+    }
+
+    /// Replaces the whole state history of the pool, e.g. importing a known-good snapshot during
+    /// recovery, and returns the replaced states. The nonces of `states` must be strictly increasing.
+    pub fn replace_states(&mut self, states: Vec<S>) -> Result<Vec<S>, String> {
+        let nonces = states
+            .iter()
+            .map(|state| state.inspect_state().nonce)
+            .collect::<Vec<_>>();
+        if let Some(w) = nonces.windows(2).find(|w| w[0] >= w[1]) {
+            return Err(format!(
+                "nonces must be strictly increasing, got {} before {}",
+                w[0], w[1]
+            ));
+        }
+        self.finalized = false;
+        Ok(std::mem::replace(&mut self.states, states))
+    }
+
+    /// Builds a PSBT spending all UTXOs in the last state of the pool to `to_address`, e.g. to migrate
+    /// or decommission the pool. The fee is `fee_rate` sats/vbyte of the transaction with the taproot
+    /// key spend signatures. There is no runestone, so the runes in the UTXOs are transferred to
+    /// `to_address` as well. Each input carries its `witness_utxo`, ready for `schnorr::sign_p2tr_inputs`.
+    pub fn sweep_psbt(
+        &self,
+        to_address: &str,
+        fee_rate: u64,
+        network: Network,
+    ) -> Result<crate::types::bitcoin::Psbt, String> {
+        use crate::types::bitcoin::{
+            Amount, Psbt, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
+            absolute::LockTime, transaction::Version,
+        };
+
+        let script_of =
+            |address: &str| parse_address(address, network).map(|address| address.script_pubkey());
+        let pool_script = script_of(&self.metadata.address)?;
+        let to_script = script_of(to_address)?;
+        let utxos = self
+            .last_state()
+            .map(|state| state.inspect_state().utxos)
+            .unwrap_or_default();
+        if utxos.is_empty() {
+            return Err("No UTXOs to sweep".to_string());
+        }
+        let total = utxos
+            .iter()
+            .try_fold(0u64, |total, utxo| total.checked_add(utxo.sats))
+            .ok_or("Total sats overflows".to_string())?;
+        let mut tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: utxos
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: utxo.as_outpoint(),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: Amount::from_sat(total),
+                script_pubkey: to_script.clone(),
+            }],
+        };
+        // the segwit marker and flag, and a witness of 1 (items) + 1 (length) + 64 (signature) bytes per input
+        let weight = tx.weight().to_wu() + 2 + 66 * utxos.len() as u64;
+        let fee = fee_rate
+            .checked_mul(weight.div_ceil(4))
+            .ok_or("Fee overflows".to_string())?;
+        let value = total
+            .checked_sub(fee)
+            .filter(|value| *value >= to_script.minimal_non_dust().to_sat())
+            .ok_or(format!(
+                "Insufficient sats {} to pay the fee {}",
+                total, fee
+            ))?;
+        tx.output[0].value = Amount::from_sat(value);
+        let mut psbt = Psbt::from_unsigned_tx(tx).map_err(|e| e.to_string())?;
+        for (input, utxo) in psbt.inputs.iter_mut().zip(utxos.iter()) {
+            input.witness_utxo = Some(TxOut {
+                value: Amount::from_sat(utxo.sats),
+                script_pubkey: pool_script.clone(),
+            });
+        }
+        Ok(psbt)
+    }
 }
 
 #[doc(hidden)]
@@ -397,8 +1562,12 @@ pub trait ReePool<S> {
 
     fn truncate(&mut self, nonce: u64) -> Result<(), String>;
 
+    fn reapply(&mut self, txid: Txid, nonce: u64) -> Result<bool, String>;
+
     fn rollback(&mut self, txid: Txid) -> Result<Vec<S>, String>;
 
+    fn rollback_to_nonce(&mut self, nonce: u64) -> Result<Vec<S>, String>;
+
     fn finalize(&mut self, txid: Txid) -> Result<(), String>;
 }
 
@@ -437,7 +1606,7 @@ where
         } = state;
         PoolInfo {
             key,
-            key_derivation_path,
+            key_derivation_path: key_derivation_path.into_vec(),
             name,
             address,
             nonce,
@@ -456,9 +1625,30 @@ where
                 break;
             }
         }
+        if self.states.is_empty() {
+            self.finalized = false;
+        }
         Ok(())
     }
 
+    /// Prepares the pool for a transaction reapplied by the orchestrator.
+    /// If the state committed by `txid` at `nonce` is still present, the states after it are
+    /// truncated and `true` is returned so the action could be skipped; otherwise the states
+    /// from `nonce` are truncated and the action must be executed again.
+    fn reapply(&mut self, txid: Txid, nonce: u64) -> Result<bool, String> {
+        let committed = self.states.iter().any(|state| {
+            let info = state.inspect_state();
+            info.txid == txid && info.nonce == nonce
+        });
+        if committed {
+            self.truncate(nonce + 1)?;
+        } else {
+            self.truncate(nonce)?;
+        }
+        Ok(committed)
+    }
+
+    /// Discards the state committed by `txid` and the ones after it, returns them most recent first.
     fn rollback(&mut self, txid: Txid) -> Result<Vec<S>, String> {
         let idx = self
             .states
@@ -470,6 +1660,32 @@ where
         while self.states.len() > idx {
             rollbacked_states.push(self.states.pop().unwrap());
         }
+        if self.states.is_empty() {
+            self.finalized = false;
+        }
+
+        Ok(rollbacked_states)
+    }
+
+    /// Rolls back the states after the one at `nonce`, which becomes the latest state.
+    fn rollback_to_nonce(&mut self, nonce: u64) -> Result<Vec<S>, String> {
+        let matched = self
+            .states
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| state.inspect_state().nonce == nonce)
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        let idx = match matched.as_slice() {
+            [] => return Err(format!("nonce {} not found", nonce)),
+            [idx] => *idx,
+            _ => return Err(format!("nonce {} appears {} times", nonce, matched.len())),
+        };
+
+        let mut rollbacked_states = vec![];
+        while self.states.len() > idx + 1 {
+            rollbacked_states.push(self.states.pop().unwrap());
+        }
 
         Ok(rollbacked_states)
     }
@@ -480,6 +1696,7 @@ where
             .iter()
             .position(|state| state.inspect_state().txid == txid)
             .ok_or("txid not found".to_string())?;
+        self.finalized = true;
         if idx == 0 {
             return Ok(());
         }
@@ -492,7 +1709,8 @@ where
 /// The Pools trait defines the interface for the exchange pools, must be marked as `#[ree_exchange_sdk::pools]`.
 pub trait Pools {
     /// The concrete type of the pool state.
-    type PoolState: StateView + Serialize + for<'de> Deserialize<'de>;
+    /// It's cloned by the SDK when committing and reapplying states, e.g. `pool.last_state().cloned()`.
+    type PoolState: StateView + Clone + Serialize + for<'de> Deserialize<'de>;
 
     /// The concret type of the block state.
     type BlockState: Serialize + for<'de> Deserialize<'de>;
@@ -503,6 +1721,10 @@ pub trait Pools {
     /// The memory ID for the pool state storage.
     const POOL_STATE_MEMORY: u8;
 
+    /// The encoding of the pools written to the stable memory, defaults to `PoolCodec::Bincode`.
+    /// Pools are decoded in whichever encoding they were written, so it's safe to switch between upgrades.
+    const POOL_CODEC: PoolCodec = PoolCodec::Bincode;
+
     /// useful for ensuring that the exchange is running on the correct network.
     fn network() -> Network;
 
@@ -511,12 +1733,97 @@ pub trait Pools {
     fn finalize_threshold() -> u32 {
         60
     }
+
+    /// Returns the finalize threshold of a specific pool, defaults to `finalize_threshold`.
+    /// A pool with a lower threshold finalizes its states earlier and can't be rolled back by deeper
+    /// reorgs, a high-value pool could require a deeper one. Values above `max_finalize_threshold`
+    /// are capped to it since the blocks beyond are pruned.
+    fn finalize_threshold_for(_address: &str) -> u32 {
+        Self::finalize_threshold()
+    }
+
+    /// Returns the maximum of `finalize_threshold_for` across pools, defaults to `finalize_threshold`.
+    /// It bounds the reorg detection and block pruning, so it must be raised together with any
+    /// per-pool threshold deeper than `finalize_threshold`.
+    fn max_finalize_threshold() -> u32 {
+        Self::finalize_threshold()
+    }
+
+    /// Returns the maximum number of seconds allowed since the last received block.
+    /// If the latest block is older than this, `execute_tx` is rejected until a new block arrives.
+    /// `None` disables the check.
+    fn max_block_silence_secs() -> Option<u64> {
+        None
+    }
+
+    /// Returns the maximum number of unconfirmed transactions of a single pool. `execute_tx` rejects
+    /// the actions on a pool having this many transactions waiting for confirmation, which bounds
+    /// the chain of states to roll back on a reorg. `None` disables the check.
+    fn max_unconfirmed_per_pool() -> Option<usize> {
+        None
+    }
+
+    /// Returns the number of seconds after which a pool locked by `execute_tx` is considered stale,
+    /// e.g. left by a trapped execution, and could be locked again. `None` keeps the lock until
+    /// the execution finishes or `force_unlock_pool` is called.
+    fn execute_lock_timeout_secs() -> Option<u64> {
+        None
+    }
+
+    /// Returns the order of the pools returned by `get_pool_list`. Any order other than
+    /// `ListOrder::ByAddress` sorts the whole list on each call.
+    fn list_order() -> ListOrder {
+        ListOrder::ByAddress
+    }
+
+    /// Returns the minimum value in sats of an output paying to a pool, by default 330 sats, i.e. the
+    /// dust limit of the P2TR outputs at the default relay fee rate. See `ensure_no_dust_pool_outputs`.
+    fn dust_threshold() -> u64 {
+        330
+    }
+
+    /// Returns true to make `execute_tx` reject the transactions paying less than `dust_threshold`
+    /// to the pool before dispatching the action.
+    fn reject_dust_pool_outputs() -> bool {
+        false
+    }
+
+    /// Returns true to keep the pool locks of `execute_tx` in the stable memory instead of the heap.
+    /// Heap locks are cleared by upgrades, which also releases the ones left by a trapped execution.
+    /// Persistent locks survive upgrades and stay visible in `get_locked_pools`, so such a lock must
+    /// be released by `execute_lock_timeout_secs` or `force_unlock_pool`, at the cost of a stable
+    /// memory write on every lock and unlock.
+    fn persistent_locks() -> bool {
+        false
+    }
+
+    /// Returns the maximum number of events kept for `get_events`, the oldest are dropped first.
+    /// `get_tx_status` reports a pruned transaction as finalized only while its event is kept.
+    fn max_events() -> u64 {
+        10_000
+    }
+
+    /// Whether to run `states::run_maintenance` periodically with a timer, which releases the stale
+    /// pool locks and finalizes the blocks beyond the reorg window without waiting for a `new_block`.
+    /// The timer doesn't survive upgrades, it's armed by the first `execute_tx` or `new_block`, or
+    /// right away by calling the generated `arm_maintenance` or `restore_after_upgrade` of the `Pools` type,
+    /// e.g. in `post_upgrade`.
+    fn enable_periodic_maintenance() -> bool {
+        false
+    }
+
+    /// Returns the interval in seconds of the periodic maintenance.
+    fn maintenance_interval_secs() -> u64 {
+        600
+    }
 }
 
 /// A hook that can be implemented to respond to block event in the exchange lifecycle.
 /// It must be implemented over the `BlockState` type and marked as `#[ree_exchange_sdk::hook]`.
 pub trait Hook: Pools {
     /// This function is called when a transaction is rejected and never confirmed.
+    /// `rollbacked_states` are the states discarded from the pool, most recent first, i.e. the first one
+    /// holds the reserves before the rollback and the last one is the state committed by `txid`.
     fn on_tx_rollbacked(
         _address: String,
         _txid: Txid,
@@ -531,8 +1838,27 @@ pub trait Hook: Pools {
     /// This function is called when a block is received.
     fn on_block_confirmed(_block: Block) {}
 
+    /// This function is called when a transaction is finalized in a pool, with the finalized state
+    /// of the pool and the block containing the transaction.
+    fn on_state_finalized(_address: String, _txid: Txid, _state: StateInfo, _block: Block) {}
+
+    /// This function is called when a block is received, before any other hooks and state mutation.
+    /// Returning an error rejects the block, `new_block` fails with the returned message.
+    fn validate_new_block(_args: &NewBlockInfo) -> Result<(), String> {
+        Ok(())
+    }
+
     /// This function is called when a block is received but before any other hooks.
     fn pre_block_confirmed(_height: u32) {}
+
+    /// This function is called when `new_block` is rejected by an unrecoverable reorg, e.g. deeper
+    /// than `max_finalize_threshold` or skipping blocks after `current_tip`, which requires manual
+    /// intervention. It's called again each time the orchestrator retries the block.
+    fn on_unrecoverable_reorg(_current_tip: u32, _new_block: &NewBlockInfo) {}
+
+    /// This function is called when the new state of a pool can't be serialized.
+    /// The action is rejected and nothing is committed.
+    fn on_serialize_error(_address: String, _txid: Txid, _error: String) {}
 }
 
 /// A trait for accessing the pool storage.
@@ -544,11 +1870,41 @@ pub trait PoolStorageAccess<P: Pools> {
 
     fn get(address: &String) -> Option<Pool<P::PoolState>>;
 
+    fn get_many(addresses: &[String]) -> Vec<Option<Pool<P::PoolState>>>;
+
+    /// Returns the number of confirmations of a transaction, i.e. `1` if it's in the latest block.
+    /// Returns `None` if it's not in the retained blocks, either unconfirmed or already finalized.
+    fn confirmations_of(txid: &Txid) -> Option<u32>;
+
+    /// Classifies the new block against the retained blocks without processing it, e.g. in
+    /// `Hook::validate_new_block` to reject any reorg instead of recovering from it.
+    fn detect_reorg(new_block: &NewBlockInfo) -> ReorgOutcome;
+
+    /// Returns the pool whose latest state holds the UTXO at `outpoint`, formatted as `txid:vout`.
+    /// Every pool is visited until a match is found, see `queries::pool_by_utxo`.
+    fn find_by_utxo(outpoint: &str) -> Option<(String, Pool<P::PoolState>)>;
+
     fn insert(pool: Pool<P::PoolState>);
 
     fn remove(address: &String) -> Option<Pool<P::PoolState>>;
 
     fn iter() -> iter::PoolIterator<P>;
+
+    /// Iterates the pools whose address is within `start..end` in lexicographic order,
+    /// e.g. all pools sharing an address prefix, without loading the rest of them.
+    fn iter_range(start: String, end: String) -> iter::PoolIterator<P>;
+
+    /// Inserts the pool unless one already exists at its address, returns the stored pool.
+    /// This prevents duplicate pools when the creation call is retried.
+    fn get_or_create(pool: Pool<P::PoolState>) -> Pool<P::PoolState> {
+        match Self::get(&pool.metadata().address) {
+            Some(existing) => existing,
+            None => {
+                Self::insert(pool.clone());
+                pool
+            }
+        }
+    }
 }
 
 #[doc(hidden)]
@@ -670,7 +2026,9 @@ where
 /// }
 ///
 /// ```
-/// Now you can call `MyPools::upgrade()` in the `post_upgrade` hook.
+/// Now you can call `MyPools::upgrade()` and `MyPools::restore_after_upgrade()` in the `post_upgrade` hook,
+/// or mark the impl block with `#[upgrade(auto)]` to generate a `post_upgrade` hook calling them. `MyPools::validate_upgrade()`, also exposed as a query, decodes
+/// and converts the previous states without writing anything, so it can be checked beforehand.
 pub trait Upgrade<P: Pools> {
     /// The previous pool state type before the upgrade.
     type PoolState: Into<P::PoolState> + for<'de> Deserialize<'de> + Clone;
@@ -685,6 +2043,59 @@ pub trait Upgrade<P: Pools> {
     const BLOCK_STATE_MEMORY: u8;
 }
 
+/// The result of decoding and converting the previous states, returned by `validate_upgrade`
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+pub struct UpgradeValidation {
+    /// The number of pools decoded and converted successfully
+    pub pool_count: u64,
+    /// The number of block states decoded and converted successfully
+    pub block_count: u64,
+    /// The key of the first entry failed to decode, i.e. the pool address or the block height,
+    /// along with the error. The validation stops there.
+    pub first_failure: Option<(String, String)>,
+}
+
+#[doc(hidden)]
+pub fn validate_upgrade<P, U>(pool_memory: Memory, block_memory: Memory) -> UpgradeValidation
+where
+    P: Pools,
+    U: Upgrade<P>,
+{
+    let mut validation = UpgradeValidation::default();
+    // read the raw values so that a malformed one doesn't trap in `Storable::from_bytes`
+    let pools = BTreeMap::<String, Vec<u8>, Memory>::init(pool_memory);
+    for (address, bytes) in pools.iter().map(|entry| entry.into_pair()) {
+        match decode_pool::<U::PoolState>(&bytes) {
+            Ok(pool) => {
+                let _ = pool
+                    .states
+                    .into_iter()
+                    .map(Into::into)
+                    .collect::<Vec<P::PoolState>>();
+                validation.pool_count += 1;
+            }
+            Err(e) => {
+                validation.first_failure = Some((address, e));
+                return validation;
+            }
+        }
+    }
+    let blocks = BTreeMap::<u32, Vec<u8>, Memory>::init(block_memory);
+    for (height, bytes) in blocks.iter().map(|entry| entry.into_pair()) {
+        match bincode::deserialize::<GlobalStateWrapper<U::BlockState>>(&bytes) {
+            Ok(block) => {
+                let _: P::BlockState = block.inner.into();
+                validation.block_count += 1;
+            }
+            Err(e) => {
+                validation.first_failure = Some((height.to_string(), e.to_string()));
+                return validation;
+            }
+        }
+    }
+    validation
+}
+
 #[doc(hidden)]
 pub fn iterator<P>(memory: Memory) -> iter::PoolIterator<P>
 where
@@ -692,11 +2103,21 @@ where
 {
     let inner = PoolStorage::<P::PoolState>::init(memory);
     let keys = inner.keys().collect::<Vec<_>>();
-    iter::PoolIterator {
-        inner,
-        cursor: 0,
-        keys,
-    }
+    iter::PoolIterator::new(inner, keys)
+}
+
+#[doc(hidden)]
+pub fn range_iterator<P>(memory: Memory, start: String, end: String) -> iter::PoolIterator<P>
+where
+    P: Pools,
+{
+    let inner = PoolStorage::<P::PoolState>::init(memory);
+    let keys = if start < end {
+        inner.keys_range(start..end).collect::<Vec<_>>()
+    } else {
+        vec![]
+    };
+    iter::PoolIterator::new(inner, keys)
 }
 
 #[doc(hidden)]
@@ -704,9 +2125,24 @@ pub mod iter {
     pub struct PoolIterator<P: super::Pools> {
         pub(crate) inner: super::PoolStorage<P::PoolState>,
         pub(crate) cursor: usize,
+        pub(crate) end: usize,
         pub(crate) keys: Vec<String>,
     }
 
+    impl<P> PoolIterator<P>
+    where
+        P: super::Pools,
+    {
+        pub(crate) fn new(inner: super::PoolStorage<P::PoolState>, keys: Vec<String>) -> Self {
+            Self {
+                inner,
+                cursor: 0,
+                end: keys.len(),
+                keys,
+            }
+        }
+    }
+
     impl<P> std::iter::Iterator for PoolIterator<P>
     where
         P: super::Pools,
@@ -714,7 +2150,7 @@ pub mod iter {
         type Item = (String, super::Pool<P::PoolState>);
 
         fn next(&mut self) -> Option<Self::Item> {
-            if self.cursor < self.keys.len() {
+            if self.cursor < self.end {
                 let key = self.keys[self.cursor].clone();
                 self.cursor += 1;
                 self.inner.get(&key).map(|v| (key.clone(), v))
@@ -722,6 +2158,27 @@ pub mod iter {
                 None
             }
         }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            (0, Some(self.end - self.cursor))
+        }
+    }
+
+    /// Iterating from the back shares the remaining range with `next`,
+    /// so `P::iter().rev().take(n)` yields the last `n` pools without collecting the rest.
+    impl<P> std::iter::DoubleEndedIterator for PoolIterator<P>
+    where
+        P: super::Pools,
+    {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            if self.cursor < self.end {
+                self.end -= 1;
+                let key = self.keys[self.end].clone();
+                self.inner.get(&key).map(|v| (key.clone(), v))
+            } else {
+                None
+            }
+        }
     }
 }
 
@@ -732,13 +2189,13 @@ pub mod test {
     use super::*;
 
     #[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
-    struct DummyPoolState {
-        nonce: u64,
-        txid: Txid,
-        coin_reserved: Vec<CoinBalance>,
-        btc_reserved: u64,
-        utxos: Vec<Utxo>,
-        attributes: String,
+    pub(crate) struct DummyPoolState {
+        pub(crate) nonce: u64,
+        pub(crate) txid: Txid,
+        pub(crate) coin_reserved: Vec<CoinBalance>,
+        pub(crate) btc_reserved: u64,
+        pub(crate) utxos: Vec<Utxo>,
+        pub(crate) attributes: String,
     }
 
     impl StateView for DummyPoolState {
@@ -758,6 +2215,57 @@ pub mod test {
         }
     }
 
+    pub(crate) struct DummyPools;
+
+    impl Pools for DummyPools {
+        type PoolState = DummyPoolState;
+
+        type BlockState = u32;
+
+        const POOL_STATE_MEMORY: u8 = 0;
+
+        const BLOCK_STATE_MEMORY: u8 = 1;
+
+        fn network() -> Network {
+            Network::Testnet4
+        }
+    }
+
+    impl Hook for DummyPools {}
+
+    pub(crate) fn memory(id: u8) -> Memory {
+        use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+        MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(id))
+    }
+
+    pub(crate) fn dummy_pool(address: &str, states: Vec<DummyPoolState>) -> Pool<DummyPoolState> {
+        Pool {
+            metadata: Metadata {
+                key: Pubkey::from_raw(vec![2u8; 33]).unwrap(),
+                key_derivation_path: vec![address.as_bytes().to_vec()].into(),
+                name: address.to_uppercase(),
+                address: address.to_string(),
+            },
+            states,
+            finalized: false,
+        }
+    }
+
+    pub(crate) fn dummy_state(
+        nonce: u64,
+        btc_reserved: u64,
+        coins: &[CoinBalance],
+    ) -> DummyPoolState {
+        DummyPoolState {
+            nonce,
+            txid: Txid::from_bytes(&[nonce as u8; 32]).unwrap(),
+            coin_reserved: coins.to_vec(),
+            btc_reserved,
+            utxos: vec![],
+            attributes: "{}".to_string(),
+        }
+    }
+
     #[test]
     pub fn test_candid_and_bincode_serialize() {
         let state = DummyPoolState {
@@ -771,11 +2279,12 @@ pub mod test {
         let pool = Pool::<DummyPoolState> {
             metadata: Metadata {
                 key: Pubkey::from_raw(vec![2u8; 33]).unwrap(),
-                key_derivation_path: vec![vec![0; 32]],
+                key_derivation_path: vec![vec![0; 32]].into(),
                 name: "Test Pool".to_string(),
                 address: "test-address".to_string(),
             },
             states: vec![state.clone()],
+            finalized: false,
         };
         let bincode_serialized = pool.to_bytes();
         Pool::<DummyPoolState>::from_bytes(bincode_serialized);
@@ -791,16 +2300,492 @@ pub mod test {
         assert!(candid_deserialized.is_ok());
     }
 
+    #[test]
+    fn test_try_to_bytes() {
+        #[derive(Clone, Debug, Deserialize, Serialize)]
+        struct UnserializableState {
+            #[serde(serialize_with = "fail")]
+            inner: u64,
+        }
+
+        fn fail<S: serde::Serializer>(_: &u64, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("unserializable"))
+        }
+
+        let metadata = Metadata {
+            key: Pubkey::from_raw(vec![2u8; 33]).unwrap(),
+            key_derivation_path: vec![vec![0; 32]].into(),
+            name: "Test Pool".to_string(),
+            address: "test-address".to_string(),
+        };
+        let mut pool = Pool::<UnserializableState> {
+            metadata,
+            states: vec![],
+            finalized: false,
+        };
+        assert_eq!(pool.try_to_bytes().unwrap(), pool.to_bytes().to_vec());
+
+        pool.states.push(UnserializableState { inner: 1 });
+        assert_eq!(pool.try_to_bytes(), Err("unserializable".to_string()));
+    }
+
+    #[test]
+    fn test_candid_service_fragment() {
+        let fragment = candid_service_fragment();
+        assert!(fragment.contains("type Result = variant { Ok : text; Err : text };"));
+        assert!(fragment.contains("type Result_1 = variant { Ok; Err : text };"));
+        assert!(fragment.trim_end().ends_with(
+            r#"service : {
+  execute_tx : (ExecuteTxArgs) -> (Result);
+  get_pool_info : (GetPoolInfoArgs) -> (opt PoolInfo) query;
+  get_pool_list : () -> (vec PoolBasic) query;
+  new_block : (NewBlockInfo) -> (Result_1);
+  rollback_tx : (RollbackTxArgs) -> (Result_1);
+}"#
+        ));
+    }
+
+    #[test]
+    fn test_error_codes() {
+        for e in [
+            error::Error::PoolNotFound,
+            error::Error::SigningFailed("timeout".to_string()),
+            error::Error::AccessDenied,
+            error::Error::ReorgUnrecoverable,
+            error::Error::FeeTooHigh { fee: 2, max: 1 },
+            error::Error::UnconfirmedLimitReached { max: 3 },
+            error::Error::Custom(7, "custom".to_string()),
+        ] {
+            let message: String = e.clone().into();
+            assert!(message.starts_with(&format!("{}:", e.code())));
+        }
+        assert_eq!(
+            String::from(SdkError::AccessDenied),
+            "115:Access denied".to_string()
+        );
+    }
+
+    #[test]
+    fn test_state_attributes() {
+        let mut state = StateInfo::default();
+        assert_eq!(state.attribute::<u64>("fee_bps"), Ok(None));
+
+        state.set_attribute("fee_bps", &30u64).unwrap();
+        state.set_attribute("paused", &true).unwrap();
+        assert_eq!(state.attribute::<u64>("fee_bps"), Ok(Some(30)));
+        assert_eq!(state.attribute::<bool>("paused"), Ok(Some(true)));
+        assert!(state.attribute::<bool>("fee_bps").is_err());
+
+        state.attributes = "[1, 2]".to_string();
+        assert!(state.attribute::<u64>("fee_bps").is_err());
+        assert!(state.set_attribute("fee_bps", &30u64).is_err());
+        assert_eq!(state.attributes, "[1, 2]");
+    }
+
+    #[test]
+    fn test_pool_codec() {
+        #[derive(Clone, Debug, Deserialize, Serialize)]
+        struct StateV1 {
+            nonce: u64,
+        }
+
+        #[derive(Clone, Debug, Deserialize, Serialize)]
+        struct StateV2 {
+            nonce: u64,
+            #[serde(default)]
+            fee_rate: u64,
+        }
+
+        let pool = Pool::<StateV1> {
+            metadata: Metadata {
+                key: Pubkey::from_raw(vec![2u8; 33]).unwrap(),
+                key_derivation_path: vec![vec![0; 32]].into(),
+                name: "Test Pool".to_string(),
+                address: "test-address".to_string(),
+            },
+            states: vec![StateV1 { nonce: 1 }],
+            finalized: false,
+        };
+
+        let cbor = encode_pool(&pool, PoolCodec::Cbor).unwrap();
+        let decoded = decode_pool::<StateV2>(&cbor).unwrap();
+        assert_eq!(decoded.metadata, pool.metadata);
+        assert_eq!(decoded.states[0].nonce, 1);
+        assert_eq!(decoded.states[0].fee_rate, 0);
+
+        let bincode = encode_pool(&pool, PoolCodec::Bincode).unwrap();
+        assert_eq!(bincode, pool.to_bytes().to_vec());
+        let decoded = Pool::<StateV1>::from_bytes(bincode.into());
+        assert_eq!(decoded.states[0].nonce, 1);
+
+        // written before the version byte, without the trailing finalized flag
+        let mut legacy = bincode::serialize(&pool).unwrap();
+        legacy.pop();
+        assert_eq!(legacy[0], 66);
+        let decoded = decode_pool::<StateV1>(&legacy).unwrap();
+        assert_eq!(decoded.metadata, pool.metadata);
+        assert_eq!(decoded.states[0].nonce, 1);
+
+        // the x-only key of the pools generated by `request_p2tr_address`
+        let mut x_only = pool.clone();
+        x_only.metadata.key = Pubkey::from_raw([vec![0x00], vec![2u8; 32]].concat()).unwrap();
+        let mut legacy = bincode::serialize(&x_only).unwrap();
+        legacy.pop();
+        assert_eq!(legacy[0], 64);
+        let decoded = decode_pool::<StateV1>(&legacy).unwrap();
+        assert_eq!(decoded.metadata, x_only.metadata);
+        assert_eq!(decoded.states[0].nonce, 1);
+
+        assert_eq!(
+            decode_pool::<StateV1>(&[0x03, 0x00]).unwrap_err(),
+            "Unknown pool format version 3"
+        );
+        assert!(decode_pool::<StateV1>(&[]).is_err());
+    }
+
+    #[test]
+    fn test_empty_pool_roundtrip() {
+        // the pool left by `new_pool` before any action
+        let pool = dummy_pool("test-address", vec![]);
+
+        let bytes = pool.to_bytes().to_vec();
+        let metadata = bincode::serialize(&pool.metadata).unwrap();
+        assert_eq!(
+            bytes,
+            [
+                vec![BINCODE_POOL_TAG],
+                metadata.clone(),
+                0u64.to_le_bytes().to_vec(),
+                vec![0]
+            ]
+            .concat()
+        );
+        let decoded = Pool::<DummyPoolState>::from_bytes(bytes.into());
+        assert_eq!(decoded.metadata, pool.metadata);
+        assert!(decoded.states.is_empty());
+
+        // written before the finalized flag
+        let v1 = [
+            vec![BINCODE_V1_POOL_TAG],
+            metadata,
+            0u64.to_le_bytes().to_vec(),
+        ]
+        .concat();
+        let decoded = decode_pool::<DummyPoolState>(&v1).unwrap();
+        assert_eq!(decoded.metadata, pool.metadata);
+        assert!(!decoded.finalized);
+
+        let cbor = encode_pool(&pool, PoolCodec::Cbor).unwrap();
+        let decoded = decode_pool::<DummyPoolState>(&cbor).unwrap();
+        assert_eq!(decoded.metadata, pool.metadata);
+        assert!(decoded.states.is_empty());
+
+        let info = decoded.get_pool_info();
+        let state = StateInfo::default();
+        assert_eq!(info.key, pool.metadata.key);
+        assert_eq!(info.address, pool.metadata.address);
+        assert_eq!(info.nonce, state.nonce);
+        assert_eq!(info.coin_reserved, state.coin_reserved);
+        assert_eq!(info.btc_reserved, state.btc_reserved);
+        assert_eq!(info.utxos, state.utxos);
+        assert_eq!(info.attributes, state.attributes);
+    }
+
+    #[test]
+    fn test_verify_fee() {
+        use crate::types::bitcoin::{
+            Amount, OutPoint, Psbt, ScriptBuf, Transaction, TxIn, TxOut, absolute::LockTime,
+            transaction::Version,
+        };
+
+        let txout = |value: u64| TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: ScriptBuf::new(),
+        };
+        let pool_outpoint = OutPoint::new(Txid::from_bytes(&[1; 32]).unwrap().into(), 0);
+        let user_outpoint = OutPoint::new(Txid::from_bytes(&[2; 32]).unwrap().into(), 0);
+        let psbt = |outputs: &[u64]| {
+            let tx = Transaction {
+                version: Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: [pool_outpoint, user_outpoint]
+                    .into_iter()
+                    .map(|previous_output| TxIn {
+                        previous_output,
+                        ..Default::default()
+                    })
+                    .collect(),
+                output: outputs.iter().map(|v| txout(*v)).collect(),
+            };
+            let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+            psbt.inputs[0].witness_utxo = Some(txout(10_000));
+            psbt.inputs[1].witness_utxo = Some(txout(5_000));
+            psbt
+        };
+
+        assert_eq!(
+            verify_fee(&psbt(&[14_000]), &[pool_outpoint], 1_000),
+            Ok(1_000)
+        );
+        assert_eq!(
+            verify_fee(&psbt(&[14_000]), &[pool_outpoint], 999),
+            Err(error::Error::FeeTooHigh {
+                fee: 1_000,
+                max: 999
+            }
+            .to_string())
+        );
+        // the pool pays at most what it spends
+        assert_eq!(verify_fee(&psbt(&[]), &[pool_outpoint], 10_000), Ok(10_000));
+        assert_eq!(verify_fee(&psbt(&[]), &[], 0), Ok(0));
+        assert!(verify_fee(&psbt(&[15_001]), &[pool_outpoint], 1_000).is_err());
+
+        let mut missing = psbt(&[14_000]);
+        missing.inputs[1].witness_utxo = None;
+        assert!(verify_fee(&missing, &[pool_outpoint], 1_000).is_err());
+    }
+
+    #[test]
+    fn test_psbt_to_tx_hex() {
+        use crate::types::bitcoin::{
+            Amount, Psbt, ScriptBuf, Transaction, TxIn, TxOut, Witness, absolute::LockTime,
+            consensus::encode::deserialize_hex, transaction::Version,
+        };
+
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default(), TxIn::default()],
+            output: vec![TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx.clone()).unwrap();
+        psbt.inputs[0].final_script_witness = Some(Witness::from_slice(&[[1u8; 64]]));
+        assert_eq!(
+            psbt_to_tx_hex(&psbt),
+            Err("Input 1 is not finalized".to_string())
+        );
+
+        psbt.inputs[1].final_script_witness = Some(Witness::from_slice(&[[2u8; 64]]));
+        let extracted: Transaction = deserialize_hex(&psbt_to_tx_hex(&psbt).unwrap()).unwrap();
+        assert_eq!(extracted.compute_txid(), tx.compute_txid());
+        assert_eq!(extracted.input[1].witness.nth(0), Some(&[2u8; 64][..]));
+    }
+
+    #[test]
+    fn test_sweep_psbt() {
+        use crate::types::bitcoin::{Address, KnownHrp, XOnlyPublicKey, key::TweakedPublicKey};
+
+        let xonly = XOnlyPublicKey::from_str(
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let address = Address::p2tr_tweaked(
+            TweakedPublicKey::dangerous_assume_tweaked(xonly),
+            KnownHrp::Testnets,
+        )
+        .to_string();
+        let utxo = |vout: u32, sats: u64| Utxo {
+            txid: Txid::from_bytes(&[1; 32]).unwrap(),
+            vout,
+            coins: CoinBalances::new(),
+            sats,
+        };
+        let mut pool = Pool::<DummyPoolState> {
+            metadata: Metadata {
+                key: Pubkey::from_raw(vec![2u8; 33]).unwrap(),
+                key_derivation_path: vec![vec![0; 32]].into(),
+                name: "Test Pool".to_string(),
+                address: address.clone(),
+            },
+            states: vec![],
+            finalized: false,
+        };
+        assert!(pool.sweep_psbt(&address, 2, Network::Testnet4).is_err());
+
+        pool.states.push(DummyPoolState {
+            utxos: vec![utxo(0, 10_000), utxo(1, 5_000)],
+            ..dummy_state(1, 15_000, &[])
+        });
+        let psbt = pool.sweep_psbt(&address, 2, Network::Testnet4).unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 2);
+        assert_eq!(psbt.unsigned_tx.input[1].previous_output.vout, 1);
+        // 2 taproot key spend inputs and 1 taproot output take 169 vbytes
+        assert_eq!(psbt.unsigned_tx.output[0].value.to_sat(), 15_000 - 2 * 169);
+        assert_eq!(
+            psbt.inputs[0].witness_utxo.as_ref().unwrap().value.to_sat(),
+            10_000
+        );
+
+        assert!(pool.sweep_psbt(&address, 100, Network::Testnet4).is_err());
+        assert!(pool.sweep_psbt(&address, 2, Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn test_pool_iterator_double_ended() {
+        let mem = memory(7);
+        let mut storage = PoolStorage::<DummyPoolState>::init(mem.clone());
+        for address in ["a", "b", "c", "d"] {
+            storage.insert(address.to_string(), dummy_pool(address, vec![]));
+        }
+
+        let tail = iterator::<DummyPools>(mem.clone())
+            .rev()
+            .take(2)
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        assert_eq!(tail, vec!["d", "c"]);
+
+        let mut iter = iterator::<DummyPools>(mem);
+        assert_eq!(iter.next().map(|(k, _)| k), Some("a".to_string()));
+        assert_eq!(iter.next_back().map(|(k, _)| k), Some("d".to_string()));
+        assert_eq!(
+            iter.map(|(k, _)| k).collect::<Vec<_>>(),
+            vec!["b".to_string(), "c".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_validate_upgrade() {
+        #[derive(Clone, Debug, Deserialize, Serialize)]
+        struct OldState {
+            nonce: u64,
+        }
+
+        impl From<OldState> for DummyPoolState {
+            fn from(old: OldState) -> Self {
+                dummy_state(old.nonce, 0, &[])
+            }
+        }
+
+        struct UpgradePools;
+
+        impl Pools for UpgradePools {
+            type PoolState = DummyPoolState;
+
+            type BlockState = u32;
+
+            const POOL_STATE_MEMORY: u8 = 0;
+
+            const BLOCK_STATE_MEMORY: u8 = 1;
+
+            fn network() -> Network {
+                Network::Testnet4
+            }
+        }
+
+        impl Upgrade<UpgradePools> for UpgradePools {
+            type PoolState = OldState;
+
+            type BlockState = u16;
+
+            const POOL_STATE_MEMORY: u8 = 9;
+
+            const BLOCK_STATE_MEMORY: u8 = 10;
+        }
+
+        let (pool_memory, block_memory) = (memory(9), memory(10));
+        let mut pools = PoolStorage::<OldState>::init(pool_memory.clone());
+        let mut blocks = BlockStateStorage::<u16>::init(block_memory.clone());
+        for address in ["a", "b"] {
+            let pool = Pool {
+                metadata: dummy_pool(address, vec![]).metadata,
+                states: vec![OldState { nonce: 1 }],
+                finalized: false,
+            };
+            pools.insert(address.to_string(), pool);
+        }
+        blocks.insert(100, GlobalStateWrapper::new(7));
+
+        assert_eq!(
+            validate_upgrade::<UpgradePools, UpgradePools>(
+                pool_memory.clone(),
+                block_memory.clone()
+            ),
+            UpgradeValidation {
+                pool_count: 2,
+                block_count: 1,
+                first_failure: None,
+            }
+        );
+        // nothing is written
+        assert_eq!(pools.len(), 2);
+
+        let mut raw = BTreeMap::<String, Vec<u8>, Memory>::init(pool_memory.clone());
+        raw.insert("b".to_string(), vec![0xde, 0xad]);
+        let validation = validate_upgrade::<UpgradePools, UpgradePools>(pool_memory, block_memory);
+        assert_eq!(validation.pool_count, 1);
+        assert_eq!(validation.block_count, 0);
+        assert_eq!(
+            validation.first_failure.map(|(key, _)| key),
+            Some("b".to_string())
+        );
+    }
+
+    #[test]
+    fn test_pool_range_iterator() {
+        let mem = memory(8);
+        let mut storage = PoolStorage::<DummyPoolState>::init(mem.clone());
+        for address in ["a1", "b1", "b2", "b3", "c1"] {
+            storage.insert(address.to_string(), dummy_pool(address, vec![]));
+        }
+
+        let range = |start: &str, end: &str| {
+            range_iterator::<DummyPools>(mem.clone(), start.to_string(), end.to_string())
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(range("b", "c"), vec!["b1", "b2", "b3"]);
+        assert_eq!(range("b2", "z"), vec!["b2", "b3", "c1"]);
+        assert!(range("c", "b").is_empty());
+        assert_eq!(
+            range_iterator::<DummyPools>(mem, "b".to_string(), "c".to_string())
+                .next_back()
+                .map(|(k, _)| k),
+            Some("b3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_verify_address() {
+        use crate::types::bitcoin;
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let keypair = bitcoin::secp256k1::Keypair::from_seckey_slice(&secp, &[1u8; 32]).unwrap();
+        let (x_only, _) = keypair.x_only_public_key();
+        let mut metadata = Metadata {
+            key: Pubkey::from_raw([&[0x00], &x_only.serialize()[..]].concat()).unwrap(),
+            key_derivation_path: vec![vec![0; 32]].into(),
+            name: "Test Pool".to_string(),
+            address: String::new(),
+        };
+        let expected = bitcoin::Address::p2tr(&secp, x_only, None, bitcoin::Network::Testnet4);
+        assert_eq!(
+            metadata.derive_address(Network::Testnet4),
+            Ok(expected.to_string())
+        );
+        assert_eq!(metadata.verify_address(Network::Testnet4), Ok(false));
+
+        metadata.address = expected.to_string();
+        assert_eq!(metadata.verify_address(Network::Testnet4), Ok(true));
+        assert_eq!(metadata.verify_address(Network::Bitcoin), Ok(false));
+
+        metadata.key = Pubkey::from_raw(vec![0xff; 33]).unwrap();
+        assert!(metadata.verify_address(Network::Testnet4).is_err());
+    }
+
     #[test]
     fn test_pool_rollback() {
         let mut pool = Pool::<DummyPoolState> {
             metadata: Metadata {
                 key: Pubkey::from_raw(vec![2u8; 33]).unwrap(),
-                key_derivation_path: vec![vec![0; 32]],
+                key_derivation_path: vec![vec![0; 32]].into(),
                 name: "Test Pool".to_string(),
                 address: "test-address".to_string(),
             },
             states: vec![],
+            finalized: false,
         };
         let push_random_state_by_txid = |txid: &str, pool: &mut Pool<DummyPoolState>| {
             let txid = Txid::from_str(txid).unwrap();
@@ -862,4 +2847,673 @@ pub mod test {
         assert_eq!(pool.states[1], before_rollback_states[1]);
         assert_eq!(rollbacked_states[0], before_rollback_states[2]);
     }
+
+    #[test]
+    fn test_pool_reapply() {
+        let txid = |i: u8| Txid::from_bytes(&[i; 32]).unwrap();
+        let state = |i: u8| DummyPoolState {
+            nonce: i as u64,
+            txid: txid(i),
+            coin_reserved: vec![],
+            btc_reserved: 0,
+            utxos: vec![],
+            attributes: "{}".to_string(),
+        };
+        let mut pool = Pool::<DummyPoolState> {
+            metadata: Metadata {
+                key: Pubkey::from_raw(vec![2u8; 33]).unwrap(),
+                key_derivation_path: vec![vec![0; 32]].into(),
+                name: "Test Pool".to_string(),
+                address: "test-address".to_string(),
+            },
+            states: vec![state(1), state(2), state(3)],
+            finalized: false,
+        };
+
+        // the committed state is kept so the action could be skipped
+        assert!(pool.reapply(txid(2), 2).unwrap());
+        assert_eq!(pool.states, vec![state(1), state(2)]);
+
+        // a different tx at the same nonce must be executed again
+        assert!(!pool.reapply(txid(9), 2).unwrap());
+        assert_eq!(pool.states, vec![state(1)]);
+    }
+
+    #[test]
+    fn test_validate_derivation_path() {
+        assert!(validate_derivation_path(&[b"pool".to_vec()]).is_ok());
+        assert!(validate_derivation_path(&[b"pool".to_vec(), vec![0; 32]]).is_ok());
+        assert!(validate_derivation_path(&[]).is_err());
+        assert!(validate_derivation_path(&[vec![]]).is_err());
+        // the components aren't bounded, e.g. a long pool name
+        assert!(validate_derivation_path(&[vec![1; 1024]]).is_ok());
+        assert!(validate_derivation_path(&vec![vec![1]; 255]).is_ok());
+        assert!(validate_derivation_path(&vec![vec![1]; 256]).is_err());
+    }
+
+    #[test]
+    fn test_derivation_path() {
+        let path = "pool".parse::<DerivationPath>().unwrap();
+        assert_eq!(path.as_vec(), &vec![b"pool".to_vec()]);
+        assert!("".parse::<DerivationPath>().is_err());
+        assert!(DerivationPath::from_components(vec![]).is_err());
+        assert_eq!(
+            DerivationPath::from_components(vec![b"pool".to_vec()]),
+            Ok(path.clone())
+        );
+
+        // the same encoding as the raw path
+        let raw = path.clone().into_vec();
+        assert_eq!(
+            bincode::serialize(&path).unwrap(),
+            bincode::serialize(&raw).unwrap()
+        );
+        assert_eq!(
+            candid::encode_one(&path).unwrap(),
+            candid::encode_one(&raw).unwrap()
+        );
+        assert_eq!(DerivationPath::from(raw), path);
+    }
+
+    #[test]
+    fn test_generate_batch_validation() {
+        use std::future::Future;
+        use std::task::{Context, Poll, Waker};
+
+        // the validation must complete without reaching the management canister
+        let poll = |names_and_paths: Vec<(&str, &str)>| {
+            let names_and_paths = names_and_paths
+                .into_iter()
+                .map(|(name, path)| (name.to_string(), path.to_string()))
+                .collect();
+            let batch = std::pin::pin!(Metadata::generate_batch::<DummyPools>(names_and_paths));
+            batch.poll(&mut Context::from_waker(Waker::noop()))
+        };
+        assert_eq!(poll(vec![]), Poll::Ready(Ok(vec![])));
+        assert!(matches!(
+            poll(vec![("a", "pool-a"), ("b", "pool-b"), ("c", "pool-a")]),
+            Poll::Ready(Err(e)) if e.contains("pool c")
+        ));
+        assert!(matches!(
+            poll(vec![("a", "pool-a"), ("b", "")]),
+            Poll::Ready(Err(_))
+        ));
+    }
+
+    #[test]
+    fn test_state_diff() {
+        let rune = CoinId::rune(840000, 846);
+        let other = CoinId::rune(840000, 1);
+        let coin = |id: CoinId, value: u128| CoinBalance { id, value };
+        let utxo = |vout: u32| Utxo {
+            txid: Txid::default(),
+            vout,
+            coins: CoinBalances::new(),
+            sats: 546,
+        };
+        let prev = StateInfo {
+            coin_reserved: vec![coin(other, 5), coin(rune, 100)],
+            btc_reserved: 1_000,
+            utxos: vec![utxo(0), utxo(1)],
+            ..Default::default()
+        };
+        let next = StateInfo {
+            coin_reserved: vec![coin(rune, 60), coin(other, 5), coin(rune, 10)],
+            btc_reserved: 1_500,
+            utxos: vec![utxo(1), utxo(2)],
+            ..Default::default()
+        };
+
+        let delta = next.diff(&prev);
+        assert_eq!(delta.coin_changes, vec![(rune, -30)]);
+        assert_eq!(delta.btc_delta, 500);
+        assert_eq!(delta.added_utxos, vec![utxo(2)]);
+        assert_eq!(delta.removed_utxos, vec![utxo(0)]);
+        assert_eq!(next.diff(&next), StateDelta::default());
+    }
+
+    #[test]
+    fn test_derive_state_view() {
+        #[derive(Clone, Debug, ree_exchange_sdk_macro::StateView)]
+        struct DerivedState {
+            txid: Txid,
+            #[state_view(nonce)]
+            seq: u64,
+            coin_reserved: Vec<CoinBalance>,
+            btc_reserved: u64,
+            utxos: Vec<Utxo>,
+            attributes: String,
+            #[allow(dead_code)]
+            nonce: u32,
+        }
+
+        let expected = dummy_state(3, 1_000, &[]).inspect_state();
+        let mut state = DerivedState {
+            txid: expected.txid,
+            seq: expected.nonce,
+            coin_reserved: expected.coin_reserved.clone(),
+            btc_reserved: expected.btc_reserved,
+            utxos: expected.utxos.clone(),
+            attributes: expected.attributes.clone(),
+            nonce: 0,
+        };
+        assert_eq!(state.inspect_state(), expected);
+
+        state.set_nonce(4);
+        assert_eq!(state.seq, 4);
+        assert_eq!(state.nonce, 0);
+    }
+
+    #[test]
+    fn test_check_nonce() {
+        let mut pool = dummy_pool("test-address", vec![]);
+        assert!(pool.check_nonce(1).is_ok());
+        assert!(pool.check_nonce(0).is_err());
+
+        pool.states = (1..=2).map(|n| dummy_state(n, 0, &[])).collect();
+        assert!(pool.check_nonce(3).is_ok());
+        let err = pool.check_nonce(2).unwrap_err();
+        assert!(err.starts_with(&error::Error::NonceExpired.to_string()));
+        assert!(err.contains("expected 3 but got 2"));
+        assert!(pool.check_nonce(4).is_err());
+    }
+
+    #[test]
+    fn test_recent_states() {
+        let mut pool = dummy_pool("test-address", vec![]);
+        assert!(pool.recent_states(2).is_empty());
+
+        pool.states = (1..=3).map(|n| dummy_state(n, 0, &[])).collect();
+        assert!(pool.recent_states(0).is_empty());
+        assert_eq!(
+            pool.recent_states(2),
+            &[dummy_state(2, 0, &[]), dummy_state(3, 0, &[])]
+        );
+        assert_eq!(pool.recent_states(5), pool.states().as_slice());
+    }
+
+    #[test]
+    fn test_available_reserve() {
+        let rune = CoinId::rune(840000, 846);
+        let coin = |value| CoinBalance { id: rune, value };
+        let mut pool = dummy_pool("test-address", vec![]);
+        assert_eq!(pool.available_reserve(), (0, CoinBalances::new()));
+        assert_eq!(pool.confirmed_reserve(), (0, CoinBalances::new()));
+
+        pool.states = vec![
+            dummy_state(1, 1_000, &[coin(50)]),
+            dummy_state(2, 1_500, &[coin(20)]),
+        ];
+        assert_eq!(
+            pool.available_reserve(),
+            (1_500, CoinBalances::single(coin(20)))
+        );
+        // nothing is finalized yet
+        assert_eq!(pool.confirmed_reserve(), (0, CoinBalances::new()));
+
+        pool.finalize(Txid::from_bytes(&[1; 32]).unwrap()).unwrap();
+        assert_eq!(
+            pool.confirmed_reserve(),
+            (1_000, CoinBalances::single(coin(50)))
+        );
+        pool.finalize(Txid::from_bytes(&[2; 32]).unwrap()).unwrap();
+        assert_eq!(pool.confirmed_reserve(), pool.available_reserve());
+
+        // a fresh pool whose only state is unconfirmed
+        let mut fresh = dummy_pool("fresh-address", vec![]);
+        fresh.states_mut().push(dummy_state(1, 1_000, &[coin(50)]));
+        assert_eq!(fresh.confirmed_reserve(), (0, CoinBalances::new()));
+        assert_eq!(
+            fresh.available_reserve(),
+            (1_000, CoinBalances::single(coin(50)))
+        );
+        // and stays so after a roundtrip through the stable memory
+        let fresh = Pool::<DummyPoolState>::from_bytes(fresh.to_bytes());
+        assert_eq!(fresh.confirmed_reserve(), (0, CoinBalances::new()));
+    }
+
+    #[test]
+    fn test_replace_states() {
+        let mut pool = dummy_pool(
+            "test-address",
+            (1..=3).map(|n| dummy_state(n, 0, &[])).collect(),
+        );
+        let snapshot = vec![dummy_state(1, 10, &[]), dummy_state(2, 20, &[])];
+        let replaced = pool.replace_states(snapshot.clone()).unwrap();
+        assert_eq!(replaced.len(), 3);
+        assert_eq!(pool.states, snapshot);
+
+        let unordered = vec![dummy_state(2, 0, &[]), dummy_state(2, 0, &[])];
+        assert!(pool.replace_states(unordered).is_err());
+        assert_eq!(pool.states, snapshot);
+        assert_eq!(pool.replace_states(vec![]).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_pool_rollback_to_nonce() {
+        let mut pool = dummy_pool(
+            "test-address",
+            (1..=4).map(|n| dummy_state(n, 0, &[])).collect(),
+        );
+        assert!(pool.rollback_to_nonce(9).is_err());
+
+        let rollbacked = pool.rollback_to_nonce(2).unwrap();
+        assert_eq!(
+            rollbacked,
+            vec![dummy_state(4, 0, &[]), dummy_state(3, 0, &[])]
+        );
+        assert_eq!(
+            pool.states,
+            vec![dummy_state(1, 0, &[]), dummy_state(2, 0, &[])]
+        );
+        assert!(pool.rollback_to_nonce(2).unwrap().is_empty());
+
+        pool.states.push(dummy_state(2, 0, &[]));
+        assert!(pool.rollback_to_nonce(2).is_err());
+    }
+
+    #[test]
+    fn test_try_lock_pool() {
+        struct TimedPools;
+
+        impl Pools for TimedPools {
+            type PoolState = DummyPoolState;
+
+            type BlockState = u32;
+
+            const POOL_STATE_MEMORY: u8 = 0;
+
+            const BLOCK_STATE_MEMORY: u8 = 1;
+
+            fn network() -> Network {
+                Network::Testnet4
+            }
+
+            fn execute_lock_timeout_secs() -> Option<u64> {
+                Some(60)
+            }
+        }
+
+        let pool = "pool-a".to_string();
+        let pools = [pool.clone()];
+        let mut locks = std::collections::HashMap::new();
+        assert!(try_lock_pool::<DummyPools>(&mut locks, &pool, 100));
+        assert!(!try_lock_pool::<DummyPools>(&mut locks, &pool, 1_000_000));
+
+        assert!(!try_lock_pool::<TimedPools>(&mut locks, &pool, 160));
+        assert!(holds_locks(&locks, &pools, 100));
+        assert!(try_lock_pool::<TimedPools>(&mut locks, &pool, 161));
+        assert_eq!(locks.get(&pool), Some(&161));
+        // the execution holding the stale lock must not commit
+        assert!(!holds_locks(&locks, &pools, 100));
+        assert!(holds_locks(&locks, &pools, 161));
+        // nor the one whose lock is released by force_unlock_pool
+        locks.remove(&pool);
+        assert!(!holds_locks(&locks, &pools, 161));
+    }
+
+    #[test]
+    fn test_action_args_multiple_pools() {
+        let intention = |exchange_id: &str, pool_address: &str, spent: &[&str]| Intention {
+            exchange_id: exchange_id.to_string(),
+            action: "swap".to_string(),
+            action_params: String::new(),
+            pool_address: pool_address.to_string(),
+            nonce: 1,
+            pool_utxo_spent: spent.iter().map(|s| s.to_string()).collect(),
+            pool_utxo_received: vec![],
+            input_coins: vec![],
+            output_coins: vec![],
+        };
+        let txid = "51230fe70deae44a92f8f44a600585e3e57b8c8720a0b67c4c422f579d9ace2a";
+        let mut args = ActionArgs {
+            txid: Txid::default(),
+            initiator_address: "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx".to_string(),
+            intention: intention("DUMMY", "a", &[&format!("{txid}:0")]),
+            other_intentions: vec![
+                intention("DUMMY", "b", &[&format!("{txid}:1")]),
+                intention("OTHER", "c", &[&format!("{txid}:2")]),
+                intention("DUMMY", "a", &[]),
+            ],
+            unconfirmed_tx_count: 0,
+            is_reapply: false,
+            invoke_caller_principal: Principal::anonymous(),
+        };
+        assert_eq!(
+            args.all_pool_inputs()
+                .unwrap()
+                .iter()
+                .map(|outpoint| outpoint.vout)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            args.affected_pools(),
+            vec!["a".to_string(), "b".to_string()]
+        );
+
+        args.other_intentions[1].pool_utxo_spent = vec!["malformed".to_string()];
+        assert!(args.all_pool_inputs().is_err());
+    }
+
+    #[test]
+    fn test_try_lock_pools() {
+        let pools = ["a".to_string(), "b".to_string(), "c".to_string()];
+        let mut locks = std::collections::HashMap::new();
+        assert!(try_lock_pool::<DummyPools>(&mut locks, &pools[2], 100));
+        assert!(!try_lock_pools::<DummyPools>(&mut locks, &pools, 200));
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks.get(&pools[2]), Some(&100));
+
+        assert!(try_lock_pools::<DummyPools>(&mut locks, &pools[..2], 200));
+        assert_eq!(locks.get(&pools[0]), Some(&200));
+        assert_eq!(locks.get(&pools[1]), Some(&200));
+    }
+
+    #[test]
+    fn test_persistent_locks() {
+        struct PersistentPools;
+
+        impl Pools for PersistentPools {
+            type PoolState = DummyPoolState;
+
+            type BlockState = u32;
+
+            const POOL_STATE_MEMORY: u8 = 0;
+
+            const BLOCK_STATE_MEMORY: u8 = 1;
+
+            fn network() -> Network {
+                Network::Testnet4
+            }
+
+            fn persistent_locks() -> bool {
+                true
+            }
+        }
+
+        let mut stable = LockStorage::init(memory(104));
+        let mut locks = std::collections::HashMap::new();
+        assert!(try_lock_pools::<PersistentPools>(
+            &mut locks,
+            &["a".to_string(), "b".to_string()],
+            100
+        ));
+        persist_locks::<PersistentPools>(&mut stable, &locks);
+        locks.remove("a");
+        persist_locks::<PersistentPools>(&mut stable, &locks);
+        // e.g. after an upgrade
+        assert_eq!(
+            restore_locks::<PersistentPools>(&stable),
+            std::collections::HashMap::from([("b".to_string(), 100)])
+        );
+        assert!(restore_locks::<DummyPools>(&stable).is_empty());
+
+        persist_locks::<DummyPools>(&mut stable, &locks);
+        assert!(stable.is_empty());
+    }
+
+    #[test]
+    fn test_parse_address() {
+        let testnet = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+        let mainnet = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+        assert_eq!(
+            parse_address(testnet, Network::Testnet4)
+                .unwrap()
+                .to_string(),
+            testnet
+        );
+        assert!(parse_address(testnet, Network::Devnet).is_ok());
+        assert!(
+            parse_address(
+                "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080",
+                Network::Devnet
+            )
+            .is_ok()
+        );
+        assert!(parse_address(mainnet, Network::Devnet).is_err());
+        assert!(parse_address(mainnet, Network::Bitcoin).is_ok());
+        assert_eq!(
+            parse_address(mainnet, Network::Testnet4).unwrap_err(),
+            error::Error::NetworkMismatch(mainnet.to_string()).to_string()
+        );
+        assert!(parse_address(testnet, Network::Bitcoin).is_err());
+        assert!(
+            parse_address("not-an-address", Network::Bitcoin)
+                .unwrap_err()
+                .starts_with("Invalid address not-an-address")
+        );
+    }
+
+    #[test]
+    fn test_ensure_no_dust_pool_outputs() {
+        use crate::types::bitcoin::{
+            Amount, Psbt, ScriptBuf, Transaction, TxOut, absolute::LockTime, transaction::Version,
+        };
+
+        let pool_address = "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c";
+        let other = parse_address(
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            Network::Testnet4,
+        )
+        .unwrap()
+        .script_pubkey();
+        let pool = parse_address(pool_address, Network::Testnet4)
+            .unwrap()
+            .script_pubkey();
+        let psbt = |outputs: Vec<(ScriptBuf, u64)>| {
+            Psbt::from_unsigned_tx(Transaction {
+                version: Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: vec![],
+                output: outputs
+                    .into_iter()
+                    .map(|(script_pubkey, sats)| TxOut {
+                        value: Amount::from_sat(sats),
+                        script_pubkey,
+                    })
+                    .collect(),
+            })
+            .unwrap()
+        };
+        let check =
+            |outputs| ensure_no_dust_pool_outputs::<DummyPools>(&psbt(outputs), pool_address);
+        assert!(check(vec![(pool.clone(), 330), (other.clone(), 100)]).is_ok());
+        let err = check(vec![(other.clone(), 1_000), (pool.clone(), 329)]).unwrap_err();
+        assert!(err.starts_with(&error::Error::IllegalPsbt.to_string()));
+        assert!(err.contains("output 1 of 329 sats"));
+        assert!(
+            ensure_no_dust_pool_outputs::<DummyPools>(&psbt(vec![]), "not-an-address").is_err()
+        );
+    }
+
+    #[test]
+    fn test_ensure_network() {
+        use crate::types::bitcoin::{
+            Amount, Psbt, ScriptBuf, Transaction, TxOut, absolute::LockTime, opcodes,
+            script::Builder, transaction::Version,
+        };
+
+        let args_paying_to = |to: &str| ActionArgs {
+            txid: Txid::default(),
+            initiator_address: "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx".to_string(),
+            intention: Intention {
+                exchange_id: "DUMMY".to_string(),
+                action: "swap".to_string(),
+                action_params: String::new(),
+                pool_address: "test-address".to_string(),
+                nonce: 1,
+                pool_utxo_spent: vec![],
+                pool_utxo_received: vec![],
+                input_coins: vec![],
+                output_coins: vec![crate::types::OutputCoin {
+                    to: to.to_string(),
+                    coin: CoinBalance {
+                        id: crate::types::CoinId::btc(),
+                        value: 10_000,
+                    },
+                }],
+            },
+            other_intentions: vec![],
+            unconfirmed_tx_count: 0,
+            is_reapply: false,
+            invoke_caller_principal: Principal::anonymous(),
+        };
+        let psbt = |outputs: Vec<ScriptBuf>| {
+            Psbt::from_unsigned_tx(Transaction {
+                version: Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: vec![],
+                output: outputs
+                    .into_iter()
+                    .map(|script_pubkey| TxOut {
+                        value: Amount::from_sat(10_000),
+                        script_pubkey,
+                    })
+                    .collect(),
+            })
+            .unwrap()
+        };
+
+        let testnet = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+        let mainnet = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+        // the same witness program on each network
+        let script = parse_address(testnet, Network::Testnet4)
+            .unwrap()
+            .script_pubkey();
+        let regtest = crate::types::bitcoin::Address::from_script(
+            &script,
+            crate::types::bitcoin::Network::Regtest,
+        )
+        .unwrap()
+        .to_string();
+        let op_return = Builder::new()
+            .push_opcode(opcodes::all::OP_RETURN)
+            .push_slice(b"ree")
+            .into_script();
+        let paid = psbt(vec![op_return.clone(), script.clone()]);
+
+        let args = args_paying_to(testnet);
+        assert!(args.ensure_network(&paid, Network::Testnet4).is_ok());
+        assert!(args.ensure_network(&paid, Network::Devnet).is_ok());
+        assert!(args.ensure_network(&paid, Network::Bitcoin).is_err());
+
+        let args = args_paying_to(mainnet);
+        assert_eq!(
+            args.ensure_network(&paid, Network::Testnet4),
+            Err(error::Error::NetworkMismatch(mainnet.to_string()))
+        );
+        assert!(args.ensure_network(&paid, Network::Bitcoin).is_ok());
+
+        let args = args_paying_to(&regtest);
+        assert!(args.ensure_network(&paid, Network::Devnet).is_ok());
+        assert!(args.ensure_network(&paid, Network::Testnet4).is_err());
+
+        // the declared address isn't paid, only the OP_RETURN is left
+        assert_eq!(
+            args_paying_to(testnet).ensure_network(&psbt(vec![op_return]), Network::Testnet4),
+            Err(error::Error::IllegalPsbt)
+        );
+
+        // the intentions of other exchanges aren't checked
+        let mut args = args_paying_to(testnet);
+        args.other_intentions.push(Intention {
+            output_coins: args_paying_to(mainnet).intention.output_coins,
+            ..args.intention.clone()
+        });
+        assert!(args.ensure_network(&paid, Network::Testnet4).is_ok());
+
+        let malformed = args_paying_to("not-an-address");
+        assert!(malformed.ensure_network(&paid, Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn test_replay_pool() {
+        let addresses = ["test-address".to_string()];
+        let address = &addresses[0];
+        let pool = dummy_pool("test-address", vec![dummy_state(3, 1_000, &[])]);
+        assert!(replayed_pool::<DummyPoolState>(address).is_none());
+
+        let replaying = replay_pool(pool.clone()).unwrap();
+        let replayed = replayed_pool::<DummyPoolState>(address).unwrap();
+        assert_eq!(replayed.states, pool.states);
+        assert!(replayed_pool::<DummyPoolState>("other-address").is_none());
+        assert_eq!(
+            replay_pool(pool.clone()).err(),
+            Some(error::Error::PoolBeingExecuted)
+        );
+        // the replayed pool can't be executed meanwhile
+        let mut locks = std::collections::HashMap::new();
+        assert!(!try_lock_pools::<DummyPools>(&mut locks, &addresses, 100));
+        assert!(locks.is_empty());
+
+        drop(replaying);
+        assert!(replayed_pool::<DummyPoolState>(address).is_none());
+        assert!(try_lock_pools::<DummyPools>(&mut locks, &addresses, 100));
+    }
+
+    #[test]
+    fn test_ensure_reserves() {
+        let btc = crate::types::CoinId::btc();
+        let rune = crate::types::CoinId::rune(840000, 846);
+        let balances = |coins: &[(crate::types::CoinId, u128)]| {
+            let mut balances = CoinBalances::new();
+            for (id, value) in coins {
+                balances.add_coin(&CoinBalance {
+                    id: *id,
+                    value: *value,
+                });
+            }
+            balances
+        };
+        let available = balances(&[(btc, 10_000), (rune, 500)]);
+
+        assert!(ensure_reserves(&available, &CoinBalances::new()).is_ok());
+        assert!(ensure_reserves(&available, &balances(&[(btc, 10_000), (rune, 500)])).is_ok());
+
+        let err = ensure_reserves(&available, &balances(&[(btc, 10_001)])).unwrap_err();
+        assert_eq!(
+            err,
+            error::Error::InsufficientReserves {
+                coin: btc,
+                required: 10_001,
+                available: 10_000,
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "111:Insufficient reserves of 0:0: required 10001, available 10000, shortfall 1"
+        );
+        // constructed by hand without a shortfall
+        let err = error::Error::InsufficientReserves {
+            coin: btc,
+            required: 1,
+            available: 2,
+        };
+        assert!(err.to_string().ends_with("shortfall 0"));
+
+        let unknown = crate::types::CoinId::rune(840106, 129);
+        let err = ensure_reserves(
+            &available,
+            &balances(&[(btc, 100), (rune, 600), (unknown, 1)]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            error::Error::InsufficientReserves {
+                coin: rune,
+                required: 600,
+                available: 500,
+            }
+        );
+        let err = ensure_reserves(&available, &balances(&[(btc, 100), (unknown, 1)])).unwrap_err();
+        assert_eq!(
+            err,
+            error::Error::InsufficientReserves {
+                coin: unknown,
+                required: 1,
+                available: 0,
+            }
+        );
+    }
 }
@@ -0,0 +1,83 @@
+//! A leveled logging shim over `ic_cdk::println!`.
+//!
+//! The level is kept in the heap and defaults to `LogLevel::Debug`, so it's reset after upgrades.
+//! The generated `set_log_level` endpoint allows the controllers to change it at runtime.
+//!
+//! ```ignore
+//! ree_exchange_sdk::log!(LogLevel::Info, "pool {} created", address);
+//! ```
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+
+/// The severity of a log message, ordered from the most verbose.
+#[derive(
+    CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LogLevel::Debug => write!(f, "DEBUG"),
+            LogLevel::Info => write!(f, "INFO"),
+            LogLevel::Warn => write!(f, "WARN"),
+            LogLevel::Error => write!(f, "ERROR"),
+        }
+    }
+}
+
+thread_local! {
+    static LOG_LEVEL: Cell<LogLevel> = const { Cell::new(LogLevel::Debug) };
+}
+
+/// Sets the minimum level of the messages to print.
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.with(|l| l.set(level));
+}
+
+/// Returns the minimum level of the messages to print.
+pub fn log_level() -> LogLevel {
+    LOG_LEVEL.with(|l| l.get())
+}
+
+/// Returns true if the messages of `level` are printed.
+pub fn enabled(level: LogLevel) -> bool {
+    level >= log_level()
+}
+
+/// Prints the message prefixed by the level if it's not below the current level.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {{
+        let level: $crate::log::LogLevel = $level;
+        if $crate::log::enabled(level) {
+            $crate::types::ic_cdk::println!("[{}] {}", level, format_args!($($arg)+));
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(log_level(), LogLevel::Debug);
+        assert!(enabled(LogLevel::Debug));
+
+        set_log_level(LogLevel::Warn);
+        assert!(!enabled(LogLevel::Debug));
+        assert!(!enabled(LogLevel::Info));
+        assert!(enabled(LogLevel::Warn));
+        assert!(enabled(LogLevel::Error));
+        crate::log!(LogLevel::Info, "suppressed {}", 1);
+        crate::log!(LogLevel::Error, "printed {}", 2);
+    }
+}
@@ -0,0 +1,759 @@
+use crate::types::CoinId;
+use crate::*;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+
+/// The maximum number of entries returned by a single query.
+pub const MAX_QUERY_LIMIT: u32 = 100;
+
+fn reserve_of(state: &StateInfo, coin: &CoinId) -> u128 {
+    if *coin == CoinId::btc() {
+        state.btc_reserved as u128
+    } else {
+        state
+            .coin_reserved
+            .iter()
+            .filter(|balance| balance.id == *coin)
+            .map(|balance| balance.value)
+            .sum()
+    }
+}
+
+/// Returns at most `limit` pools ordered by the reserve of `coin` in their latest state, descending.
+///
+/// Every pool has to be visited, so the cost grows linearly with the number of pools.
+/// Only `limit` (capped by `MAX_QUERY_LIMIT`) entries are kept in memory during the iteration.
+pub fn top_pools<P>(
+    pools: &PoolStorage<P::PoolState>,
+    coin: CoinId,
+    limit: u32,
+) -> Vec<(PoolBasic, u128)>
+where
+    P: Pools,
+{
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    if limit == 0 {
+        return vec![];
+    }
+    let mut top = BinaryHeap::with_capacity(limit + 1);
+    for entry in pools.iter() {
+        let (address, pool) = entry.into_pair();
+        let reserve = pool
+            .last_state()
+            .map(|state| reserve_of(&state.inspect_state(), &coin))
+            .unwrap_or_default();
+        // ties are ordered by address
+        top.push(Reverse((
+            reserve,
+            Reverse(address),
+            pool.metadata().name.clone(),
+        )));
+        if top.len() > limit {
+            top.pop();
+        }
+    }
+    top.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((reserve, Reverse(address), name))| (PoolBasic { name, address }, reserve))
+        .collect()
+}
+
+/// Returns all the pools in the order of `P::list_order()`.
+pub fn pool_list<P>(pools: &PoolStorage<P::PoolState>) -> Vec<PoolBasic>
+where
+    P: Pools,
+{
+    let entries = pools.iter().map(|entry| entry.into_pair());
+    // the pools are visited by address, so the stable sorts keep ties ordered by address
+    match P::list_order() {
+        ListOrder::ByAddress => entries.map(|(_, pool)| pool.get_pool_basic()).collect(),
+        ListOrder::ByName => {
+            let mut list = entries
+                .map(|(_, pool)| pool.get_pool_basic())
+                .collect::<Vec<_>>();
+            list.sort_by(|a, b| a.name.cmp(&b.name));
+            list
+        }
+        ListOrder::ByBtcReserved => {
+            let mut list = entries
+                .map(|(_, pool)| {
+                    let reserved = pool
+                        .last_state()
+                        .map(|state| state.inspect_state().btc_reserved)
+                        .unwrap_or_default();
+                    (Reverse(reserved), pool.get_pool_basic())
+                })
+                .collect::<Vec<_>>();
+            list.sort_by_key(|(reserved, _)| *reserved);
+            list.into_iter().map(|(_, basic)| basic).collect()
+        }
+    }
+}
+
+/// Returns the reserves of every coin summed over the latest state of all pools, ordered by `CoinId`.
+///
+/// Coins without reserves are omitted and the sums saturate at `u128::MAX`.
+pub fn total_reserves<P>(pools: &PoolStorage<P::PoolState>) -> Vec<CoinBalance>
+where
+    P: Pools,
+{
+    let mut totals = BTreeMap::<CoinId, u128>::new();
+    let mut add = |id: CoinId, value: u128| {
+        let total = totals.entry(id).or_default();
+        *total = total.saturating_add(value);
+    };
+    for pool in pools.values() {
+        let Some(state) = pool.last_state().map(|state| state.inspect_state()) else {
+            continue;
+        };
+        add(CoinId::btc(), state.btc_reserved as u128);
+        for balance in state.coin_reserved {
+            add(balance.id, balance.value);
+        }
+    }
+    totals
+        .into_iter()
+        .filter(|(_, value)| *value > 0)
+        .map(|(id, value)| CoinBalance { id, value })
+        .collect()
+}
+
+/// Returns the info of the first pool whose metadata key matches `key`, compared by the x-only
+/// serialization so both the compressed and x-only forms of a key match.
+///
+/// There is no index by key, every pool is visited until a match is found, so it's only
+/// a convenience for exchanges with a small number of pools.
+pub fn pool_info_by_key<P>(pools: &PoolStorage<P::PoolState>, key: &Pubkey) -> Option<PoolInfo>
+where
+    P: Pools,
+{
+    let x_only = key.to_x_only_bytes();
+    pools
+        .values()
+        .find(|pool| pool.metadata().key.to_x_only_bytes() == x_only)
+        .map(|pool| pool.get_pool_info())
+}
+
+/// Returns the addresses of the pools whose stored address no longer matches the one derived from
+/// the metadata key, including those with an invalid key.
+pub fn audit_pool_addresses<P>(pools: &PoolStorage<P::PoolState>) -> Vec<String>
+where
+    P: Pools,
+{
+    pools
+        .iter()
+        .map(|entry| entry.into_pair())
+        .filter(|(_, pool)| {
+            !pool
+                .metadata()
+                .verify_address(P::network())
+                .unwrap_or(false)
+        })
+        .map(|(address, _)| address)
+        .collect()
+}
+
+/// Returns the pool whose latest state holds the UTXO at `outpoint`, formatted as `txid:vout`,
+/// or `None` if the outpoint is malformed or no pool holds it.
+///
+/// There is no index by UTXO, every pool is visited until a match is found, so it's meant for
+/// reconciliation rather than the hot paths of large exchanges.
+pub fn pool_by_utxo<P>(
+    pools: &PoolStorage<P::PoolState>,
+    outpoint: &str,
+) -> Option<(String, Pool<P::PoolState>)>
+where
+    P: Pools,
+{
+    let outpoint = outpoint.parse::<crate::types::bitcoin::OutPoint>().ok()?;
+    pools
+        .iter()
+        .map(|entry| entry.into_pair())
+        .find(|(_, pool)| {
+            pool.last_state().is_some_and(|state| {
+                state
+                    .inspect_state()
+                    .utxos
+                    .iter()
+                    .any(|utxo| crate::types::utxo_matches_outpoint(utxo, &outpoint))
+            })
+        })
+}
+
+/// Returns the UTXOs in the latest state of the pools whose address is greater than `after`,
+/// paired with the pool address, e.g. to plan the consolidation of dust UTXOs.
+///
+/// A page always holds all the UTXOs of a pool, the iteration stops at the pool reaching `limit`
+/// (capped by `MAX_QUERY_LIMIT`) UTXOs, so the last page may slightly exceed it. The next page
+/// starts after the address of the last entry, an empty page means all UTXOs are listed.
+pub fn all_utxos<P>(
+    pools: &PoolStorage<P::PoolState>,
+    after: Option<String>,
+    limit: u32,
+) -> Vec<(String, Utxo)>
+where
+    P: Pools,
+{
+    let limit = limit.min(MAX_QUERY_LIMIT) as usize;
+    let mut utxos = vec![];
+    if limit == 0 {
+        return utxos;
+    }
+    let range = match after {
+        Some(after) => pools.range((std::ops::Bound::Excluded(after), std::ops::Bound::Unbounded)),
+        None => pools.iter(),
+    };
+    for entry in range {
+        let (address, pool) = entry.into_pair();
+        let Some(state) = pool.last_state().map(|state| state.inspect_state()) else {
+            continue;
+        };
+        utxos.extend(state.utxos.into_iter().map(|utxo| (address.clone(), utxo)));
+        if utxos.len() >= limit {
+            break;
+        }
+    }
+    utxos
+}
+
+/// Returns at most `limit` (capped by `MAX_QUERY_LIMIT`) events with `seq` greater than `after_seq`.
+pub fn events(events: &EventStorage, after_seq: u64, limit: u32) -> Vec<ExchangeEvent> {
+    events
+        .values_range(after_seq.saturating_add(1)..)
+        .take(limit.min(MAX_QUERY_LIMIT) as usize)
+        .collect()
+}
+
+/// Returns the finalization configuration and the current window of the retained blocks.
+pub fn reorg_diagnostics<P>(
+    blocks: &BlockStorage,
+    unconfirmed: &UnconfirmedTxStorage,
+) -> ReorgDiagnostics
+where
+    P: Pools,
+{
+    let finalize_threshold = P::max_finalize_threshold();
+    let tip_height = blocks.keys().next_back();
+    ReorgDiagnostics {
+        finalize_threshold,
+        tip_height,
+        oldest_block: blocks.keys().next(),
+        confirmed_boundary: tip_height.and_then(|tip| (tip + 1).checked_sub(finalize_threshold)),
+        unconfirmed_count: unconfirmed.len(),
+    }
+}
+
+/// Returns the overview of the exchange, `now` is in seconds.
+pub fn exchange_status<P>(
+    pools: &PoolStorage<P::PoolState>,
+    blocks: &BlockStorage,
+    unconfirmed: &UnconfirmedTxStorage,
+    now: u64,
+) -> ExchangeStatus
+where
+    P: Pools,
+{
+    ExchangeStatus {
+        pool_count: pools.len(),
+        current_block_height: blocks.keys().next_back(),
+        unconfirmed_tx_count: unconfirmed.len(),
+        paused: crate::states::ensure_fresh_chain::<P>(blocks, now).is_err(),
+        network: P::network(),
+    }
+}
+
+/// Returns the number of confirmations of the transaction in the retained blocks relative to the tip.
+pub fn confirmations(blocks: &BlockStorage, txid: &Txid) -> Option<u32> {
+    let tip = blocks.keys().next_back()?;
+    blocks
+        .iter()
+        .rev()
+        .map(|entry| entry.into_pair())
+        .find(|(_, block)| block.txs.iter().any(|tx| tx.txid == *txid))
+        .map(|(height, _)| tip - height + 1)
+}
+
+/// Returns the lifecycle status of the transaction.
+///
+/// Once the block containing the transaction is pruned, the status is derived from the retained events,
+/// newest first, so `Unknown` means either finalized or never seen after the `Finalized` event is
+/// trimmed. Callers needing a durable answer should keep their own record of the finalized txids,
+/// e.g. from `Hook::on_state_finalized`.
+pub fn tx_status(
+    unconfirmed: &UnconfirmedTxStorage,
+    blocks: &BlockStorage,
+    events: &EventStorage,
+    txid: &Txid,
+) -> TxStatus {
+    if unconfirmed.contains_key(txid) {
+        return TxStatus::Unconfirmed;
+    }
+    for entry in blocks.iter().rev() {
+        let (height, block) = entry.into_pair();
+        if let Some(tx) = block.txs.iter().find(|tx| tx.txid == *txid) {
+            // the pools are dropped from the block once finalized
+            return if tx.pools.is_empty() {
+                TxStatus::Finalized
+            } else {
+                TxStatus::Confirmed { height }
+            };
+        }
+    }
+    if events
+        .values()
+        .rev()
+        .any(|event| event.kind == EventKind::Finalized && event.txid == *txid)
+    {
+        return TxStatus::Finalized;
+    }
+    TxStatus::Unknown
+}
+
+/// Returns at most `limit` (capped by `MAX_QUERY_LIMIT`) retained states of the pool, most recent first.
+pub fn pool_states<P>(
+    pools: &PoolStorage<P::PoolState>,
+    address: &String,
+    limit: u32,
+) -> Vec<StateInfo>
+where
+    P: Pools,
+{
+    pools
+        .get(address)
+        .map(|pool| {
+            pool.states()
+                .iter()
+                .rev()
+                .take(limit.min(MAX_QUERY_LIMIT) as usize)
+                .map(|state| state.inspect_state())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn test_top_pools() {
+        let rune = CoinId::rune(840000, 846);
+        let coin = |value: u128| CoinBalance { id: rune, value };
+        let mut pools = PoolStorage::<DummyPoolState>::init(memory(0));
+        pools.insert(
+            "a".to_string(),
+            dummy_pool("a", vec![dummy_state(1, 300, &[coin(10)])]),
+        );
+        pools.insert(
+            "b".to_string(),
+            dummy_pool(
+                "b",
+                vec![dummy_state(1, 100, &[]), dummy_state(2, 900, &[coin(30)])],
+            ),
+        );
+        pools.insert(
+            "c".to_string(),
+            dummy_pool("c", vec![dummy_state(1, 500, &[coin(20)])]),
+        );
+        pools.insert("d".to_string(), dummy_pool("d", vec![]));
+        pools.insert(
+            "e".to_string(),
+            dummy_pool("e", vec![dummy_state(1, 500, &[])]),
+        );
+
+        let top = top_pools::<DummyPools>(&pools, CoinId::btc(), 10);
+        let ranked = top
+            .iter()
+            .map(|(basic, reserve)| (basic.address.as_str(), *reserve))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranked,
+            vec![("b", 900), ("c", 500), ("e", 500), ("a", 300), ("d", 0)]
+        );
+        assert_eq!(top[0].0.name, "B");
+
+        let top = top_pools::<DummyPools>(&pools, rune, 2);
+        let ranked = top
+            .iter()
+            .map(|(basic, reserve)| (basic.address.as_str(), *reserve))
+            .collect::<Vec<_>>();
+        assert_eq!(ranked, vec![("b", 30), ("c", 20)]);
+
+        assert!(top_pools::<DummyPools>(&pools, rune, 0).is_empty());
+    }
+
+    #[test]
+    fn test_audit_pool_addresses() {
+        let mut pools = PoolStorage::<DummyPoolState>::init(memory(0));
+        assert!(audit_pool_addresses::<DummyPools>(&pools).is_empty());
+
+        let x_only = crate::types::bitcoin::XOnlyPublicKey::from_slice(&[
+            0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95, 0xce, 0x87,
+            0x0b, 0x07, 0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59, 0xf2, 0x81, 0x5b,
+            0x16, 0xf8, 0x17, 0x98,
+        ])
+        .unwrap();
+        let mut valid = dummy_pool("valid", vec![]);
+        valid.metadata.key = Pubkey::from_raw([&[0x00], &x_only.serialize()[..]].concat()).unwrap();
+        valid.metadata.address = valid
+            .metadata
+            .derive_address(DummyPools::network())
+            .unwrap();
+        let address = valid.metadata.address.clone();
+        pools.insert(address, valid);
+        pools.insert("drifted".to_string(), dummy_pool("drifted", vec![]));
+
+        assert_eq!(
+            audit_pool_addresses::<DummyPools>(&pools),
+            vec!["drifted".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_pool_info_by_key() {
+        let mut pools = PoolStorage::<DummyPoolState>::init(memory(0));
+        let key = |prefix: u8, x: u8| Pubkey::from_raw([&[prefix], &[x; 32][..]].concat()).unwrap();
+        assert!(pool_info_by_key::<DummyPools>(&pools, &key(0x02, 1)).is_none());
+
+        for (address, x) in [("a", 1), ("b", 2)] {
+            let mut pool = dummy_pool(address, vec![dummy_state(1, x as u64, &[])]);
+            pool.metadata.key = key(0x02, x);
+            pools.insert(address.to_string(), pool);
+        }
+        let info = pool_info_by_key::<DummyPools>(&pools, &key(0x02, 2)).unwrap();
+        assert_eq!(info.address, "b");
+        assert_eq!(info.btc_reserved, 2);
+        // the x-only form of the same key
+        let info = pool_info_by_key::<DummyPools>(&pools, &key(0x00, 1)).unwrap();
+        assert_eq!(info.address, "a");
+        assert!(pool_info_by_key::<DummyPools>(&pools, &key(0x02, 3)).is_none());
+    }
+
+    #[test]
+    fn test_pool_list() {
+        thread_local! {
+            static ORDER: std::cell::Cell<ListOrder> = const { std::cell::Cell::new(ListOrder::ByAddress) };
+        }
+        struct SortedPools;
+
+        impl Pools for SortedPools {
+            type PoolState = DummyPoolState;
+            type BlockState = u32;
+            const POOL_STATE_MEMORY: u8 = 0;
+            const BLOCK_STATE_MEMORY: u8 = 1;
+
+            fn network() -> Network {
+                Network::Testnet4
+            }
+
+            fn list_order() -> ListOrder {
+                ORDER.with(|order| order.get())
+            }
+        }
+
+        let mut pools = PoolStorage::<DummyPoolState>::init(memory(0));
+        for (address, name, btc) in [("a", "zeta", 100), ("b", "alpha", 300), ("c", "mid", 100)] {
+            let mut pool = dummy_pool(address, vec![dummy_state(1, btc, &[])]);
+            pool.metadata.name = name.to_string();
+            pools.insert(address.to_string(), pool);
+        }
+        pools.insert("d".to_string(), dummy_pool("d", vec![]));
+        let addresses = |order: ListOrder| {
+            ORDER.with(|o| o.set(order));
+            pool_list::<SortedPools>(&pools)
+                .into_iter()
+                .map(|basic| basic.address)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(addresses(ListOrder::ByAddress), ["a", "b", "c", "d"]);
+        // the dummy pool "d" is named "D"
+        assert_eq!(addresses(ListOrder::ByName), ["d", "b", "c", "a"]);
+        assert_eq!(addresses(ListOrder::ByBtcReserved), ["b", "a", "c", "d"]);
+    }
+
+    #[test]
+    fn test_total_reserves() {
+        let rune = CoinId::rune(840000, 846);
+        let coin = |value: u128| CoinBalance { id: rune, value };
+        let mut pools = PoolStorage::<DummyPoolState>::init(memory(0));
+        assert!(total_reserves::<DummyPools>(&pools).is_empty());
+
+        pools.insert(
+            "a".to_string(),
+            dummy_pool(
+                "a",
+                vec![
+                    dummy_state(1, 100, &[coin(5)]),
+                    dummy_state(2, 300, &[coin(10)]),
+                ],
+            ),
+        );
+        pools.insert(
+            "b".to_string(),
+            dummy_pool("b", vec![dummy_state(1, 500, &[coin(20)])]),
+        );
+        pools.insert("c".to_string(), dummy_pool("c", vec![]));
+
+        assert_eq!(
+            total_reserves::<DummyPools>(&pools),
+            vec![
+                CoinBalance {
+                    id: CoinId::btc(),
+                    value: 800
+                },
+                coin(30)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pool_by_utxo() {
+        let txid = Txid::from_bytes(&[7; 32]).unwrap();
+        let with_utxo = |address: &str, vout: u32| {
+            let mut state = dummy_state(1, 546, &[]);
+            state.utxos = vec![Utxo {
+                txid,
+                vout,
+                coins: CoinBalances::new(),
+                sats: 546,
+            }];
+            dummy_pool(address, vec![state])
+        };
+        let mut pools = PoolStorage::<DummyPoolState>::init(memory(0));
+        pools.insert("a".to_string(), with_utxo("a", 0));
+        pools.insert("b".to_string(), dummy_pool("b", vec![]));
+        pools.insert("c".to_string(), with_utxo("c", 1));
+
+        let (address, pool) = pool_by_utxo::<DummyPools>(&pools, &format!("{txid}:1")).unwrap();
+        assert_eq!(address, "c");
+        assert_eq!(pool.metadata().address, "c");
+        assert!(pool_by_utxo::<DummyPools>(&pools, &format!("{txid}:2")).is_none());
+        assert!(pool_by_utxo::<DummyPools>(&pools, "not-an-outpoint").is_none());
+    }
+
+    #[test]
+    fn test_all_utxos() {
+        let utxo = |vout: u32| Utxo {
+            txid: Txid::default(),
+            vout,
+            coins: CoinBalances::new(),
+            sats: 546,
+        };
+        let with_utxos = |address: &str, vouts: &[u32]| {
+            let mut state = dummy_state(1, 546 * vouts.len() as u64, &[]);
+            state.utxos = vouts.iter().map(|vout| utxo(*vout)).collect();
+            dummy_pool(address, vec![state])
+        };
+        let mut pools = PoolStorage::<DummyPoolState>::init(memory(0));
+        assert!(all_utxos::<DummyPools>(&pools, None, 10).is_empty());
+
+        pools.insert("a".to_string(), with_utxos("a", &[0, 1]));
+        pools.insert("b".to_string(), dummy_pool("b", vec![]));
+        pools.insert("c".to_string(), with_utxos("c", &[2]));
+        pools.insert("d".to_string(), with_utxos("d", &[3, 4]));
+
+        let all = all_utxos::<DummyPools>(&pools, None, 10);
+        assert_eq!(
+            all.iter()
+                .map(|(address, utxo)| (address.as_str(), utxo.vout))
+                .collect::<Vec<_>>(),
+            vec![("a", 0), ("a", 1), ("c", 2), ("d", 3), ("d", 4)]
+        );
+        // the pages hold whole pools
+        let page = all_utxos::<DummyPools>(&pools, None, 1);
+        assert_eq!(page, all[..2].to_vec());
+        let page = all_utxos::<DummyPools>(&pools, Some("a".to_string()), 2);
+        assert_eq!(page, all[2..].to_vec());
+        assert!(all_utxos::<DummyPools>(&pools, Some("d".to_string()), 2).is_empty());
+        assert!(all_utxos::<DummyPools>(&pools, None, 0).is_empty());
+    }
+
+    #[test]
+    fn test_reorg_diagnostics() {
+        let mut blocks = BlockStorage::init(memory(100));
+        let mut unconfirmed = UnconfirmedTxStorage::init(memory(101));
+        let empty = reorg_diagnostics::<DummyPools>(&blocks, &unconfirmed);
+        assert_eq!(empty.tip_height, None);
+        assert_eq!(empty.confirmed_boundary, None);
+
+        for height in 100..=130 {
+            blocks.insert(
+                height,
+                Block {
+                    block_height: height,
+                    block_hash: format!("{:064x}", height),
+                    block_timestamp: height as u64,
+                    txs: vec![],
+                },
+            );
+        }
+        let txid = Txid::from_bytes(&[1; 32]).unwrap();
+        unconfirmed.insert(
+            txid,
+            TxRecord {
+                txid,
+                pools: vec!["a".to_string()],
+            },
+        );
+        assert_eq!(
+            reorg_diagnostics::<DummyPools>(&blocks, &unconfirmed),
+            ReorgDiagnostics {
+                finalize_threshold: 60,
+                tip_height: Some(130),
+                oldest_block: Some(100),
+                confirmed_boundary: Some(71),
+                unconfirmed_count: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_exchange_status() {
+        struct SilentPools;
+
+        impl Pools for SilentPools {
+            type PoolState = DummyPoolState;
+
+            type BlockState = u32;
+
+            const POOL_STATE_MEMORY: u8 = 0;
+
+            const BLOCK_STATE_MEMORY: u8 = 1;
+
+            fn network() -> Network {
+                Network::Bitcoin
+            }
+
+            fn max_block_silence_secs() -> Option<u64> {
+                Some(600)
+            }
+        }
+
+        impl Hook for SilentPools {}
+
+        let mut pools = PoolStorage::<DummyPoolState>::init(memory(0));
+        let mut blocks = BlockStorage::init(memory(100));
+        let unconfirmed = UnconfirmedTxStorage::init(memory(101));
+        pools.insert("a".to_string(), dummy_pool("a", vec![]));
+        blocks.insert(
+            100,
+            Block {
+                block_height: 100,
+                block_hash: format!("{:064x}", 100),
+                block_timestamp: 1_000,
+                txs: vec![],
+            },
+        );
+
+        let status = exchange_status::<SilentPools>(&pools, &blocks, &unconfirmed, 1_600);
+        assert_eq!(
+            status,
+            ExchangeStatus {
+                pool_count: 1,
+                current_block_height: Some(100),
+                unconfirmed_tx_count: 0,
+                paused: false,
+                network: Network::Bitcoin,
+            }
+        );
+        assert!(exchange_status::<SilentPools>(&pools, &blocks, &unconfirmed, 1_601).paused);
+        assert!(!exchange_status::<DummyPools>(&pools, &blocks, &unconfirmed, 1_601).paused);
+    }
+
+    #[test]
+    fn test_confirmations() {
+        let mut blocks = BlockStorage::init(memory(100));
+        let txid = Txid::from_bytes(&[1; 32]).unwrap();
+        assert_eq!(confirmations(&blocks, &txid), None);
+
+        for height in 100..=102 {
+            let txs = if height == 101 {
+                vec![TxRecord {
+                    txid,
+                    pools: vec!["a".to_string()],
+                }]
+            } else {
+                vec![]
+            };
+            blocks.insert(
+                height,
+                Block {
+                    block_height: height,
+                    block_hash: format!("{:064x}", height),
+                    block_timestamp: height as u64,
+                    txs,
+                },
+            );
+        }
+        assert_eq!(confirmations(&blocks, &txid), Some(2));
+        assert_eq!(
+            confirmations(&blocks, &Txid::from_bytes(&[2; 32]).unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_tx_status() {
+        let mut unconfirmed = UnconfirmedTxStorage::init(memory(101));
+        let mut blocks = BlockStorage::init(memory(100));
+        let mut events = EventStorage::init(memory(103));
+        let txid = |n: u8| Txid::from_bytes(&[n; 32]).unwrap();
+        let record = |n: u8, pools: &[&str]| TxRecord {
+            txid: txid(n),
+            pools: pools.iter().map(|p| p.to_string()).collect(),
+        };
+        unconfirmed.insert(txid(1), record(1, &["a"]));
+        blocks.insert(
+            100,
+            Block {
+                block_height: 100,
+                block_hash: format!("{:064x}", 100),
+                block_timestamp: 100,
+                txs: vec![record(2, &["a"]), record(3, &[])],
+            },
+        );
+        events.insert(
+            1,
+            ExchangeEvent {
+                seq: 1,
+                kind: EventKind::Finalized,
+                txid: txid(4),
+                pool: "a".to_string(),
+                height: Some(90),
+            },
+        );
+        let status = |n: u8| tx_status(&unconfirmed, &blocks, &events, &txid(n));
+
+        assert_eq!(status(1), TxStatus::Unconfirmed);
+        assert_eq!(status(2), TxStatus::Confirmed { height: 100 });
+        assert_eq!(status(3), TxStatus::Finalized);
+        assert_eq!(status(4), TxStatus::Finalized);
+        assert_eq!(status(5), TxStatus::Unknown);
+
+        // the finalized tx can't be told from an unseen one once its event is trimmed
+        events.pop_first();
+        assert_eq!(
+            tx_status(&unconfirmed, &blocks, &events, &txid(4)),
+            TxStatus::Unknown
+        );
+    }
+
+    #[test]
+    fn test_pool_states() {
+        let mut pools = PoolStorage::<DummyPoolState>::init(memory(0));
+        let states = (1..=3).map(|nonce| dummy_state(nonce, 0, &[])).collect();
+        pools.insert("a".to_string(), dummy_pool("a", states));
+        let nonces = |limit: u32| {
+            pool_states::<DummyPools>(&pools, &"a".to_string(), limit)
+                .into_iter()
+                .map(|state| state.nonce)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(nonces(10), vec![3, 2, 1]);
+        assert_eq!(nonces(2), vec![3, 2]);
+        assert!(nonces(0).is_empty());
+        assert!(pool_states::<DummyPools>(&pools, &"b".to_string(), 10).is_empty());
+    }
+}
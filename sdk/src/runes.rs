@@ -0,0 +1,191 @@
+//! A minimal runestone codec for the edicts transferring runes to the transaction outputs.
+//!
+//! A runestone is an `OP_RETURN OP_13` output followed by data pushes, the concatenated
+//! payload is a sequence of LEB128 integers. The edicts come after the `Body` tag, each of them
+//! is `(block delta, tx delta, amount, output)` where the rune ids are delta-encoded in ascending order.
+//! reference: <https://docs.ordinals.com/runes/specification.html>
+//!
+//! ```ignore
+//! let script = runes::build_runestone(&[(CoinId::rune(840000, 846), 1000, 1)]);
+//! assert_eq!(runes::parse_runestone(&script)?, vec![(CoinId::rune(840000, 846), 1000, 1)]);
+//! ```
+
+use crate::types::{
+    CoinId,
+    bitcoin::{
+        Script, ScriptBuf,
+        opcodes::all::{OP_PUSHNUM_13, OP_RETURN},
+        script::{Builder, Instruction, PushBytes},
+    },
+};
+
+/// The tag after which all the remaining integers are edicts.
+const TAG_BODY: u128 = 0;
+
+/// The maximum size of a single data push allowed by the standardness rules.
+const MAX_PUSH_SIZE: usize = 520;
+
+fn encode_varint(mut n: u128, buf: &mut Vec<u8>) {
+    while n >> 7 > 0 {
+        buf.push((n & 0x7f) as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn decode_varints(payload: &[u8]) -> Result<Vec<u128>, String> {
+    let mut integers = vec![];
+    let mut n = 0u128;
+    let mut shift = 0;
+    for byte in payload.iter() {
+        if shift > 126 || (shift == 126 && byte & 0x7c != 0) {
+            return Err("Runestone varint overflows u128".to_string());
+        }
+        n |= ((byte & 0x7f) as u128) << shift;
+        if byte & 0x80 == 0 {
+            integers.push(n);
+            n = 0;
+            shift = 0;
+        } else {
+            shift += 7;
+        }
+    }
+    if shift != 0 {
+        return Err("Runestone payload ends with a truncated varint".to_string());
+    }
+    Ok(integers)
+}
+
+/// Builds the runestone script moving `amount` of each rune to the `output` index,
+/// the edicts are given as `(rune id, amount, output)` in any order.
+pub fn build_runestone(edicts: &[(CoinId, u128, u32)]) -> ScriptBuf {
+    let mut edicts = edicts.to_vec();
+    edicts.sort_by_key(|(id, _, _)| *id);
+    let mut payload = vec![];
+    encode_varint(TAG_BODY, &mut payload);
+    let mut previous = CoinId::btc();
+    for (id, amount, output) in edicts {
+        let block_delta = id.block - previous.block;
+        let tx_delta = if block_delta == 0 {
+            id.tx - previous.tx
+        } else {
+            id.tx
+        };
+        encode_varint(block_delta as u128, &mut payload);
+        encode_varint(tx_delta as u128, &mut payload);
+        encode_varint(amount, &mut payload);
+        encode_varint(output as u128, &mut payload);
+        previous = id;
+    }
+    let mut builder = Builder::new()
+        .push_opcode(OP_RETURN)
+        .push_opcode(OP_PUSHNUM_13);
+    for chunk in payload.chunks(MAX_PUSH_SIZE) {
+        builder =
+            builder.push_slice(<&PushBytes>::try_from(chunk).expect("chunk within 520 bytes"));
+    }
+    builder.into_script()
+}
+
+/// Parses the edicts of the runestone script as `(rune id, amount, output)` in the encoded order.
+///
+/// Returns an error if the script isn't a runestone or is malformed, e.g. a cenotaph whose
+/// runes would be burned instead of transferred. The fields before the edicts are skipped.
+pub fn parse_runestone(script: &Script) -> Result<Vec<(CoinId, u128, u32)>, String> {
+    let mut instructions = script.instructions();
+    match (instructions.next(), instructions.next()) {
+        (Some(Ok(Instruction::Op(OP_RETURN))), Some(Ok(Instruction::Op(OP_PUSHNUM_13)))) => {}
+        _ => return Err("Not a runestone".to_string()),
+    }
+    let mut payload = vec![];
+    for instruction in instructions {
+        match instruction.map_err(|e| e.to_string())? {
+            Instruction::PushBytes(data) => payload.extend_from_slice(data.as_bytes()),
+            Instruction::Op(op) => return Err(format!("Unexpected {op} in runestone")),
+        }
+    }
+    let integers = decode_varints(&payload)?;
+    let mut fields = integers.iter();
+    loop {
+        match fields.next() {
+            None => return Ok(vec![]),
+            Some(&TAG_BODY) => break,
+            Some(tag) => {
+                fields
+                    .next()
+                    .ok_or(format!("Runestone field {tag} without value"))?;
+            }
+        }
+    }
+    let body = fields.as_slice();
+    if body.len() % 4 != 0 {
+        return Err("Runestone edicts are truncated".to_string());
+    }
+    let mut edicts = vec![];
+    let mut previous = CoinId::btc();
+    for edict in body.chunks(4) {
+        let overflow = || "Runestone edict overflows".to_string();
+        let block_delta = u64::try_from(edict[0]).map_err(|_| overflow())?;
+        let tx_delta = u32::try_from(edict[1]).map_err(|_| overflow())?;
+        let block = previous
+            .block
+            .checked_add(block_delta)
+            .ok_or_else(overflow)?;
+        let tx = if block_delta == 0 {
+            previous.tx.checked_add(tx_delta).ok_or_else(overflow)?
+        } else {
+            tx_delta
+        };
+        let output = u32::try_from(edict[3]).map_err(|_| overflow())?;
+        let id = CoinId::rune(block, tx);
+        edicts.push((id, edict[2], output));
+        previous = id;
+    }
+    Ok(edicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runestone_roundtrip() {
+        let rune = CoinId::rune(840000, 846);
+        let script = build_runestone(&[(rune, 1000, 1)]);
+        assert_eq!(
+            script.as_bytes(),
+            &[
+                0x6a, 0x5d, 0x09, 0x00, 0xc0, 0xa2, 0x33, 0xce, 0x06, 0xe8, 0x07, 0x01
+            ]
+        );
+        assert_eq!(parse_runestone(&script), Ok(vec![(rune, 1000, 1)]));
+
+        let edicts = vec![
+            (CoinId::rune(840000, 846), u128::MAX, 2),
+            (CoinId::rune(840000, 1), 5, 0),
+            (CoinId::rune(900000, 3), 7, 1),
+        ];
+        let mut sorted = edicts.clone();
+        sorted.sort_by_key(|(id, _, _)| *id);
+        assert_eq!(parse_runestone(&build_runestone(&edicts)), Ok(sorted));
+    }
+
+    #[test]
+    fn test_parse_malformed_runestone() {
+        assert!(parse_runestone(&ScriptBuf::new()).is_err());
+        assert!(parse_runestone(&ScriptBuf::new_op_return([0u8; 4])).is_err());
+
+        let script = build_runestone(&[(CoinId::rune(840000, 846), 1000, 1)]);
+        // drop the output of the edict
+        let mut truncated = script.to_bytes();
+        truncated.pop();
+        truncated[2] -= 1;
+        assert!(parse_runestone(&ScriptBuf::from_bytes(truncated)).is_err());
+        // a varint with the continuation bit at the end
+        let dangling = ScriptBuf::from_bytes(vec![0x6a, 0x5d, 0x02, 0x00, 0x80]);
+        assert!(parse_runestone(&dangling).is_err());
+        // the fields without edicts
+        let no_body = ScriptBuf::from_bytes(vec![0x6a, 0x5d, 0x02, 0x16, 0x01]);
+        assert_eq!(parse_runestone(&no_body), Ok(vec![]));
+    }
+}
@@ -1,15 +1,21 @@
-use crate::Network;
 use crate::types::{
     Pubkey, Utxo,
     bitcoin::{
-        self, OutPoint, TapSighashType, Witness,
+        self, TapLeafHash, TapSighash, TapSighashType, TxOut, Witness,
+        hashes::Hash,
         psbt::Psbt,
         sighash::{Prevouts, SighashCache},
-        {key::TapTweak, secp256k1::Secp256k1},
+        taproot::ControlBlock,
+        {
+            key::TapTweak,
+            secp256k1::{Message, Secp256k1, XOnlyPublicKey},
+        },
     },
 };
+use crate::{DerivationPath, Network};
 use candid::{CandidType, Principal};
 use ic_cdk::management_canister::{self, SchnorrAlgorithm, SchnorrKeyId, SchnorrPublicKeyArgs};
+use ic_stable_structures::{Storable, storable::Bound};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 
@@ -41,41 +47,94 @@ struct ManagementCanisterSignatureReply {
 
 const MGMT_CANISTER_ID: &str = "aaaaa-aa";
 
+/// The cycles attached to each `sign_with_schnorr` call by default.
+pub const DEFAULT_SIGN_CYCLES: u128 = 26_153_846_153;
+
+/// The configuration of the IC chain-key signing.
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SchnorrConfig {
+    /// The cycles attached to each `sign_with_schnorr` call, the unused part is refunded.
+    pub sign_cycles: u128,
+    /// Whether the key path spend signatures are verified against the spent output key right
+    /// after signing, see `verify_p2tr_key_spend`. It costs a signature verification per input.
+    pub verify_after_sign: bool,
+}
+
+impl Default for SchnorrConfig {
+    fn default() -> Self {
+        Self {
+            sign_cycles: DEFAULT_SIGN_CYCLES,
+            verify_after_sign: true,
+        }
+    }
+}
+
+impl Storable for SchnorrConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(bincode::serialize(self).unwrap())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<'_, [u8]>) -> Self {
+        bincode::deserialize(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static SCHNORR_CONFIG: std::cell::Cell<SchnorrConfig> = std::cell::Cell::new(SchnorrConfig::default());
+}
+
+/// Sets the signing configuration used by this module, it's kept in the heap.
+/// The generated `set_schnorr_config` also persists it in the stable memory, which is the source of truth:
+/// the generated `restore_after_upgrade` of the `Pools` type copies it back to the heap after upgrades.
+pub fn set_schnorr_config(config: SchnorrConfig) {
+    SCHNORR_CONFIG.with(|c| c.set(config));
+}
+
+/// Returns the current signing configuration.
+pub fn schnorr_config() -> SchnorrConfig {
+    SCHNORR_CONFIG.with(|c| c.get())
+}
+
 fn mgmt_canister_id() -> CanisterId {
     CanisterId::from_text(MGMT_CANISTER_ID).unwrap()
 }
 
+/// Builds the BIP-341 auxiliary input which makes the IC chain-key API sign with the key tweaked
+/// by the merkle root, an empty root means the key path spend without script tree.
+fn bip341_aux(merkle_root: Vec<u8>) -> Result<SignWithSchnorrAux, String> {
+    if merkle_root.len() != 32 && !merkle_root.is_empty() {
+        return Err(format!(
+            "merkle tree root bytes must be 0 or 32 bytes long but got {}",
+            merkle_root.len()
+        ));
+    }
+    Ok(SignWithSchnorrAux::Bip341(SignWithBip341Aux {
+        merkle_root_hash: ByteBuf::from(merkle_root),
+    }))
+}
+
 /// sign the provided message using the IC chain-key API.
+/// Without `aux` the message is signed by the untweaked key, e.g. for the script path spend.
 async fn sign_with_schnorr(
     message: Vec<u8>,
     network: Network,
-    derivation_path: Vec<Vec<u8>>,
-    merkle_root: Option<Vec<u8>>,
+    derivation_path: DerivationPath,
+    aux: Option<SignWithSchnorrAux>,
 ) -> Result<Vec<u8>, String> {
     let key_name = match network {
         Network::Bitcoin => "key_1",
         Network::Testnet4 => "test_key_1",
         Network::Devnet => "test_key_1",
     };
-    let merkle_root_hash = merkle_root
-        .map(|bytes| {
-            if bytes.len() == 32 || bytes.is_empty() {
-                Ok(ByteBuf::from(bytes))
-            } else {
-                Err(format!(
-                    "merkle tree root bytes must be 0 or 32 bytes long but got {}",
-                    bytes.len()
-                ))
-            }
-        })
-        .transpose()?
-        .unwrap_or_default();
-    let aux = Some(SignWithSchnorrAux::Bip341(SignWithBip341Aux {
-        merkle_root_hash,
-    }));
     let request = ManagementCanisterSignatureRequest {
         message,
-        derivation_path,
+        derivation_path: derivation_path.into_vec(),
         key_id: SchnorrKeyId {
             algorithm: SchnorrAlgorithm::Bip340secp256k1,
             name: key_name.to_string(),
@@ -83,11 +142,11 @@ async fn sign_with_schnorr(
         aux,
     };
     #[allow(deprecated)]
-    let (reply,): (ManagementCanisterSignatureReply,) = ic_cdk::api::call::call_with_payment(
+    let (reply,): (ManagementCanisterSignatureReply,) = ic_cdk::api::call::call_with_payment128(
         mgmt_canister_id(),
         "sign_with_schnorr",
         (request,),
-        26_153_846_153,
+        schnorr_config().sign_cycles,
     )
     .await
     .map_err(|e| format!("sign_with_schnorr failed {e:?}"))?;
@@ -99,12 +158,16 @@ async fn sign_with_schnorr(
 pub async fn sign_p2tr_key_spend(
     digest: impl AsRef<[u8; 32]>,
     network: Network,
-    derivation_path: Vec<Vec<u8>>,
+    derivation_path: impl Into<DerivationPath>,
 ) -> Result<Vec<u8>, String> {
-    let signature =
-        self::sign_with_schnorr(digest.as_ref().to_vec(), network, derivation_path, None)
-            .await
-            .map_err(|e| e.to_string())?;
+    let signature = self::sign_with_schnorr(
+        digest.as_ref().to_vec(),
+        network,
+        derivation_path.into(),
+        Some(bip341_aux(vec![])?),
+    )
+    .await
+    .map_err(|e| crate::error::Error::SigningFailed(e.to_string()))?;
     Ok(signature)
 }
 
@@ -112,12 +175,16 @@ pub async fn sign_p2tr_key_spend(
 pub async fn sign_p2tr_prehashed(
     digest: impl AsRef<[u8; 32]>,
     network: Network,
-    derivation_path: Vec<Vec<u8>>,
+    derivation_path: impl Into<DerivationPath>,
 ) -> Result<Vec<u8>, String> {
-    let signature =
-        self::sign_with_schnorr(digest.as_ref().to_vec(), network, derivation_path, None)
-            .await
-            .map_err(|e| e.to_string())?;
+    let signature = self::sign_with_schnorr(
+        digest.as_ref().to_vec(),
+        network,
+        derivation_path.into(),
+        Some(bip341_aux(vec![])?),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
     Ok(signature)
 }
 
@@ -129,10 +196,32 @@ pub fn tweak_pubkey_with_empty(untweaked: Pubkey) -> Pubkey {
     Pubkey::from_raw([&[0x00], &raw[..]].concat()).expect("tweaked 33bytes; qed")
 }
 
+/// Verifies the key path spend signature of `sighash` against the output key of the spent P2TR
+/// output, i.e. `tweak_pubkey_with_empty(Metadata::key)` for the pool addresses. A chain-key
+/// failure or a wrong derivation path is caught here instead of at the broadcast.
+pub fn verify_p2tr_key_spend(
+    prevout: &TxOut,
+    sighash: &TapSighash,
+    signature: &bitcoin::secp256k1::schnorr::Signature,
+) -> Result<(), String> {
+    if !prevout.script_pubkey.is_p2tr() {
+        return Err(format!(
+            "the spent output {} isn't P2TR",
+            prevout.script_pubkey
+        ));
+    }
+    let output_key = XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..])
+        .map_err(|e| format!("invalid P2TR output key: {e}"))?;
+    let message = Message::from_digest(sighash.to_byte_array());
+    Secp256k1::verification_only()
+        .verify_schnorr(signature, &message, &output_key)
+        .map_err(|e| format!("invalid signature for the output key {output_key}: {e}"))
+}
+
 /// request the IC chain-key API to generate a P2TR address
 /// reference: <https://internetcomputer.org/docs/references/t-sigs-how-it-works#key-derivation>
 pub async fn request_p2tr_address(
-    derivation_path: Vec<Vec<u8>>,
+    derivation_path: impl Into<DerivationPath>,
     network: Network,
 ) -> Result<(Pubkey, Pubkey, bitcoin::Address), String> {
     // validate_schnorr_key_name(&schnorr_key_name)?;
@@ -143,7 +232,7 @@ pub async fn request_p2tr_address(
     };
     let arg = SchnorrPublicKeyArgs {
         canister_id: None,
-        derivation_path,
+        derivation_path: derivation_path.into().into_vec(),
         key_id: SchnorrKeyId {
             algorithm: SchnorrAlgorithm::Bip340secp256k1,
             name: key_name.to_string(),
@@ -164,26 +253,66 @@ pub async fn request_p2tr_address(
     Ok((untweaked_pubkey, tweaked_pubkey, addr))
 }
 
+/// Collects the spent outputs of all PSBT inputs, preferring `witness_utxo` and falling back to
+/// the output of `non_witness_utxo` referenced by the input.
+///
+/// These are the `Prevouts::All` of a taproot sighash, e.g. for signing a custom script path.
+/// The error names the first input whose spent output is missing or doesn't match.
+pub fn collect_prevouts(psbt: &Psbt) -> Result<Vec<TxOut>, String> {
+    let mut prevouts = vec![];
+    for (i, input) in psbt.inputs.iter().enumerate() {
+        if let Some(pout) = input.witness_utxo.as_ref() {
+            prevouts.push(pout.clone());
+            continue;
+        }
+        let prev_tx = input.non_witness_utxo.as_ref().ok_or(format!(
+            "witness_utxo or non_witness_utxo required for input {i}"
+        ))?;
+        let outpoint = psbt
+            .unsigned_tx
+            .input
+            .get(i)
+            .map(|txin| txin.previous_output)
+            .ok_or(format!(
+                "Input index {i} exceeds unsigned transaction inputs"
+            ))?;
+        if prev_tx.compute_txid() != outpoint.txid {
+            return Err(format!(
+                "non_witness_utxo of input {i} doesn't match {outpoint}"
+            ));
+        }
+        let pout = prev_tx
+            .output
+            .get(outpoint.vout as usize)
+            .cloned()
+            .ok_or(format!(
+                "non_witness_utxo of input {i} has no output {}",
+                outpoint.vout
+            ))?;
+        prevouts.push(pout);
+    }
+    Ok(prevouts)
+}
+
 fn cmp_outpoint<'a>(mine: &'a bitcoin::OutPoint, outpoint: &bitcoin::OutPoint) -> bool {
     mine.txid == outpoint.txid && mine.vout == outpoint.vout
 }
 
+/// Signs the PSBT inputs matching the pool inputs, returns the indices of the signed inputs.
 pub async fn sign_p2tr_inputs(
     psbt: &mut Psbt,
     pool_inputs: &[bitcoin::OutPoint],
     network: Network,
-    derivation_path: Vec<Vec<u8>>,
-) -> Result<(), String> {
-    let mut cache = SighashCache::new(&psbt.unsigned_tx);
-    let mut prevouts = vec![];
-    for input in psbt.inputs.iter() {
-        let pout = input
-            .witness_utxo
-            .as_ref()
-            .cloned()
-            .ok_or("witness_utxo required".to_string())?;
-        prevouts.push(pout);
+    derivation_path: impl Into<DerivationPath>,
+) -> Result<Vec<usize>, String> {
+    // e.g. receive-only actions, nothing to sign
+    if pool_inputs.is_empty() {
+        return Ok(vec![]);
     }
+    let derivation_path: DerivationPath = derivation_path.into();
+    let prevouts = collect_prevouts(psbt)?;
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+    let mut signed = vec![];
     for (i, input) in psbt.unsigned_tx.input.iter().enumerate() {
         let outpoint = &input.previous_output;
         if let Some(_) = pool_inputs
@@ -207,21 +336,117 @@ pub async fn sign_p2tr_inputs(
                 .map_err(|e| e.to_string())?;
             let inner_sig = bitcoin::secp256k1::schnorr::Signature::from_slice(&raw_sig)
                 .expect("assert: chain-key schnorr signature is 64-bytes format");
+            if schnorr_config().verify_after_sign {
+                verify_p2tr_key_spend(&prevouts[i], &sighash, &inner_sig).map_err(|e| {
+                    crate::error::Error::SigningFailed(format!("input {i}: {e}")).to_string()
+                })?;
+            }
             let signature = bitcoin::taproot::Signature {
                 signature: inner_sig,
                 sighash_type: TapSighashType::Default,
             };
             input.final_script_witness = Some(Witness::p2tr_key_spend(&signature));
+            signed.push(i);
         }
     }
-    Ok(())
+    Ok(signed)
+}
+
+/// Computes the BIP-341 sighash of the input spending `leaf_script` through the script path.
+/// The leaf hash is `TapLeafHash::from_script(leaf_script, leaf_version)` where the version is
+/// taken from the control block, so both must belong to the same leaf of the script tree.
+fn tapscript_sighash(
+    cache: &mut SighashCache<&bitcoin::Transaction>,
+    index: usize,
+    prevouts: &[TxOut],
+    leaf_script: &bitcoin::Script,
+    control_block: &ControlBlock,
+) -> Result<TapSighash, String> {
+    let leaf_hash = TapLeafHash::from_script(leaf_script, control_block.leaf_version);
+    cache
+        .taproot_script_spend_signature_hash(
+            index,
+            &Prevouts::All(prevouts),
+            leaf_hash,
+            TapSighashType::Default,
+        )
+        .map_err(|e| format!("couldn't construct tapscript sighash of input {index}: {e}"))
+}
+
+/// Assembles the script path witness stack: signature, leaf script and control block.
+fn p2tr_script_spend_witness(
+    signature: &bitcoin::taproot::Signature,
+    leaf_script: &bitcoin::Script,
+    control_block: &ControlBlock,
+) -> Witness {
+    let mut witness = Witness::new();
+    witness.push(signature.to_vec());
+    witness.push(leaf_script.as_bytes());
+    witness.push(control_block.serialize());
+    witness
 }
 
-fn cmp<'a>(mine: &'a Utxo, outpoint: &OutPoint) -> bool {
-    Into::<bitcoin::Txid>::into(mine.txid) == outpoint.txid && mine.vout == outpoint.vout
+/// Signs the PSBT inputs matching the pool inputs through the Taproot script path, e.g. a
+/// covenant-style emergency script, returns the indices of the signed inputs.
+///
+/// The signature is made by the untweaked chain-key of `derivation_path`, i.e. `Metadata::key`,
+/// so `leaf_script` must check a signature of that key, e.g. `<key> OP_CHECKSIG`. The witness
+/// only satisfies scripts requiring that single signature.
+pub async fn sign_p2tr_script_spend_in_psbt(
+    psbt: &mut Psbt,
+    pool_inputs: &[bitcoin::OutPoint],
+    network: Network,
+    derivation_path: impl Into<DerivationPath>,
+    leaf_script: &bitcoin::Script,
+    control_block: &ControlBlock,
+) -> Result<Vec<usize>, String> {
+    if pool_inputs.is_empty() {
+        return Ok(vec![]);
+    }
+    let derivation_path: DerivationPath = derivation_path.into();
+    let prevouts = collect_prevouts(psbt)?;
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+    let mut signed = vec![];
+    for (i, input) in psbt.unsigned_tx.input.iter().enumerate() {
+        let outpoint = &input.previous_output;
+        if pool_inputs
+            .iter()
+            .any(|input| cmp_outpoint(input, outpoint))
+        {
+            if i >= psbt.inputs.len() {
+                return Err(format!(
+                    "Input index {i} exceeds available inputs ({})",
+                    psbt.inputs.len()
+                ));
+            }
+            let sighash = tapscript_sighash(&mut cache, i, &prevouts, leaf_script, control_block)?;
+            let raw_sig = self::sign_with_schnorr(
+                sighash.as_byte_array().to_vec(),
+                network,
+                derivation_path.clone(),
+                None,
+            )
+            .await
+            .map_err(|e| crate::error::Error::SigningFailed(e.to_string()))?;
+            let inner_sig = bitcoin::secp256k1::schnorr::Signature::from_slice(&raw_sig)
+                .expect("assert: chain-key schnorr signature is 64-bytes format");
+            let signature = bitcoin::taproot::Signature {
+                signature: inner_sig,
+                sighash_type: TapSighashType::Default,
+            };
+            psbt.inputs[i].final_script_witness = Some(p2tr_script_spend_witness(
+                &signature,
+                leaf_script,
+                control_block,
+            ));
+            signed.push(i);
+        }
+    }
+    Ok(signed)
 }
 
 /// Signs the PSBT inputs using IC chain-key that match the provided pool inputs with a Taproot key spend signature.
+/// Returns the indices of the signed inputs.
 #[deprecated(
     since = "0.10.0",
     note = "You shouldn't sign PSBT since the SDK now automatically signs PSBTs with the chain-key API"
@@ -230,21 +455,15 @@ pub async fn sign_p2tr_in_psbt(
     psbt: &mut Psbt,
     pool_inputs: &[Utxo],
     network: Network,
-    derivation_path: Vec<Vec<u8>>,
-) -> Result<(), String> {
+    derivation_path: impl Into<DerivationPath>,
+) -> Result<Vec<usize>, String> {
+    let derivation_path: DerivationPath = derivation_path.into();
+    let prevouts = collect_prevouts(psbt)?;
     let mut cache = SighashCache::new(&psbt.unsigned_tx);
-    let mut prevouts = vec![];
-    for input in psbt.inputs.iter() {
-        let pout = input
-            .witness_utxo
-            .as_ref()
-            .cloned()
-            .ok_or("witness_utxo required".to_string())?;
-        prevouts.push(pout);
-    }
+    let mut signed = vec![];
     for (i, input) in psbt.unsigned_tx.input.iter().enumerate() {
         let outpoint = &input.previous_output;
-        if let Some(_) = pool_inputs.iter().find(|input| cmp(input, outpoint)) {
+        if let Some(_) = crate::types::find_utxo(pool_inputs, outpoint) {
             (i < psbt.inputs.len()).then(|| ()).ok_or(format!(
                 "Input index {i} exceeds available inputs ({})",
                 psbt.inputs.len()
@@ -262,12 +481,233 @@ pub async fn sign_p2tr_in_psbt(
                 .map_err(|e| e.to_string())?;
             let inner_sig = bitcoin::secp256k1::schnorr::Signature::from_slice(&raw_sig)
                 .expect("assert: chain-key schnorr signature is 64-bytes format");
+            if schnorr_config().verify_after_sign {
+                verify_p2tr_key_spend(&prevouts[i], &sighash, &inner_sig).map_err(|e| {
+                    crate::error::Error::SigningFailed(format!("input {i}: {e}")).to_string()
+                })?;
+            }
             let signature = bitcoin::taproot::Signature {
                 signature: inner_sig,
                 sighash_type: TapSighashType::Default,
             };
             input.final_script_witness = Some(Witness::p2tr_key_spend(&signature));
+            signed.push(i);
         }
     }
-    Ok(())
+    Ok(signed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{
+        Amount, OutPoint, ScriptBuf, Transaction, TxIn, absolute::LockTime, taproot::LeafVersion,
+        transaction::Version,
+    };
+
+    fn tx(inputs: Vec<TxIn>, values: &[u64]) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs,
+            output: values
+                .iter()
+                .map(|v| TxOut {
+                    value: Amount::from_sat(*v),
+                    script_pubkey: ScriptBuf::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_schnorr_config() {
+        assert_eq!(schnorr_config().sign_cycles, DEFAULT_SIGN_CYCLES);
+        assert!(schnorr_config().verify_after_sign);
+        set_schnorr_config(SchnorrConfig {
+            sign_cycles: 10_000_000_000,
+            verify_after_sign: false,
+        });
+        assert_eq!(schnorr_config().sign_cycles, 10_000_000_000);
+
+        let config = schnorr_config();
+        assert_eq!(SchnorrConfig::from_bytes(config.to_bytes()), config);
+    }
+
+    #[test]
+    fn test_collect_prevouts_from_non_witness_utxo() {
+        let prev_tx = tx(vec![], &[1_000, 2_000]);
+        let spending = tx(
+            vec![TxIn {
+                previous_output: OutPoint::new(prev_tx.compute_txid(), 1),
+                ..Default::default()
+            }],
+            &[1_500],
+        );
+        let mut psbt = Psbt::from_unsigned_tx(spending).unwrap();
+        assert_eq!(
+            collect_prevouts(&psbt).unwrap_err(),
+            "witness_utxo or non_witness_utxo required for input 0"
+        );
+
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx.clone());
+        let prevouts = collect_prevouts(&psbt).unwrap();
+        assert_eq!(prevouts, vec![prev_tx.output[1].clone()]);
+
+        let witness_utxo = TxOut {
+            value: Amount::from_sat(3_000),
+            script_pubkey: ScriptBuf::new(),
+        };
+        psbt.inputs[0].witness_utxo = Some(witness_utxo.clone());
+        assert_eq!(collect_prevouts(&psbt).unwrap(), vec![witness_utxo]);
+
+        psbt.inputs[0].witness_utxo = None;
+        psbt.inputs[0].non_witness_utxo = Some(tx(vec![], &[1_000]));
+        assert!(collect_prevouts(&psbt).is_err());
+    }
+
+    #[test]
+    fn test_p2tr_script_spend() {
+        use bitcoin::{
+            Address, KnownHrp, opcodes::all::OP_CHECKSIG, script::Builder, secp256k1::Keypair,
+            taproot::TaprootBuilder,
+        };
+
+        let secp = Secp256k1::new();
+        let internal = Keypair::from_seckey_slice(&secp, &[1u8; 32]).unwrap();
+        let signer = Keypair::from_seckey_slice(&secp, &[2u8; 32]).unwrap();
+        let leaf_script = Builder::new()
+            .push_x_only_key(&signer.x_only_public_key().0)
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, leaf_script.clone())
+            .unwrap()
+            .finalize(&secp, internal.x_only_public_key().0)
+            .unwrap();
+        let control_block = spend_info
+            .control_block(&(leaf_script.clone(), LeafVersion::TapScript))
+            .unwrap();
+        assert!(control_block.verify_taproot_commitment(
+            &secp,
+            spend_info.output_key().to_x_only_public_key(),
+            &leaf_script
+        ));
+
+        let prevout = TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: Address::p2tr_tweaked(spend_info.output_key(), KnownHrp::Testnets)
+                .script_pubkey(),
+        };
+        let spending = tx(
+            vec![TxIn {
+                previous_output: OutPoint::new(tx(vec![], &[10_000]).compute_txid(), 0),
+                ..Default::default()
+            }],
+            &[9_000],
+        );
+        let mut cache = SighashCache::new(&spending);
+        let sighash = tapscript_sighash(
+            &mut cache,
+            0,
+            std::slice::from_ref(&prevout),
+            &leaf_script,
+            &control_block,
+        )
+        .unwrap();
+        // the leaf hash must commit to the leaf script, unlike the key path sighash
+        let key_spend = cache
+            .taproot_key_spend_signature_hash(
+                0,
+                &Prevouts::All(&[prevout]),
+                TapSighashType::Default,
+            )
+            .unwrap();
+        assert_ne!(sighash, key_spend);
+
+        let message = bitcoin::secp256k1::Message::from_digest(sighash.to_byte_array());
+        let signature = bitcoin::taproot::Signature {
+            signature: secp.sign_schnorr_no_aux_rand(&message, &signer),
+            sighash_type: TapSighashType::Default,
+        };
+        secp.verify_schnorr(
+            &signature.signature,
+            &message,
+            &signer.x_only_public_key().0,
+        )
+        .unwrap();
+
+        let witness = p2tr_script_spend_witness(&signature, &leaf_script, &control_block);
+        assert_eq!(witness.len(), 3);
+        assert_eq!(witness.nth(0).unwrap(), &signature.to_vec()[..]);
+        assert_eq!(witness.nth(1).unwrap(), leaf_script.as_bytes());
+        assert_eq!(witness.nth(2).unwrap(), &control_block.serialize()[..]);
+    }
+
+    #[test]
+    fn test_verify_p2tr_key_spend() {
+        use bitcoin::{Address, KnownHrp, secp256k1::Keypair};
+
+        let secp = Secp256k1::new();
+        let keypair = Keypair::from_seckey_slice(&secp, &[1u8; 32]).unwrap();
+        let key = Pubkey::from_raw(keypair.public_key().serialize().to_vec()).unwrap();
+        let tweaked = tweak_pubkey_with_empty(key.clone());
+        let prevout = TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: Address::p2tr(
+                &secp,
+                key.to_x_only_public_key(),
+                None,
+                KnownHrp::Testnets,
+            )
+            .script_pubkey(),
+        };
+        assert_eq!(
+            &prevout.script_pubkey.as_bytes()[2..],
+            &tweaked.to_x_only_bytes()[..]
+        );
+        let spending = tx(vec![TxIn::default()], &[9_000]);
+        let sighash = SighashCache::new(&spending)
+            .taproot_key_spend_signature_hash(
+                0,
+                &Prevouts::All(std::slice::from_ref(&prevout)),
+                TapSighashType::Default,
+            )
+            .unwrap();
+        let message = Message::from_digest(sighash.to_byte_array());
+        let tweaked_keypair = keypair.tap_tweak(&secp, None).to_keypair();
+        let signature = secp.sign_schnorr_no_aux_rand(&message, &tweaked_keypair);
+        assert_eq!(
+            verify_p2tr_key_spend(&prevout, &sighash, &signature),
+            Ok(())
+        );
+
+        // signed by the untweaked key, e.g. a missing bip341 aux
+        let untweaked = secp.sign_schnorr_no_aux_rand(&message, &keypair);
+        assert!(verify_p2tr_key_spend(&prevout, &sighash, &untweaked).is_err());
+        let other = TapSighash::from_byte_array([7; 32]);
+        assert!(verify_p2tr_key_spend(&prevout, &other, &signature).is_err());
+        let p2wpkh = TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::from_byte_array([1; 20])),
+        };
+        assert!(verify_p2tr_key_spend(&p2wpkh, &sighash, &signature).is_err());
+    }
+
+    #[test]
+    fn test_skip_signing_without_pool_inputs() {
+        use std::future::Future;
+        use std::task::{Context, Poll, Waker};
+
+        let spending = tx(vec![TxIn::default()], &[1_500]);
+        let mut psbt = Psbt::from_unsigned_tx(spending).unwrap();
+        // must complete without reaching the management canister
+        let polled = {
+            let signing =
+                std::pin::pin!(sign_p2tr_inputs(&mut psbt, &[], Network::Testnet4, vec![]));
+            signing.poll(&mut Context::from_waker(Waker::noop()))
+        };
+        assert_eq!(polled, Poll::Ready(Ok(vec![])));
+        assert!(psbt.inputs[0].final_script_witness.is_none());
+    }
 }
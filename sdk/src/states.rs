@@ -1,3 +1,4 @@
+use crate::log::LogLevel;
 use crate::*;
 use std::fmt::{self, Display, Formatter};
 
@@ -27,36 +28,49 @@ impl Display for Error {
 
 impl std::error::Error for Error {}
 
-fn detect_reorg(
+/// Classifies the new block against the retained blocks as `confirm_txs` does before processing it.
+pub fn detect_reorg<P>(blocks: &BlockStorage, new_block: &NewBlockInfo) -> ReorgOutcome
+where
+    P: Pools,
+{
+    match check_reorg(blocks, P::max_finalize_threshold(), new_block) {
+        Ok(()) => ReorgOutcome::Normal,
+        Err(Error::DuplicateBlock { .. }) => ReorgOutcome::Duplicate,
+        Err(Error::Recoverable { from, to }) => ReorgOutcome::Recoverable { from, to },
+        Err(Error::Unrecoverable) => ReorgOutcome::Unrecoverable,
+    }
+}
+
+fn check_reorg(
     blocks: &BlockStorage,
     finalize_threshold: u32,
     new_block: &NewBlockInfo,
 ) -> Result<(), Error> {
-    ic_cdk::println!(
-        "Processing new block - height: {}, hash: {}, timestamp: {}, confirmed_txs: {}",
-        new_block.block_height,
-        new_block.block_hash,
-        new_block.block_timestamp,
-        new_block.confirmed_txids.len()
-    );
     let current_block = blocks.last_key_value().map(|(_, v)| v);
     match current_block {
         None => {
-            ic_cdk::println!("No blocks found in exchange - this is expected for new exchanges");
+            crate::log!(
+                LogLevel::Info,
+                "No blocks found in exchange - this is expected for new exchanges"
+            );
             return Ok(());
         }
         Some(current_block) => {
-            ic_cdk::println!(
+            crate::log!(
+                LogLevel::Debug,
                 "Current block: height: {:?}, hash: {:?}, timestamp: {:?}",
                 current_block.block_height,
                 current_block.block_hash,
                 current_block.block_timestamp
             );
             if new_block.block_height == current_block.block_height + 1 {
-                ic_cdk::println!("New block is the next block in the chain");
+                crate::log!(LogLevel::Debug, "New block is the next block in the chain");
                 return Ok(());
             } else if new_block.block_height > current_block.block_height + 1 {
-                ic_cdk::println!("New block is more than one block ahead of the current block");
+                crate::log!(
+                    LogLevel::Warn,
+                    "New block is more than one block ahead of the current block"
+                );
                 return Err(Error::Unrecoverable);
             } else {
                 let reorg_depth = current_block.block_height - new_block.block_height + 1;
@@ -64,25 +78,30 @@ fn detect_reorg(
                     .get(&new_block.block_height)
                     .ok_or(Error::Unrecoverable)
                     .inspect_err(|_| {
-                        ic_cdk::println!(
+                        crate::log!(
+                            LogLevel::Warn,
                             "Detected reorg at {}, but it was removed.",
                             new_block.block_height
                         )
                     })?;
                 if target_block.block_hash == new_block.block_hash {
-                    ic_cdk::println!("New block is a duplicate block");
+                    crate::log!(LogLevel::Debug, "New block is a duplicate block");
                     return Err(Error::DuplicateBlock {
                         height: new_block.block_height,
                         hash: new_block.block_hash.clone(),
                     });
                 }
-                ic_cdk::println!(
+                crate::log!(
+                    LogLevel::Info,
                     "Reorg detected from {} to {}",
                     new_block.block_height,
                     current_block.block_height
                 );
                 if reorg_depth > finalize_threshold {
-                    ic_cdk::println!("Reorg depth is greater than the max recoverable reorg depth");
+                    crate::log!(
+                        LogLevel::Warn,
+                        "Reorg depth is greater than the max recoverable reorg depth"
+                    );
                     return Err(Error::Unrecoverable);
                 }
                 return Err(Error::Recoverable {
@@ -112,7 +131,8 @@ where
     (from..=to).rev().for_each(|h| {
         if let Some(reverted) = blocks.remove(&h) {
             for tx in reverted.txs.into_iter() {
-                ic_cdk::println!(
+                crate::log!(
+                    LogLevel::Info,
                     "Rollback confirmed txid: {} with pools: {:?}",
                     tx.txid,
                     tx.pools
@@ -122,29 +142,139 @@ where
             }
         }
     });
-    ic_cdk::println!("successfully rolled back state to {}", to,);
+    crate::log!(LogLevel::Info, "successfully rolled back state to {}", to,);
     Ok(())
 }
 
+pub fn ensure_fresh_chain<P>(blocks: &BlockStorage, now: u64) -> Result<(), error::Error>
+where
+    P: Pools,
+{
+    let Some(max_silence) = P::max_block_silence_secs() else {
+        return Ok(());
+    };
+    match blocks.last_key_value() {
+        Some((_, block)) if now.saturating_sub(block.block_timestamp) > max_silence => {
+            crate::log!(
+                LogLevel::Warn,
+                "No block received since {} (height {}), rejecting execution",
+                block.block_timestamp,
+                block.block_height
+            );
+            Err(error::Error::StaleChainState)
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn ensure_unexecuted(
+    unconfirmed: &UnconfirmedTxStorage,
+    txid: &Txid,
+    pool_address: &String,
+    is_reapply: bool,
+) -> Result<(), error::Error> {
+    if is_reapply {
+        return Ok(());
+    }
+    match unconfirmed.get(txid) {
+        Some(record) if record.pools.contains(pool_address) => {
+            crate::log!(
+                LogLevel::Warn,
+                "txid {} already executed in pool {}",
+                txid,
+                pool_address
+            );
+            Err(error::Error::DuplicateTxid)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Rejects the execution if the pool already has `Pools::max_unconfirmed_per_pool` unconfirmed
+/// transactions, a reapplied transaction has been counted already so it's never rejected.
+pub fn ensure_unconfirmed_capacity<P>(
+    unconfirmed: &UnconfirmedTxStorage,
+    pool_address: &String,
+    is_reapply: bool,
+) -> Result<(), error::Error>
+where
+    P: Pools,
+{
+    let Some(max) = P::max_unconfirmed_per_pool() else {
+        return Ok(());
+    };
+    if is_reapply {
+        return Ok(());
+    }
+    let count = unconfirmed
+        .values()
+        .filter(|record| record.pools.contains(pool_address))
+        .count();
+    if count >= max {
+        crate::log!(
+            LogLevel::Warn,
+            "pool {} has {} unconfirmed txs, rejecting execution",
+            pool_address,
+            count
+        );
+        return Err(error::Error::UnconfirmedLimitReached { max });
+    }
+    Ok(())
+}
+
+pub fn record_tx(unconfirmed: &mut UnconfirmedTxStorage, txid: Txid, pool_address: String) {
+    let mut record = unconfirmed.get(&txid).unwrap_or(TxRecord {
+        txid,
+        pools: vec![],
+    });
+    if !record.pools.contains(&pool_address) {
+        record.pools.push(pool_address);
+    }
+    unconfirmed.insert(txid, record);
+}
+
 pub fn confirm_txs<P>(
     block_states: &mut BlockStateStorage<P::BlockState>,
     blocks: &mut BlockStorage,
     unconfirmed: &mut UnconfirmedTxStorage,
+    events: &mut EventStorage,
     args: NewBlockArgs,
 ) -> Result<Option<Block>, String>
 where
     P: Hook,
 {
+    crate::log!(
+        LogLevel::Info,
+        "Processing new block - height: {}, hash: {}, timestamp: {}, confirmed_txs: {}",
+        args.block_height,
+        args.block_hash,
+        args.block_timestamp,
+        args.confirmed_txids.len()
+    );
+    P::validate_new_block(&args)?;
     P::pre_block_confirmed(args.block_height);
     // Check for blockchain reorganizations
-    match detect_reorg(blocks, P::finalize_threshold(), &args) {
+    match check_reorg(blocks, P::max_finalize_threshold(), &args) {
         Ok(_) => {}
         Err(Error::DuplicateBlock { height, hash }) => {
-            ic_cdk::println!("Ignored duplicated block {}({}).", height, hash);
+            crate::log!(
+                LogLevel::Info,
+                "Ignored duplicated block {}({}).",
+                height,
+                hash
+            );
             return Ok(None);
         }
         Err(Error::Unrecoverable) => {
-            return Err("Unrecoverable reorg detected".to_string());
+            let current_tip = blocks.keys().next_back().unwrap_or_default();
+            crate::log!(
+                LogLevel::Error,
+                "Rejected block {} by an unrecoverable reorg, current tip: {}",
+                args.block_height,
+                current_tip
+            );
+            P::on_unrecoverable_reorg(current_tip, &args);
+            return Err(error::Error::ReorgUnrecoverable.into());
         }
         Err(Error::Recoverable { from, to }) => {
             handle_reorg::<P>(block_states, blocks, unconfirmed, from, to)
@@ -161,7 +291,12 @@ where
     let mut confirmed = vec![];
     for txid in confirmed_txids.into_iter() {
         if let Some(record) = unconfirmed.remove(&txid) {
-            ic_cdk::println!("confirm txid: {} with pools: {:?}", txid, record.pools);
+            crate::log!(
+                LogLevel::Debug,
+                "confirm txid: {} with pools: {:?}",
+                txid,
+                record.pools
+            );
             confirmed.push(record);
         }
     }
@@ -173,6 +308,13 @@ where
     };
     for tx in block.txs.iter() {
         for addr in tx.pools.iter() {
+            push_event::<P>(
+                events,
+                EventKind::Confirmed,
+                tx.txid,
+                addr.clone(),
+                Some(block.block_height),
+            );
             P::on_tx_confirmed(addr.to_string(), tx.txid, block.clone());
         }
     }
@@ -183,49 +325,107 @@ pub fn accept_block<P>(
     block_states: &mut BlockStateStorage<P::BlockState>,
     blocks: &mut BlockStorage,
     pools: &mut PoolStorage<P::PoolState>,
+    events: &mut EventStorage,
     block: Block,
 ) -> NewBlockResponse
 where
-    P: Pools,
+    P: Hook,
 {
-    let block_height = block.block_height;
     blocks.insert(block.block_height, block);
+    finalize_blocks::<P>(block_states, blocks, pools, events)
+}
+
+/// Finalize the transactions of the blocks beyond the reorg window of the current tip,
+/// then prune those blocks. It is safe to call repeatedly since finalized blocks are removed.
+pub fn finalize_blocks<P>(
+    block_states: &mut BlockStateStorage<P::BlockState>,
+    blocks: &mut BlockStorage,
+    pools: &mut PoolStorage<P::PoolState>,
+    events: &mut EventStorage,
+) -> NewBlockResponse
+where
+    P: Hook,
+{
+    let Some(block_height) = blocks.keys().next_back() else {
+        return Ok(());
+    };
 
-    // Calculate the height below which blocks are considered fully confirmed (beyond reorg risk)
-    let confirmed_height = block_height - P::finalize_threshold() + 1;
+    // Calculate the height below which blocks are considered fully confirmed (beyond reorg risk),
+    // there could be none yet for an exchange starting near height 0, e.g. on a regtest
+    let confirmed_height = (block_height + 1).checked_sub(P::max_finalize_threshold());
+    let is_confirmed = |height: u32| confirmed_height.is_some_and(|h| height <= h);
 
-    // Finalize transactions in confirmed blocks
+    // Finalize transactions in confirmed blocks, pools with a lower threshold
+    // may finalize their transactions before the block is confirmed globally
+    let mut retained = vec![];
     for entry in blocks.iter() {
         let (height, block_info) = entry.into_pair();
-        if height <= confirmed_height {
-            ic_cdk::println!("finalizing txs in block: {}", height);
-            let mut affected_pools: std::collections::HashMap<_, Vec<_>> =
-                std::collections::HashMap::new();
-            for tx in block_info.txs.iter() {
-                ic_cdk::println!("finalize txid: {} with pools: {:?}", tx.txid, tx.pools);
-                // Make transaction state permanent in each affected pool
-                for addr in tx.pools.clone().into_iter() {
-                    affected_pools
-                        .entry(addr)
-                        .and_modify(|txs| txs.push(tx.txid))
-                        .or_insert_with(|| vec![tx.txid]);
+        if is_confirmed(height) {
+            crate::log!(LogLevel::Debug, "finalizing txs in block: {}", height);
+        }
+        let mut affected_pools: std::collections::HashMap<_, Vec<_>> =
+            std::collections::HashMap::new();
+        let mut remaining = block_info.clone();
+        for tx in remaining.txs.iter_mut() {
+            // Make transaction state permanent in each affected pool
+            tx.pools.retain(|addr| {
+                let threshold =
+                    P::finalize_threshold_for(addr).clamp(1, P::max_finalize_threshold());
+                if height + threshold > block_height + 1 {
+                    return true;
                 }
-            }
-            for (addr, txids) in affected_pools.into_iter() {
-                if let Some(mut pool) = pools.get(&addr) {
-                    for txid in txids {
-                        pool.finalize(txid)?;
+                crate::log!(
+                    LogLevel::Debug,
+                    "finalize txid: {} in pool: {}",
+                    tx.txid,
+                    addr
+                );
+                affected_pools
+                    .entry(addr.clone())
+                    .and_modify(|txs| txs.push(tx.txid))
+                    .or_insert_with(|| vec![tx.txid]);
+                false
+            });
+        }
+        if affected_pools.is_empty() {
+            continue;
+        }
+        for (addr, txids) in affected_pools.into_iter() {
+            if let Some(mut pool) = pools.get(&addr) {
+                let mut finalized = vec![];
+                for txid in txids {
+                    pool.finalize(txid)?;
+                    if let Some(state) = pool.get(txid) {
+                        finalized.push((txid, state.inspect_state()));
                     }
-                    pools.insert(addr.clone(), pool);
+                }
+                pools.insert(addr.clone(), pool);
+                for (txid, state) in finalized.into_iter() {
+                    push_event::<P>(
+                        events,
+                        EventKind::Finalized,
+                        txid,
+                        addr.clone(),
+                        Some(height),
+                    );
+                    P::on_state_finalized(addr.clone(), txid, state, block_info.clone());
                 }
             }
         }
+        // The pools finalized ahead of the block are dropped from it,
+        // they won't be finalized again nor rolled back by a reorg
+        if !is_confirmed(height) {
+            retained.push(remaining);
+        }
     }
-    // Clean up old block data that's no longer needed
-    let removing = blocks
-        .keys()
-        .take_while(|h| *h <= confirmed_height)
-        .collect::<Vec<_>>();
+    for block in retained.into_iter() {
+        blocks.insert(block.block_height, block);
+    }
+    // Clean up old block data that's no longer needed. The confirmed blocks are removed on every
+    // call, so only those confirmed since the last call are visited, no matter how long the history is
+    let removing = confirmed_height
+        .map(|h| blocks.keys_range(..=h).collect::<Vec<_>>())
+        .unwrap_or_default();
     for height in removing.iter() {
         blocks.remove(&height);
     }
@@ -237,27 +437,51 @@ where
     Ok(())
 }
 
+/// The work of the periodic maintenance enabled by `Pools::enable_periodic_maintenance`, run by
+/// its timer at `now` in seconds.
+///
+/// The blocks beyond the reorg window are finalized, which catches up without a new block once an
+/// upgrade lowers the finalize thresholds, and the locks older than `Pools::execute_lock_timeout_secs`
+/// are released. Returns the addresses of the released pools.
+pub fn run_maintenance<P>(
+    block_states: &mut BlockStateStorage<P::BlockState>,
+    blocks: &mut BlockStorage,
+    pools: &mut PoolStorage<P::PoolState>,
+    events: &mut EventStorage,
+    locks: &mut std::collections::HashMap<String, u64>,
+    now: u64,
+) -> Result<Vec<String>, String>
+where
+    P: Hook,
+{
+    finalize_blocks::<P>(block_states, blocks, pools, events)?;
+    Ok(release_stale_locks::<P>(locks, now))
+}
+
 pub fn reject_tx<P>(
     unconfirmed: &mut UnconfirmedTxStorage,
     pools: &mut PoolStorage<P::PoolState>,
+    events: &mut EventStorage,
     args: RollbackTxArgs,
 ) -> RollbackTxResponse
 where
     P: Hook,
 {
     if let Some(tx) = unconfirmed.remove(&args.txid) {
-        ic_cdk::println!(
+        crate::log!(
+            LogLevel::Info,
             "rollback unconfirmed tx {} with pools: {:?}",
             tx.txid,
             tx.pools
         );
-        return rollback_tx::<P>(pools, tx, args.reason_code);
+        return rollback_tx::<P>(pools, events, tx, args.reason_code);
     }
     Ok(())
 }
 
 fn rollback_tx<P>(
     pools: &mut PoolStorage<P::PoolState>,
+    events: &mut EventStorage,
     tx: TxRecord,
     reason: String,
 ) -> RollbackTxResponse
@@ -274,7 +498,1182 @@ where
             .rollback(tx.txid)
             .map_err(|e| format!("Failed to rollback pool {}: {}", addr, e))?;
         pools.insert(addr.clone(), pool);
+        push_event::<P>(events, EventKind::Rollbacked, tx.txid, addr.clone(), None);
         P::on_tx_rollbacked(addr.to_string(), tx.txid, reason.clone(), reverted);
     }
     Ok(())
 }
+
+/// Rolls back the pool at `address` to the state at `nonce` for operational recovery.
+/// The rolled back transactions are detached from the pool in the unconfirmed and the
+/// retained confirmed records, so they won't be finalized or rolled back again.
+pub fn rollback_pool_to_nonce<P>(
+    pools: &mut PoolStorage<P::PoolState>,
+    unconfirmed: &mut UnconfirmedTxStorage,
+    blocks: &mut BlockStorage,
+    events: &mut EventStorage,
+    address: String,
+    nonce: u64,
+) -> Result<(), String>
+where
+    P: Hook,
+{
+    let mut pool = pools
+        .get(&address)
+        .ok_or(format!("Pool {} not found", address))?;
+    let reverted = pool.rollback_to_nonce(nonce)?;
+    pools.insert(address.clone(), pool);
+    let txids = reverted
+        .iter()
+        .map(|state| state.inspect_state().txid)
+        .collect::<Vec<_>>();
+    for txid in txids.iter() {
+        if let Some(mut record) = unconfirmed.remove(txid) {
+            record.pools.retain(|addr| *addr != address);
+            if !record.pools.is_empty() {
+                unconfirmed.insert(*txid, record);
+            }
+        }
+    }
+    let detached = blocks
+        .iter()
+        .map(|entry| entry.into_pair().1)
+        .filter(|block| {
+            block
+                .txs
+                .iter()
+                .any(|tx| txids.contains(&tx.txid) && tx.pools.contains(&address))
+        })
+        .collect::<Vec<_>>();
+    for mut block in detached.into_iter() {
+        for tx in block.txs.iter_mut().filter(|tx| txids.contains(&tx.txid)) {
+            tx.pools.retain(|addr| *addr != address);
+        }
+        blocks.insert(block.block_height, block);
+    }
+    let reason = format!("rollback to nonce {}", nonce);
+    for (txid, state) in txids.into_iter().zip(reverted) {
+        crate::log!(
+            LogLevel::Info,
+            "rollback txid: {} in pool: {} to nonce {}",
+            txid,
+            address,
+            nonce
+        );
+        push_event::<P>(events, EventKind::Rollbacked, txid, address.clone(), None);
+        P::on_tx_rollbacked(address.clone(), txid, reason.clone(), vec![state]);
+    }
+    Ok(())
+}
+
+/// Replaces the states of the pool with `states`, see `Pool::replace_states`. It bypasses the
+/// transitions of the actions, so the pool must not have unconfirmed transactions, otherwise their
+/// rollbacks or confirmations would apply to the imported history.
+pub fn restore_pool<P>(
+    pools: &mut PoolStorage<P::PoolState>,
+    unconfirmed: &UnconfirmedTxStorage,
+    address: String,
+    states: Vec<P::PoolState>,
+) -> Result<(), String>
+where
+    P: Pools,
+{
+    let mut pool = pools
+        .get(&address)
+        .ok_or(format!("Pool {} not found", address))?;
+    let pending = unconfirmed
+        .values()
+        .filter(|record| record.pools.contains(&address))
+        .count();
+    if pending > 0 {
+        return Err(format!(
+            "Pool {} has {} unconfirmed transactions, roll them back first",
+            address, pending
+        ));
+    }
+    let replaced = pool.replace_states(states)?;
+    let nonce = |state: Option<&P::PoolState>| state.map(|state| state.inspect_state().nonce);
+    crate::log!(
+        LogLevel::Warn,
+        "restored pool {}: {} states up to nonce {:?} replaced by {} states up to nonce {:?}",
+        address,
+        replaced.len(),
+        nonce(replaced.last()),
+        pool.states().len(),
+        nonce(pool.last_state())
+    );
+    pools.insert(address, pool);
+    Ok(())
+}
+
+pub fn push_event<P>(
+    events: &mut EventStorage,
+    kind: EventKind,
+    txid: Txid,
+    pool: String,
+    height: Option<u32>,
+) where
+    P: Pools,
+{
+    let seq = events.keys().next_back().map(|seq| seq + 1).unwrap_or(1);
+    events.insert(
+        seq,
+        ExchangeEvent {
+            seq,
+            kind,
+            txid,
+            pool,
+            height,
+        },
+    );
+    // Keep the latest one at least so the sequence never restarts
+    while events.len() > P::max_events().max(1) {
+        events.pop_first();
+    }
+}
+
+/// An in-memory replica of the exchange storages driven the same way as the generated
+/// `execute_tx`, `new_block` and `rollback_tx`, for testing the finalize and rollback
+/// behaviors of an exchange across reorgs without a canister environment.
+///
+/// It is not available in canisters since the memory would overlap with the stable memory.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct ReorgEngine<P: Hook> {
+    pub block_states: BlockStateStorage<P::BlockState>,
+    pub blocks: BlockStorage,
+    pub unconfirmed: UnconfirmedTxStorage,
+    pub pools: PoolStorage<P::PoolState>,
+    pub events: EventStorage,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<P: Hook> Default for ReorgEngine<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<P: Hook> ReorgEngine<P> {
+    pub fn new() -> Self {
+        use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+        let manager = MemoryManager::init(DefaultMemoryImpl::default());
+        Self {
+            block_states: BlockStateStorage::init(manager.get(MemoryId::new(0))),
+            blocks: BlockStorage::init(manager.get(MemoryId::new(1))),
+            unconfirmed: UnconfirmedTxStorage::init(manager.get(MemoryId::new(2))),
+            pools: PoolStorage::init(manager.get(MemoryId::new(3))),
+            events: EventStorage::init(manager.get(MemoryId::new(4))),
+        }
+    }
+
+    pub fn insert_pool(&mut self, pool: Pool<P::PoolState>) {
+        self.pools.insert(pool.metadata().address.clone(), pool);
+    }
+
+    pub fn pool(&self, address: &String) -> Option<Pool<P::PoolState>> {
+        self.pools.get(address)
+    }
+
+    /// Commits `state` to the pool as if it was returned by an action for `txid`.
+    pub fn execute(
+        &mut self,
+        txid: Txid,
+        pool_address: String,
+        mut state: P::PoolState,
+    ) -> Result<(), String> {
+        let mut pool = self
+            .pools
+            .get(&pool_address)
+            .ok_or(format!("Pool {} not found", pool_address))?;
+        state.set_nonce(pool.get_pool_info().nonce + 1);
+        pool.states_mut().push(state);
+        self.pools.insert(pool_address.clone(), pool);
+        record_tx(&mut self.unconfirmed, txid, pool_address);
+        Ok(())
+    }
+
+    pub fn new_block(&mut self, args: NewBlockArgs) -> NewBlockResponse {
+        let block = confirm_txs::<P>(
+            &mut self.block_states,
+            &mut self.blocks,
+            &mut self.unconfirmed,
+            &mut self.events,
+            args,
+        )?;
+        if let Some(block) = block {
+            accept_block::<P>(
+                &mut self.block_states,
+                &mut self.blocks,
+                &mut self.pools,
+                &mut self.events,
+                block.clone(),
+            )?;
+            P::on_block_confirmed(block);
+        }
+        Ok(())
+    }
+
+    pub fn rollback_tx(&mut self, args: RollbackTxArgs) -> RollbackTxResponse {
+        reject_tx::<P>(
+            &mut self.unconfirmed,
+            &mut self.pools,
+            &mut self.events,
+            args,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+    struct DummyPoolState {
+        txid: Txid,
+        nonce: u64,
+    }
+
+    impl StateView for DummyPoolState {
+        fn inspect_state(&self) -> StateInfo {
+            StateInfo {
+                txid: self.txid,
+                nonce: self.nonce,
+                ..Default::default()
+            }
+        }
+
+        fn set_nonce(&mut self, nonce: u64) {
+            self.nonce = nonce;
+        }
+    }
+
+    struct DummyPools;
+
+    impl Pools for DummyPools {
+        type PoolState = DummyPoolState;
+
+        type BlockState = u32;
+
+        const POOL_STATE_MEMORY: u8 = 0;
+
+        const BLOCK_STATE_MEMORY: u8 = 1;
+
+        fn network() -> Network {
+            Network::Testnet4
+        }
+
+        fn max_block_silence_secs() -> Option<u64> {
+            Some(600)
+        }
+
+        fn max_events() -> u64 {
+            3
+        }
+
+        fn max_unconfirmed_per_pool() -> Option<usize> {
+            Some(2)
+        }
+    }
+
+    fn memory(id: u8) -> Memory {
+        MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(id))
+    }
+
+    fn block(height: u32, timestamp: u64) -> Block {
+        Block {
+            block_height: height,
+            block_hash: format!("{:064x}", height),
+            block_timestamp: timestamp,
+            txs: vec![],
+        }
+    }
+
+    #[test]
+    fn test_reject_on_block_silence() {
+        let mut blocks = BlockStorage::init(memory(100));
+        assert!(ensure_fresh_chain::<DummyPools>(&blocks, 1_000_000).is_ok());
+
+        blocks.insert(1, block(1, 1_000));
+        assert!(ensure_fresh_chain::<DummyPools>(&blocks, 1_600).is_ok());
+        assert_eq!(
+            ensure_fresh_chain::<DummyPools>(&blocks, 1_601),
+            Err(error::Error::StaleChainState)
+        );
+
+        blocks.insert(2, block(2, 1_500));
+        assert!(ensure_fresh_chain::<DummyPools>(&blocks, 1_601).is_ok());
+    }
+
+    #[test]
+    fn test_reject_duplicate_txid() {
+        let mut unconfirmed = UnconfirmedTxStorage::init(memory(101));
+        let txid = Txid::from_bytes(&[1u8; 32]).unwrap();
+        let pool = "pool-a".to_string();
+        let other = "pool-b".to_string();
+
+        assert!(ensure_unexecuted(&unconfirmed, &txid, &pool, false).is_ok());
+        record_tx(&mut unconfirmed, txid, pool.clone());
+        assert_eq!(
+            ensure_unexecuted(&unconfirmed, &txid, &pool, false),
+            Err(error::Error::DuplicateTxid)
+        );
+        assert!(ensure_unexecuted(&unconfirmed, &txid, &pool, true).is_ok());
+        assert!(ensure_unexecuted(&unconfirmed, &txid, &other, false).is_ok());
+
+        record_tx(&mut unconfirmed, txid, pool.clone());
+        record_tx(&mut unconfirmed, txid, other.clone());
+        assert_eq!(unconfirmed.get(&txid).unwrap().pools, vec![pool, other]);
+    }
+
+    #[test]
+    fn test_reject_over_unconfirmed_limit() {
+        let mut unconfirmed = UnconfirmedTxStorage::init(memory(101));
+        let pool = "pool-a".to_string();
+        let other = "pool-b".to_string();
+        let txid = |i: u8| Txid::from_bytes(&[i; 32]).unwrap();
+
+        for i in 1..=2 {
+            assert!(ensure_unconfirmed_capacity::<DummyPools>(&unconfirmed, &pool, false).is_ok());
+            record_tx(&mut unconfirmed, txid(i), pool.clone());
+        }
+        record_tx(&mut unconfirmed, txid(3), other.clone());
+        assert_eq!(
+            ensure_unconfirmed_capacity::<DummyPools>(&unconfirmed, &pool, false),
+            Err(error::Error::UnconfirmedLimitReached { max: 2 })
+        );
+        assert!(ensure_unconfirmed_capacity::<DummyPools>(&unconfirmed, &pool, true).is_ok());
+        assert!(ensure_unconfirmed_capacity::<DummyPools>(&unconfirmed, &other, false).is_ok());
+        assert!(
+            ensure_unconfirmed_capacity::<crate::test::DummyPools>(&unconfirmed, &pool, false)
+                .is_ok()
+        );
+
+        // confirmed
+        unconfirmed.remove(&txid(1));
+        assert!(ensure_unconfirmed_capacity::<DummyPools>(&unconfirmed, &pool, false).is_ok());
+    }
+
+    #[test]
+    fn test_validate_new_block() {
+        struct StrictPools;
+
+        impl Pools for StrictPools {
+            type PoolState = DummyPoolState;
+
+            type BlockState = u32;
+
+            const POOL_STATE_MEMORY: u8 = 0;
+
+            const BLOCK_STATE_MEMORY: u8 = 1;
+
+            fn network() -> Network {
+                Network::Testnet4
+            }
+        }
+
+        impl Hook for StrictPools {
+            fn validate_new_block(args: &NewBlockInfo) -> Result<(), String> {
+                (args.block_timestamp > 0)
+                    .then_some(())
+                    .ok_or("missing block timestamp".to_string())
+            }
+        }
+
+        let mut block_states = BlockStateStorage::<u32>::init(memory(1));
+        let mut blocks = BlockStorage::init(memory(100));
+        let mut unconfirmed = UnconfirmedTxStorage::init(memory(101));
+        let mut events = EventStorage::init(memory(103));
+        let txid = Txid::from_bytes(&[1; 32]).unwrap();
+        record_tx(&mut unconfirmed, txid, "pool-a".to_string());
+        let args = |timestamp: u64| NewBlockArgs {
+            block_height: 100,
+            block_hash: format!("{:064x}", 100),
+            block_timestamp: timestamp,
+            confirmed_txids: vec![txid],
+        };
+
+        assert_eq!(
+            confirm_txs::<StrictPools>(
+                &mut block_states,
+                &mut blocks,
+                &mut unconfirmed,
+                &mut events,
+                args(0)
+            ),
+            Err("missing block timestamp".to_string())
+        );
+        assert!(blocks.is_empty());
+        assert!(unconfirmed.contains_key(&txid));
+        assert!(events.is_empty());
+
+        let block = confirm_txs::<StrictPools>(
+            &mut block_states,
+            &mut blocks,
+            &mut unconfirmed,
+            &mut events,
+            args(1_000),
+        )
+        .unwrap();
+        assert_eq!(block.unwrap().txs.len(), 1);
+        assert!(unconfirmed.is_empty());
+    }
+
+    #[test]
+    fn test_detect_reorg() {
+        let mut blocks = BlockStorage::init(memory(100));
+        let new_block = |height: u32, hash: &str| NewBlockInfo {
+            block_height: height,
+            block_hash: hash.to_string(),
+            block_timestamp: height as u64,
+            confirmed_txids: vec![],
+        };
+        assert_eq!(
+            detect_reorg::<DummyPools>(&blocks, &new_block(100, "a")),
+            ReorgOutcome::Normal
+        );
+        for height in 100..=102 {
+            let mut accepted = block(height, height as u64);
+            accepted.block_hash = format!("hash-{height}");
+            blocks.insert(height, accepted);
+        }
+        let outcome = |height, hash| detect_reorg::<DummyPools>(&blocks, &new_block(height, hash));
+        assert_eq!(outcome(103, "hash-103"), ReorgOutcome::Normal);
+        assert_eq!(outcome(102, "hash-102"), ReorgOutcome::Duplicate);
+        assert_eq!(
+            outcome(101, "other"),
+            ReorgOutcome::Recoverable { from: 101, to: 102 }
+        );
+        assert_eq!(outcome(105, "hash-105"), ReorgOutcome::Unrecoverable);
+        assert_eq!(outcome(99, "hash-99"), ReorgOutcome::Unrecoverable);
+    }
+
+    #[test]
+    fn test_unrecoverable_reorg_hook() {
+        struct AlertingPools;
+
+        impl Pools for AlertingPools {
+            type PoolState = DummyPoolState;
+
+            type BlockState = u32;
+
+            const POOL_STATE_MEMORY: u8 = 0;
+
+            const BLOCK_STATE_MEMORY: u8 = 1;
+
+            fn network() -> Network {
+                Network::Testnet4
+            }
+        }
+
+        thread_local! {
+            static ALERTS: std::cell::RefCell<Vec<(u32, u32)>> = const { std::cell::RefCell::new(vec![]) };
+        }
+
+        impl Hook for AlertingPools {
+            fn on_unrecoverable_reorg(current_tip: u32, new_block: &NewBlockInfo) {
+                ALERTS.with_borrow_mut(|alerts| alerts.push((current_tip, new_block.block_height)));
+            }
+        }
+
+        let mut block_states = BlockStateStorage::<u32>::init(memory(1));
+        let mut blocks = BlockStorage::init(memory(100));
+        let mut unconfirmed = UnconfirmedTxStorage::init(memory(101));
+        let mut events = EventStorage::init(memory(103));
+        blocks.insert(100, block(100, 100));
+        blocks.insert(101, block(101, 101));
+        let mut new_block = |height: u32| {
+            confirm_txs::<AlertingPools>(
+                &mut block_states,
+                &mut blocks,
+                &mut unconfirmed,
+                &mut events,
+                NewBlockArgs {
+                    block_height: height,
+                    block_hash: format!("{:064x}", height),
+                    block_timestamp: height as u64,
+                    confirmed_txids: vec![],
+                },
+            )
+        };
+        // a duplicated block
+        assert!(new_block(101).is_ok());
+        assert!(ALERTS.with_borrow(|alerts| alerts.is_empty()));
+
+        assert_eq!(
+            new_block(103),
+            Err(error::Error::ReorgUnrecoverable.to_string())
+        );
+        assert_eq!(
+            ALERTS.with_borrow(|alerts| alerts.clone()),
+            vec![(101, 103)]
+        );
+    }
+
+    #[test]
+    fn test_finalize_blocks_outside_reorg_window() {
+        use crate::test::{DummyPools as Exchange, dummy_pool, dummy_state};
+
+        let mut block_states = BlockStateStorage::<u32>::init(memory(1));
+        let mut blocks = BlockStorage::init(memory(100));
+        let mut pools = PoolStorage::init(memory(0));
+        let mut events = EventStorage::init(memory(103));
+        let states = (0..3).map(|nonce| dummy_state(nonce, 0, &[])).collect();
+        pools.insert("pool-a".to_string(), dummy_pool("pool-a", states));
+        assert!(
+            finalize_blocks::<Exchange>(&mut block_states, &mut blocks, &mut pools, &mut events)
+                .is_ok()
+        );
+
+        let tx = |nonce: u8| TxRecord {
+            txid: Txid::from_bytes(&[nonce; 32]).unwrap(),
+            pools: vec!["pool-a".to_string()],
+        };
+        for height in 1..=70 {
+            let mut b = block(height, height as u64);
+            match height {
+                5 => b.txs.push(tx(1)),
+                30 => b.txs.push(tx(2)),
+                _ => {}
+            }
+            blocks.insert(height, b);
+        }
+        let nonces = |pools: &PoolStorage<_>| {
+            pools
+                .get(&"pool-a".to_string())
+                .unwrap()
+                .states()
+                .iter()
+                .map(|s: &crate::test::DummyPoolState| s.nonce)
+                .collect::<Vec<_>>()
+        };
+
+        // only blocks at or below `70 - 60 + 1` are finalized
+        assert!(
+            finalize_blocks::<Exchange>(&mut block_states, &mut blocks, &mut pools, &mut events)
+                .is_ok()
+        );
+        assert_eq!(blocks.keys().next(), Some(12));
+        assert_eq!(nonces(&pools), vec![1, 2]);
+
+        // a sweep without new blocks is a no-op
+        assert!(
+            finalize_blocks::<Exchange>(&mut block_states, &mut blocks, &mut pools, &mut events)
+                .is_ok()
+        );
+        assert_eq!(blocks.len(), 59);
+        assert_eq!(nonces(&pools), vec![1, 2]);
+
+        blocks.insert(90, block(90, 90));
+        assert!(
+            finalize_blocks::<Exchange>(&mut block_states, &mut blocks, &mut pools, &mut events)
+                .is_ok()
+        );
+        assert_eq!(blocks.keys().next(), Some(32));
+        assert_eq!(nonces(&pools), vec![2]);
+        assert_eq!(
+            events
+                .values()
+                .map(|e| (e.kind, e.txid, e.height))
+                .collect::<Vec<_>>(),
+            vec![
+                (EventKind::Finalized, tx(1).txid, Some(5)),
+                (EventKind::Finalized, tx(2).txid, Some(30)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_blocks_bounded_by_threshold() {
+        use crate::test::DummyPools as Exchange;
+
+        let mut block_states = BlockStateStorage::<u32>::init(memory(1));
+        let mut blocks = BlockStorage::init(memory(100));
+        let mut pools = PoolStorage::init(memory(0));
+        let mut events = EventStorage::init(memory(103));
+        let threshold = Exchange::finalize_threshold();
+        for height in 1..=1_000 {
+            assert!(
+                accept_block::<Exchange>(
+                    &mut block_states,
+                    &mut blocks,
+                    &mut pools,
+                    &mut events,
+                    block(height, height as u64),
+                )
+                .is_ok()
+            );
+            // the blocks kept, and so visited by the next block, don't grow with the history
+            assert!(blocks.len() < threshold as u64);
+        }
+        assert_eq!(blocks.keys().next(), Some(1_000 - threshold + 2));
+        assert_eq!(blocks.keys().next_back(), Some(1_000));
+    }
+
+    #[test]
+    fn test_finalize_blocks_below_threshold() {
+        use crate::test::{DummyPools as Exchange, dummy_pool, dummy_state};
+
+        let mut block_states = BlockStateStorage::<u32>::init(memory(1));
+        let mut blocks = BlockStorage::init(memory(100));
+        let mut pools = PoolStorage::init(memory(0));
+        let mut events = EventStorage::init(memory(103));
+        let states = (0..2).map(|nonce| dummy_state(nonce, 0, &[])).collect();
+        pools.insert("pool-a".to_string(), dummy_pool("pool-a", states));
+        for height in 0..=5 {
+            let mut b = block(height, height as u64);
+            if height == 1 {
+                b.txs.push(TxRecord {
+                    txid: Txid::from_bytes(&[1; 32]).unwrap(),
+                    pools: vec!["pool-a".to_string()],
+                });
+            }
+            blocks.insert(height, b);
+        }
+
+        // the tip 5 is far below the threshold 60, nothing is finalized nor pruned
+        assert!(
+            finalize_blocks::<Exchange>(&mut block_states, &mut blocks, &mut pools, &mut events)
+                .is_ok()
+        );
+        assert_eq!(blocks.len(), 6);
+        assert_eq!(blocks.get(&1).unwrap().txs[0].pools.len(), 1);
+        assert_eq!(pools.get(&"pool-a".to_string()).unwrap().states().len(), 2);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_periodic_maintenance() {
+        use crate::test::{DummyPoolState, dummy_pool, dummy_state};
+
+        thread_local! {
+            static THRESHOLD: std::cell::Cell<u32> = const { std::cell::Cell::new(60) };
+        }
+
+        struct MaintainedPools;
+
+        impl Pools for MaintainedPools {
+            type PoolState = DummyPoolState;
+
+            type BlockState = u32;
+
+            const POOL_STATE_MEMORY: u8 = 0;
+
+            const BLOCK_STATE_MEMORY: u8 = 1;
+
+            fn network() -> Network {
+                Network::Testnet4
+            }
+
+            fn finalize_threshold() -> u32 {
+                THRESHOLD.with(|t| t.get())
+            }
+
+            fn execute_lock_timeout_secs() -> Option<u64> {
+                Some(900)
+            }
+
+            fn enable_periodic_maintenance() -> bool {
+                true
+            }
+        }
+
+        impl Hook for MaintainedPools {}
+
+        let mut block_states = BlockStateStorage::<u32>::init(memory(1));
+        let mut blocks = BlockStorage::init(memory(100));
+        let mut pools = PoolStorage::init(memory(0));
+        let mut events = EventStorage::init(memory(103));
+        let mut locks = std::collections::HashMap::new();
+        let states = (0..3).map(|nonce| dummy_state(nonce, 0, &[])).collect();
+        pools.insert("pool-a".to_string(), dummy_pool("pool-a", states));
+        for height in 1..=20 {
+            let mut b = block(height, height as u64);
+            if height == 10 {
+                b.txs.push(TxRecord {
+                    txid: Txid::from_bytes(&[1; 32]).unwrap(),
+                    pools: vec!["pool-a".to_string()],
+                });
+            }
+            blocks.insert(height, b);
+        }
+        // an execution trapped after locking the pool at 1_000
+        locks.insert("pool-a".to_string(), 1_000);
+
+        let interval = MaintainedPools::maintenance_interval_secs();
+        let mut tick = |now: u64, locks: &mut std::collections::HashMap<String, u64>| {
+            run_maintenance::<MaintainedPools>(
+                &mut block_states,
+                &mut blocks,
+                &mut pools,
+                &mut events,
+                locks,
+                now,
+            )
+            .unwrap()
+        };
+        let mut released = vec![];
+        let mut now = 1_000;
+        while locks.contains_key("pool-a") {
+            now += interval;
+            released.extend(tick(now, &mut locks));
+        }
+        // the lock is released by the first tick after the timeout
+        assert_eq!(now, 1_000 + 2 * interval);
+        assert_eq!(released, vec!["pool-a".to_string()]);
+
+        // an upgrade lowers the threshold, the next tick finalizes without a new block
+        THRESHOLD.with(|t| t.set(6));
+        assert!(tick(now + interval, &mut locks).is_empty());
+        assert_eq!(blocks.keys().next(), Some(16));
+        assert_eq!(pools.get(&"pool-a".to_string()).unwrap().states().len(), 2);
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn test_finalize_with_per_pool_threshold() {
+        use crate::test::{DummyPoolState, dummy_pool, dummy_state};
+
+        struct TieredPools;
+
+        impl Pools for TieredPools {
+            type PoolState = DummyPoolState;
+
+            type BlockState = u32;
+
+            const POOL_STATE_MEMORY: u8 = 0;
+
+            const BLOCK_STATE_MEMORY: u8 = 1;
+
+            fn network() -> Network {
+                Network::Testnet4
+            }
+
+            fn finalize_threshold() -> u32 {
+                6
+            }
+
+            fn finalize_threshold_for(address: &str) -> u32 {
+                if address == "pool-fast" { 2 } else { 6 }
+            }
+        }
+
+        thread_local! {
+            static FINALIZED: std::cell::RefCell<Vec<(String, u64, u32)>> = Default::default();
+        }
+
+        impl Hook for TieredPools {
+            fn on_state_finalized(address: String, _txid: Txid, state: StateInfo, block: Block) {
+                FINALIZED.with_borrow_mut(|f| f.push((address, state.nonce, block.block_height)));
+            }
+        }
+
+        let mut block_states = BlockStateStorage::<u32>::init(memory(1));
+        let mut blocks = BlockStorage::init(memory(100));
+        let mut pools = PoolStorage::init(memory(0));
+        let mut events = EventStorage::init(memory(103));
+        for addr in ["pool-fast", "pool-slow"] {
+            let states = (0..3).map(|nonce| dummy_state(nonce, 0, &[])).collect();
+            pools.insert(addr.to_string(), dummy_pool(addr, states));
+        }
+        let tx = |nonce: u8| TxRecord {
+            txid: Txid::from_bytes(&[nonce; 32]).unwrap(),
+            pools: vec!["pool-fast".to_string(), "pool-slow".to_string()],
+        };
+        let nonces = |pools: &PoolStorage<DummyPoolState>, addr: &str| {
+            pools
+                .get(&addr.to_string())
+                .unwrap()
+                .states()
+                .iter()
+                .map(|s| s.nonce)
+                .collect::<Vec<_>>()
+        };
+        for height in 1..=12 {
+            let mut b = block(height, height as u64);
+            match height {
+                10 => b.txs.push(tx(1)),
+                11 => b.txs.push(tx(2)),
+                _ => {}
+            }
+            blocks.insert(height, b);
+        }
+
+        assert!(
+            finalize_blocks::<TieredPools>(&mut block_states, &mut blocks, &mut pools, &mut events)
+                .is_ok()
+        );
+        assert_eq!(nonces(&pools, "pool-fast"), vec![2]);
+        assert_eq!(nonces(&pools, "pool-slow"), vec![0, 1, 2]);
+        assert_eq!(blocks.keys().next(), Some(8));
+        let fast = |n: u64, h: u32| ("pool-fast".to_string(), n, h);
+        let slow = |n: u64, h: u32| ("pool-slow".to_string(), n, h);
+        assert_eq!(FINALIZED.take(), vec![fast(1, 10), fast(2, 11)]);
+
+        // sweeping again doesn't finalize the fast pool twice
+        assert!(
+            finalize_blocks::<TieredPools>(&mut block_states, &mut blocks, &mut pools, &mut events)
+                .is_ok()
+        );
+        assert!(FINALIZED.take().is_empty());
+
+        blocks.insert(16, block(16, 16));
+        assert!(
+            finalize_blocks::<TieredPools>(&mut block_states, &mut blocks, &mut pools, &mut events)
+                .is_ok()
+        );
+        assert_eq!(nonces(&pools, "pool-fast"), vec![2]);
+        assert_eq!(nonces(&pools, "pool-slow"), vec![2]);
+        assert_eq!(blocks.keys().next(), Some(12));
+        assert_eq!(FINALIZED.take(), vec![slow(1, 10), slow(2, 11)]);
+    }
+
+    #[test]
+    fn test_finalize_deeper_than_global_threshold() {
+        use crate::test::{DummyPoolState, dummy_pool, dummy_state};
+
+        struct GuardedPools;
+
+        impl Pools for GuardedPools {
+            type PoolState = DummyPoolState;
+
+            type BlockState = u32;
+
+            const POOL_STATE_MEMORY: u8 = 0;
+
+            const BLOCK_STATE_MEMORY: u8 = 1;
+
+            fn network() -> Network {
+                Network::Testnet4
+            }
+
+            fn finalize_threshold() -> u32 {
+                6
+            }
+
+            fn finalize_threshold_for(address: &str) -> u32 {
+                if address == "pool-deep" { 10 } else { 6 }
+            }
+
+            fn max_finalize_threshold() -> u32 {
+                10
+            }
+        }
+
+        impl Hook for GuardedPools {}
+
+        let mut block_states = BlockStateStorage::<u32>::init(memory(1));
+        let mut blocks = BlockStorage::init(memory(100));
+        let mut pools = PoolStorage::init(memory(0));
+        let mut events = EventStorage::init(memory(103));
+        for addr in ["pool-deep", "pool-std"] {
+            let states = (0..2).map(|nonce| dummy_state(nonce, 0, &[])).collect();
+            pools.insert(addr.to_string(), dummy_pool(addr, states));
+        }
+        let states_of = |pools: &PoolStorage<DummyPoolState>, addr: &str| {
+            pools.get(&addr.to_string()).unwrap().states().len()
+        };
+        for height in 1..=16 {
+            let mut b = block(height, height as u64);
+            if height == 10 {
+                b.txs.push(TxRecord {
+                    txid: Txid::from_bytes(&[1; 32]).unwrap(),
+                    pools: vec!["pool-deep".to_string(), "pool-std".to_string()],
+                });
+            }
+            blocks.insert(height, b);
+        }
+
+        assert!(
+            finalize_blocks::<GuardedPools>(
+                &mut block_states,
+                &mut blocks,
+                &mut pools,
+                &mut events
+            )
+            .is_ok()
+        );
+        // 7 confirmations are enough for the global threshold but not for the deep pool
+        assert_eq!(states_of(&pools, "pool-std"), 1);
+        assert_eq!(states_of(&pools, "pool-deep"), 2);
+        assert_eq!(blocks.keys().next(), Some(8));
+        // a reorg deeper than the global threshold still rolls the deep pool back
+        assert_eq!(
+            detect_reorg::<GuardedPools>(
+                &blocks,
+                &NewBlockInfo {
+                    block_height: 9,
+                    block_hash: "reorged".to_string(),
+                    block_timestamp: 9,
+                    confirmed_txids: vec![],
+                }
+            ),
+            ReorgOutcome::Recoverable { from: 9, to: 16 }
+        );
+
+        blocks.insert(19, block(19, 19));
+        assert!(
+            finalize_blocks::<GuardedPools>(
+                &mut block_states,
+                &mut blocks,
+                &mut pools,
+                &mut events
+            )
+            .is_ok()
+        );
+        assert_eq!(states_of(&pools, "pool-deep"), 1);
+        assert_eq!(blocks.keys().next(), Some(11));
+    }
+
+    #[test]
+    fn test_events_ring_buffer() {
+        let mut events = EventStorage::init(memory(103));
+        for nonce in 1..=5u8 {
+            let txid = Txid::from_bytes(&[nonce; 32]).unwrap();
+            push_event::<DummyPools>(
+                &mut events,
+                EventKind::Confirmed,
+                txid,
+                "pool-a".to_string(),
+                Some(nonce as u32),
+            );
+        }
+        assert_eq!(events.keys().collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert_eq!(
+            crate::queries::events(&events, 3, 10)
+                .into_iter()
+                .map(|e| e.seq)
+                .collect::<Vec<_>>(),
+            vec![4, 5]
+        );
+        assert_eq!(crate::queries::events(&events, 0, 1)[0].seq, 3);
+        assert!(crate::queries::events(&events, 5, 10).is_empty());
+    }
+
+    #[test]
+    fn test_rollback_pool_to_nonce() {
+        use crate::test::{DummyPools as Exchange, dummy_pool, dummy_state};
+
+        let mut pools = PoolStorage::init(memory(0));
+        let mut unconfirmed = UnconfirmedTxStorage::init(memory(101));
+        let mut blocks = BlockStorage::init(memory(100));
+        let mut events = EventStorage::init(memory(103));
+        let states = (1..=4).map(|nonce| dummy_state(nonce, 0, &[])).collect();
+        pools.insert("pool-a".to_string(), dummy_pool("pool-a", states));
+        let txid = |nonce: u8| Txid::from_bytes(&[nonce; 32]).unwrap();
+        let both = vec!["pool-a".to_string(), "pool-b".to_string()];
+        let mut confirmed = block(10, 10);
+        confirmed.txs.push(TxRecord {
+            txid: txid(3),
+            pools: both.clone(),
+        });
+        blocks.insert(10, confirmed);
+        unconfirmed.insert(
+            txid(4),
+            TxRecord {
+                txid: txid(4),
+                pools: vec!["pool-a".to_string()],
+            },
+        );
+
+        assert!(
+            rollback_pool_to_nonce::<Exchange>(
+                &mut pools,
+                &mut unconfirmed,
+                &mut blocks,
+                &mut events,
+                "pool-a".to_string(),
+                9
+            )
+            .is_err()
+        );
+        assert!(
+            rollback_pool_to_nonce::<Exchange>(
+                &mut pools,
+                &mut unconfirmed,
+                &mut blocks,
+                &mut events,
+                "pool-a".to_string(),
+                2
+            )
+            .is_ok()
+        );
+        let pool = pools.get(&"pool-a".to_string()).unwrap();
+        assert_eq!(pool.last_state().unwrap().nonce, 2);
+        assert!(unconfirmed.is_empty());
+        assert_eq!(
+            blocks.get(&10).unwrap().txs[0].pools,
+            vec!["pool-b".to_string()]
+        );
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn test_restore_pool() {
+        use crate::test::{DummyPools as Exchange, dummy_pool, dummy_state};
+
+        let mut pools = PoolStorage::init(memory(0));
+        let mut unconfirmed = UnconfirmedTxStorage::init(memory(101));
+        let states = (1..=3).map(|nonce| dummy_state(nonce, 0, &[])).collect();
+        pools.insert("pool-a".to_string(), dummy_pool("pool-a", states));
+        let snapshot = vec![dummy_state(1, 100, &[]), dummy_state(2, 200, &[])];
+        let restore =
+            |pools: &mut PoolStorage<_>, unconfirmed: &UnconfirmedTxStorage, address: &str| {
+                restore_pool::<Exchange>(pools, unconfirmed, address.to_string(), snapshot.clone())
+            };
+
+        assert!(restore(&mut pools, &unconfirmed, "pool-b").is_err());
+        let txid = Txid::from_bytes(&[3; 32]).unwrap();
+        unconfirmed.insert(
+            txid,
+            TxRecord {
+                txid,
+                pools: vec!["pool-a".to_string()],
+            },
+        );
+        assert!(
+            restore(&mut pools, &unconfirmed, "pool-a")
+                .unwrap_err()
+                .contains("1 unconfirmed transactions")
+        );
+        assert_eq!(pools.get(&"pool-a".to_string()).unwrap().states().len(), 3);
+
+        unconfirmed.remove(&txid);
+        restore(&mut pools, &unconfirmed, "pool-a").unwrap();
+        assert_eq!(
+            pools.get(&"pool-a".to_string()).unwrap().states(),
+            &snapshot
+        );
+    }
+
+    #[test]
+    fn test_reorg_engine() {
+        use crate::test::{DummyPools as Exchange, dummy_pool, dummy_state};
+
+        let mut engine = ReorgEngine::<Exchange>::new();
+        let pool = "pool-a".to_string();
+        engine.insert_pool(dummy_pool("pool-a", vec![dummy_state(0, 0, &[])]));
+        let txid = Txid::from_bytes(&[1; 32]).unwrap();
+        engine
+            .execute(txid, pool.clone(), dummy_state(1, 1_000, &[]))
+            .unwrap();
+        let new_block = |height: u32, hash: &str, txids: Vec<Txid>| NewBlockArgs {
+            block_height: height,
+            block_hash: hash.to_string(),
+            block_timestamp: height as u64,
+            confirmed_txids: txids,
+        };
+
+        engine
+            .new_block(new_block(100, "a100", vec![txid]))
+            .unwrap();
+        engine.new_block(new_block(101, "a101", vec![])).unwrap();
+        assert!(engine.unconfirmed.is_empty());
+
+        // the block confirming the tx is replaced, so it becomes unconfirmed again
+        engine.new_block(new_block(100, "b100", vec![])).unwrap();
+        assert_eq!(engine.blocks.keys().collect::<Vec<_>>(), vec![100]);
+        assert!(engine.unconfirmed.contains_key(&txid));
+
+        engine
+            .rollback_tx(RollbackTxArgs {
+                txid,
+                reason_code: "reorg".to_string(),
+            })
+            .unwrap();
+        assert_eq!(engine.pool(&pool).unwrap().states().len(), 1);
+        assert_eq!(
+            engine.events.values().map(|e| e.kind).collect::<Vec<_>>(),
+            vec![EventKind::Confirmed, EventKind::Rollbacked]
+        );
+    }
+
+    #[test]
+    fn test_deeper_threshold_protects_large_pool() {
+        use crate::test::{DummyPoolState, dummy_pool, dummy_state};
+
+        struct ProtectedPools;
+
+        impl Pools for ProtectedPools {
+            type PoolState = DummyPoolState;
+
+            type BlockState = u32;
+
+            const POOL_STATE_MEMORY: u8 = 0;
+
+            const BLOCK_STATE_MEMORY: u8 = 1;
+
+            fn network() -> Network {
+                Network::Testnet4
+            }
+
+            fn finalize_threshold() -> u32 {
+                3
+            }
+
+            fn finalize_threshold_for(address: &str) -> u32 {
+                if address == "pool-large" { 8 } else { 3 }
+            }
+
+            fn max_finalize_threshold() -> u32 {
+                8
+            }
+        }
+
+        impl Hook for ProtectedPools {}
+
+        let mut engine = ReorgEngine::<ProtectedPools>::new();
+        let (small, large) = ("pool-small".to_string(), "pool-large".to_string());
+        engine.insert_pool(dummy_pool("pool-small", vec![dummy_state(0, 0, &[])]));
+        engine.insert_pool(dummy_pool("pool-large", vec![dummy_state(0, 0, &[])]));
+        let (small_txid, large_txid) = (
+            Txid::from_bytes(&[1; 32]).unwrap(),
+            Txid::from_bytes(&[2; 32]).unwrap(),
+        );
+        engine
+            .execute(small_txid, small.clone(), dummy_state(1, 10, &[]))
+            .unwrap();
+        engine
+            .execute(
+                large_txid,
+                large.clone(),
+                DummyPoolState {
+                    txid: large_txid,
+                    ..dummy_state(1, 10_000, &[])
+                },
+            )
+            .unwrap();
+        let new_block = |height: u32, hash: &str, txids: Vec<Txid>| NewBlockArgs {
+            block_height: height,
+            block_hash: hash.to_string(),
+            block_timestamp: height as u64,
+            confirmed_txids: txids,
+        };
+
+        engine
+            .new_block(new_block(100, "a100", vec![small_txid, large_txid]))
+            .unwrap();
+        for height in 101..=104 {
+            engine
+                .new_block(new_block(height, &format!("a{height}"), vec![]))
+                .unwrap();
+        }
+        // 5 confirmations finalize the small pool only
+        assert_eq!(engine.pool(&small).unwrap().states().len(), 1);
+        assert_eq!(engine.pool(&large).unwrap().states().len(), 2);
+
+        // a reorg deeper than the global threshold reverts the large pool's tx
+        engine.new_block(new_block(100, "b100", vec![])).unwrap();
+        assert!(engine.unconfirmed.contains_key(&large_txid));
+        engine
+            .rollback_tx(RollbackTxArgs {
+                txid: large_txid,
+                reason_code: "reorg".to_string(),
+            })
+            .unwrap();
+        assert_eq!(engine.pool(&large).unwrap().states().len(), 1);
+        assert_eq!(engine.pool(&large).unwrap().last_state().unwrap().nonce, 0);
+        assert_eq!(engine.pool(&small).unwrap().last_state().unwrap().nonce, 1);
+    }
+}
@@ -29,6 +29,9 @@
 //!    DummyStorage::with_mut(|map| map.insert("foo".to_string(), "bar".to_string()));
 //! }
 //! ```
+//!
+//! `StableBTreeMap` storages could also use `T::range_with` to visit a range of keys, and `StableMinHeap`
+//! storages could use `T::drain_while` to pop the smallest items, e.g. processing an ordered queue.
 
 use ic_stable_structures::{BTreeMap, BTreeSet, Cell, MinHeap, Storable, Vec};
 
@@ -124,3 +127,72 @@ where
         MinHeap::init(memory)
     }
 }
+
+/// Calls `f` with the entries of the map within the key range, in ascending order of keys.
+pub fn range_with<K, V, B, F, R>(map: &BTreeMap<K, V, crate::Memory>, range: B, f: F) -> R
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    B: std::ops::RangeBounds<K>,
+    F: FnOnce(&mut dyn Iterator<Item = (K, V)>) -> R,
+{
+    f(&mut map.range(range).map(|entry| entry.into_pair()))
+}
+
+/// Pops the smallest items of the heap as long as they satisfy the predicate, returns them in ascending order.
+pub fn drain_while<T, P>(heap: &mut MinHeap<T, crate::Memory>, mut pred: P) -> std::vec::Vec<T>
+where
+    T: Storable + Ord + Clone,
+    P: FnMut(&T) -> bool,
+{
+    let mut drained = std::vec::Vec::new();
+    while let Some(item) = heap.peek() {
+        if !pred(&item) {
+            break;
+        }
+        heap.pop();
+        drained.push(item);
+    }
+    drained
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::{
+        DefaultMemoryImpl,
+        memory_manager::{MemoryId, MemoryManager},
+    };
+
+    fn memory(id: u8) -> crate::Memory {
+        MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(id))
+    }
+
+    #[test]
+    fn test_range_with() {
+        let mut map = StableBTreeMap::<u32, String>::init(memory(0));
+        for i in 0..10 {
+            map.insert(i, i.to_string());
+        }
+        let keys = range_with(&map, 3..6, |entries| {
+            entries.map(|(k, _)| k).collect::<std::vec::Vec<_>>()
+        });
+        assert_eq!(keys, vec![3, 4, 5]);
+        assert_eq!(range_with(&map, 20.., |entries| entries.count()), 0);
+    }
+
+    #[test]
+    fn test_drain_while() {
+        let mut heap = StableMinHeap::<u64>::init(memory(0));
+        for fee_rate in [7, 2, 9, 4, 1] {
+            heap.push(&fee_rate);
+        }
+        assert_eq!(
+            drain_while(&mut heap, |fee_rate| *fee_rate < 5),
+            vec![1, 2, 4]
+        );
+        assert_eq!(heap.len(), 2);
+        assert!(drain_while(&mut heap, |_| false).is_empty());
+        assert_eq!(drain_while(&mut heap, |_| true), vec![7, 9]);
+    }
+}
@@ -0,0 +1,180 @@
+//! Helpers for unit testing the exchanges without the orchestrator, enabled by the `test-util` feature.
+//!
+//! ```ignore
+//! let args = ActionArgsBuilder::new("swap", "bc1p...")
+//!     .nonce(2)
+//!     .input_coin("bc1q...", CoinBalance { id: CoinId::btc(), value: 10_000 })
+//!     .build();
+//! let state = execute_swap(&psbt, args).await?;
+//! ```
+
+use crate::ActionArgs;
+use crate::types::{CoinBalance, InputCoin, Intention, OutputCoin, Txid, Utxo};
+use candid::Principal;
+
+/// Builds the `ActionArgs` passed to the action functions.
+///
+/// By default the intention targets `pool_address` with the nonce 1 and moves nothing,
+/// the txid is all zeros and the initiator is a testnet address.
+#[derive(Clone, Debug)]
+pub struct ActionArgsBuilder {
+    args: ActionArgs,
+}
+
+impl ActionArgsBuilder {
+    /// The initiator address used unless `initiator_address` is called.
+    pub const DEFAULT_INITIATOR: &'static str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+
+    pub fn new(action: impl Into<String>, pool_address: impl Into<String>) -> Self {
+        Self {
+            args: ActionArgs {
+                txid: Txid::default(),
+                initiator_address: Self::DEFAULT_INITIATOR.to_string(),
+                intention: Intention {
+                    exchange_id: String::new(),
+                    action: action.into(),
+                    action_params: String::new(),
+                    pool_address: pool_address.into(),
+                    nonce: 1,
+                    pool_utxo_spent: vec![],
+                    pool_utxo_received: vec![],
+                    input_coins: vec![],
+                    output_coins: vec![],
+                },
+                other_intentions: vec![],
+                unconfirmed_tx_count: 0,
+                is_reapply: false,
+                invoke_caller_principal: Principal::anonymous(),
+            },
+        }
+    }
+
+    pub fn txid(mut self, txid: Txid) -> Self {
+        self.args.txid = txid;
+        self
+    }
+
+    pub fn initiator_address(mut self, address: impl Into<String>) -> Self {
+        self.args.initiator_address = address.into();
+        self
+    }
+
+    pub fn exchange_id(mut self, exchange_id: impl Into<String>) -> Self {
+        self.args.intention.exchange_id = exchange_id.into();
+        self
+    }
+
+    pub fn action_params(mut self, params: impl Into<String>) -> Self {
+        self.args.intention.action_params = params.into();
+        self
+    }
+
+    pub fn nonce(mut self, nonce: u64) -> Self {
+        self.args.intention.nonce = nonce;
+        self
+    }
+
+    /// Adds a pool UTXO spent by the intention, formatted as `txid:vout`.
+    pub fn pool_utxo_spent(mut self, outpoint: impl Into<String>) -> Self {
+        self.args.intention.pool_utxo_spent.push(outpoint.into());
+        self
+    }
+
+    /// Adds a UTXO received by the pool.
+    pub fn pool_utxo_received(mut self, utxo: Utxo) -> Self {
+        self.args.intention.pool_utxo_received.push(utxo);
+        self
+    }
+
+    /// Adds the coins paid by `from` to the pool.
+    pub fn input_coin(mut self, from: impl Into<String>, coin: CoinBalance) -> Self {
+        self.args.intention.input_coins.push(InputCoin {
+            from: from.into(),
+            coin,
+        });
+        self
+    }
+
+    /// Adds the coins paid by the pool to `to`.
+    pub fn output_coin(mut self, to: impl Into<String>, coin: CoinBalance) -> Self {
+        self.args.intention.output_coins.push(OutputCoin {
+            to: to.into(),
+            coin,
+        });
+        self
+    }
+
+    /// Adds an intention of the same transaction targeting another pool.
+    pub fn other_intention(mut self, intention: Intention) -> Self {
+        self.args.other_intentions.push(intention);
+        self
+    }
+
+    pub fn unconfirmed_tx_count(mut self, count: usize) -> Self {
+        self.args.unconfirmed_tx_count = count;
+        self
+    }
+
+    pub fn reapply(mut self, is_reapply: bool) -> Self {
+        self.args.is_reapply = is_reapply;
+        self
+    }
+
+    pub fn invoke_caller_principal(mut self, principal: Principal) -> Self {
+        self.args.invoke_caller_principal = principal;
+        self
+    }
+
+    pub fn build(self) -> ActionArgs {
+        self.args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::CoinId;
+    use crate::{Pool, StateView, test::*};
+
+    // a minimal action, adding the paid sats to the reserve of the last state
+    fn deposit(pool: &Pool<DummyPoolState>, args: ActionArgs) -> Result<DummyPoolState, String> {
+        pool.check_nonce(args.intention.nonce)?;
+        let paid = args
+            .intention
+            .input_coins
+            .iter()
+            .filter(|input| input.coin.id == CoinId::btc())
+            .map(|input| input.coin.value as u64)
+            .sum::<u64>();
+        let mut state = pool.last_state().cloned().ok_or("empty pool")?;
+        state.btc_reserved += paid;
+        state.set_nonce(args.intention.nonce);
+        state.txid = args.txid;
+        Ok(state)
+    }
+
+    #[test]
+    fn test_action_args_builder() {
+        let pool = dummy_pool("test-address", vec![dummy_state(1, 100, &[])]);
+        let sats = |value| CoinBalance {
+            id: CoinId::btc(),
+            value,
+        };
+        let args = ActionArgsBuilder::new("deposit", "test-address")
+            .nonce(2)
+            .input_coin(ActionArgsBuilder::DEFAULT_INITIATOR, sats(1_000))
+            .build();
+        assert_eq!(args.intention.action, "deposit");
+        assert_eq!(args.initiator_address, ActionArgsBuilder::DEFAULT_INITIATOR);
+        assert!(args.other_intentions.is_empty());
+
+        let state = deposit(&pool, args).unwrap();
+        assert_eq!(state.inspect_state().nonce, 2);
+        assert_eq!(state.inspect_state().btc_reserved, 1_100);
+
+        let stale = ActionArgsBuilder::new("deposit", "test-address")
+            .input_coin(ActionArgsBuilder::DEFAULT_INITIATOR, sats(1_000))
+            .build();
+        assert!(deposit(&pool, stale).is_err());
+    }
+}
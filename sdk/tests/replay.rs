@@ -0,0 +1,5 @@
+#[test]
+fn replay_action() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/replay_action.rs");
+}
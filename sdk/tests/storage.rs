@@ -0,0 +1,6 @@
+#[test]
+fn storage_declarations() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/storage_nested_generics.rs");
+    t.compile_fail("tests/ui/storage_generic_alias.rs");
+}
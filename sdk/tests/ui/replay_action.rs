@@ -0,0 +1,102 @@
+use ree_exchange_sdk::{error::*, prelude::*, types::*};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, Serialize, Default, StateView)]
+pub struct DummyPoolState {
+    pub txid: Txid,
+    pub nonce: u64,
+    pub coin_reserved: Vec<CoinBalance>,
+    pub btc_reserved: u64,
+    pub utxos: Vec<Utxo>,
+    pub attributes: String,
+}
+
+#[exchange]
+pub mod exchange {
+    use super::*;
+
+    #[pools]
+    pub struct DummyPools;
+
+    impl Pools for DummyPools {
+        type PoolState = DummyPoolState;
+        type BlockState = u32;
+        const POOL_STATE_MEMORY: u8 = 1;
+        const BLOCK_STATE_MEMORY: u8 = 2;
+        fn network() -> Network {
+            Network::Testnet4
+        }
+    }
+
+    #[action(name = "swap")]
+    pub async fn execute_swap(
+        _psbt: &bitcoin::Psbt,
+        args: ActionArgs,
+    ) -> ActionResult<DummyPoolState> {
+        let pool = DummyPools::get(&args.intention.pool_address).ok_or(Error::PoolNotFound)?;
+        let mut state = pool.last_state().cloned().unwrap_or_default();
+        state.btc_reserved += 1_000;
+        Ok(state)
+    }
+}
+
+fn main() {
+    use exchange::DummyPools;
+    use std::future::Future;
+    use std::task::{Context, Poll, Waker};
+
+    let address = "pool-a".to_string();
+    let mut pool = Pool::new(Metadata {
+        key: Pubkey::from_raw(vec![2; 33]).unwrap(),
+        key_derivation_path: vec![b"pool-a".to_vec()].into(),
+        name: "pool-a".to_string(),
+        address: address.clone(),
+    });
+    pool.states_mut().push(DummyPoolState {
+        btc_reserved: 50_000,
+        ..Default::default()
+    });
+    DummyPools::insert(pool);
+
+    let args = ActionArgs {
+        txid: Txid::default(),
+        initiator_address: String::new(),
+        intention: Intention {
+            exchange_id: "DUMMY".to_string(),
+            action: "swap".to_string(),
+            action_params: String::new(),
+            pool_address: address.clone(),
+            nonce: 1,
+            pool_utxo_spent: vec![],
+            pool_utxo_received: vec![],
+            input_coins: vec![],
+            output_coins: vec![],
+        },
+        other_intentions: vec![],
+        unconfirmed_tx_count: 0,
+        is_reapply: false,
+        invoke_caller_principal: candid::Principal::anonymous(),
+    };
+    let psbt = bitcoin::Psbt::from_unsigned_tx(bitcoin::Transaction {
+        version: bitcoin::transaction::Version::TWO,
+        lock_time: bitcoin::absolute::LockTime::ZERO,
+        input: vec![],
+        output: vec![],
+    })
+    .unwrap();
+    let base_state = DummyPoolState {
+        btc_reserved: 7_000,
+        ..Default::default()
+    };
+    let replay = std::pin::pin!(DummyPools::replay_action("swap", &psbt, args, base_state));
+    let Poll::Ready(result) = replay.poll(&mut Context::from_waker(Waker::noop())) else {
+        panic!("the action doesn't await");
+    };
+
+    // the action reading its pool through `get` saw the base state
+    assert_eq!(result.unwrap().btc_reserved, 8_000);
+    // and the stored pool is untouched
+    let stored = DummyPools::get(&address).unwrap();
+    assert_eq!(stored.states().len(), 1);
+    assert_eq!(stored.last_state().unwrap().btc_reserved, 50_000);
+}
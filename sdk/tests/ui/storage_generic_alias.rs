@@ -0,0 +1,37 @@
+use candid::CandidType;
+use ree_exchange_sdk::{prelude::*, types::*};
+use serde::{Deserialize, Serialize};
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Default, StateView)]
+pub struct DummyPoolState {
+    pub txid: Txid,
+    pub nonce: u64,
+    pub coin_reserved: Vec<CoinBalance>,
+    pub btc_reserved: u64,
+    pub utxos: Vec<Utxo>,
+    pub attributes: String,
+}
+
+#[exchange]
+pub mod exchange {
+    use super::*;
+    use ree_exchange_sdk::store::*;
+
+    #[pools]
+    pub struct DummyPools;
+
+    impl Pools for DummyPools {
+        type PoolState = DummyPoolState;
+        type BlockState = u32;
+        const POOL_STATE_MEMORY: u8 = 1;
+        const BLOCK_STATE_MEMORY: u8 = 2;
+        fn network() -> Network {
+            Network::Testnet4
+        }
+    }
+
+    #[storage(memory = 3)]
+    pub type Queue<T> = StableVec<T>;
+}
+
+fn main() {}
@@ -0,0 +1,52 @@
+use candid::CandidType;
+use ree_exchange_sdk::{prelude::*, types::*};
+use serde::{Deserialize, Serialize};
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Default, StateView)]
+pub struct DummyPoolState {
+    pub txid: Txid,
+    pub nonce: u64,
+    pub coin_reserved: Vec<CoinBalance>,
+    pub btc_reserved: u64,
+    pub utxos: Vec<Utxo>,
+    pub attributes: String,
+}
+
+#[exchange]
+pub mod exchange {
+    use super::*;
+    use ree_exchange_sdk::store::*;
+
+    #[pools]
+    pub struct DummyPools;
+
+    impl Pools for DummyPools {
+        type PoolState = DummyPoolState;
+        type BlockState = u32;
+        const POOL_STATE_MEMORY: u8 = 1;
+        const BLOCK_STATE_MEMORY: u8 = 2;
+        fn network() -> Network {
+            Network::Testnet4
+        }
+    }
+
+    #[storage(memory = 3)]
+    pub type Reserves = StableCell<ree_exchange_sdk::types::CoinBalances>;
+
+    #[storage(memory = 4)]
+    pub type Blobs = ree_exchange_sdk::store::StableBTreeMap<String, Vec<u8>>;
+
+    #[storage(memory = 5)]
+    pub type HTTPRoutes = StableVec<u64>;
+
+    #[ic_cdk::query]
+    pub fn storage_sizes() -> (usize, u64, u64) {
+        (
+            Reserves::with(|reserves| reserves.get().iter().count()),
+            Blobs::with(|blobs| blobs.len()),
+            HTTPRoutes::with(|routes| routes.len()),
+        )
+    }
+}
+
+fn main() {}
@@ -0,0 +1,76 @@
+use ree_exchange_sdk::{error::*, prelude::*, types::*};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, Serialize, Default, StateView)]
+pub struct OldPoolState {
+    pub txid: Txid,
+    pub nonce: u64,
+    pub coin_reserved: Vec<CoinBalance>,
+    pub btc_reserved: u64,
+    pub utxos: Vec<Utxo>,
+    pub attributes: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Default, StateView)]
+pub struct DummyPoolState {
+    pub txid: Txid,
+    pub nonce: u64,
+    pub coin_reserved: Vec<CoinBalance>,
+    pub btc_reserved: u64,
+    pub utxos: Vec<Utxo>,
+    pub attributes: String,
+    pub fee_rate: u64,
+}
+
+impl From<OldPoolState> for DummyPoolState {
+    fn from(old: OldPoolState) -> Self {
+        Self {
+            txid: old.txid,
+            nonce: old.nonce,
+            coin_reserved: old.coin_reserved,
+            btc_reserved: old.btc_reserved,
+            utxos: old.utxos,
+            attributes: old.attributes,
+            fee_rate: 0,
+        }
+    }
+}
+
+#[exchange]
+pub mod exchange {
+    use super::*;
+
+    #[pools]
+    pub struct DummyPools;
+
+    impl Pools for DummyPools {
+        type PoolState = DummyPoolState;
+        type BlockState = u32;
+        const POOL_STATE_MEMORY: u8 = 3;
+        const BLOCK_STATE_MEMORY: u8 = 4;
+        fn network() -> Network {
+            Network::Testnet4
+        }
+    }
+
+    #[upgrade(auto)]
+    impl Upgrade<DummyPools> for DummyPools {
+        type PoolState = OldPoolState;
+        type BlockState = u32;
+        const POOL_STATE_MEMORY: u8 = 1;
+        const BLOCK_STATE_MEMORY: u8 = 2;
+    }
+
+    #[action(name = "swap")]
+    pub async fn execute_swap(
+        _psbt: &bitcoin::Psbt,
+        args: ActionArgs,
+    ) -> ActionResult<DummyPoolState> {
+        let pool = DummyPools::get(&args.intention.pool_address).ok_or(Error::PoolNotFound)?;
+        let mut state = pool.last_state().cloned().unwrap_or_default();
+        state.txid = args.txid;
+        Ok(state)
+    }
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[test]
+fn upgrade_declarations() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/upgrade_user_post_upgrade.rs");
+    t.pass("tests/ui/upgrade_auto.rs");
+}
@@ -0,0 +1,94 @@
+use crate::{CoinBalance, CoinBalances, CoinId, Pubkey, Txid, Utxo};
+use proptest::prelude::*;
+
+impl Arbitrary for Txid {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        any::<[u8; 32]>()
+            .prop_map(|bytes| Txid::from_bytes(&bytes).unwrap())
+            .boxed()
+    }
+}
+
+impl Arbitrary for Pubkey {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    /// Generates compressed public keys which are valid points on the curve.
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (any::<bool>(), any::<[u8; 32]>())
+            .prop_filter_map("not a point on the curve", |(odd, x)| {
+                bitcoin::XOnlyPublicKey::from_slice(&x).ok()?;
+                let mut key = vec![if odd { 0x03 } else { 0x02 }];
+                key.extend_from_slice(&x);
+                Pubkey::from_raw(key).ok()
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for CoinId {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (any::<u64>(), any::<u32>())
+            .prop_map(|(block, tx)| CoinId { block, tx })
+            .boxed()
+    }
+}
+
+impl Arbitrary for CoinBalance {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (any::<CoinId>(), any::<u128>())
+            .prop_map(|(id, value)| CoinBalance { id, value })
+            .boxed()
+    }
+}
+
+impl Arbitrary for Utxo {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    /// Generates UTXOs holding up to 4 distinct coins.
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (
+            any::<Txid>(),
+            any::<u32>(),
+            prop::collection::btree_map(any::<CoinId>(), any::<u128>(), 0..4),
+            any::<u64>(),
+        )
+            .prop_map(|(txid, vout, coins, sats)| Utxo {
+                txid,
+                vout,
+                coins: CoinBalances(
+                    coins
+                        .into_iter()
+                        .map(|(id, value)| CoinBalance { id, value })
+                        .collect(),
+                ),
+                sats,
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::str::FromStr;
+
+    proptest! {
+        #[test]
+        fn test_arbitrary_roundtrip(txid in any::<Txid>(), pubkey in any::<Pubkey>(), utxo in any::<Utxo>()) {
+            prop_assert_eq!(Txid::from_str(&txid.to_string()).unwrap(), txid);
+            prop_assert!(pubkey.to_public_key().is_ok());
+            prop_assert_eq!(Utxo::try_from(utxo.outpoint(), utxo.coins.clone(), utxo.sats).unwrap(), utxo);
+        }
+    }
+}
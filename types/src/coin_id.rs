@@ -1,8 +1,10 @@
+use alloc::collections::BTreeSet;
 use alloc::str::FromStr;
 use candid::{
     CandidType,
     types::{Serializer, Type, TypeInner},
 };
+use core::ops::RangeInclusive;
 use ic_stable_structures::{Storable, storable::Bound};
 
 /// The identifier for a RUNE in the Bitcoin network. Specially, BTC is represented by `CoinId::btc()`.
@@ -26,7 +28,7 @@ impl PartialOrd for CoinId {
 
 impl Storable for CoinId {
     const BOUND: Bound = Bound::Bounded {
-        max_size: 12,
+        max_size: CoinId::BYTE_LEN as u32,
         is_fixed_size: true,
     };
 
@@ -59,6 +61,16 @@ impl Storable for CoinId {
 }
 
 impl CoinId {
+    /// The length of the canonical bytes, i.e. the big-endian `block` followed by `tx`.
+    pub const BYTE_LEN: usize = 12;
+
+    /// The maximum length of the `block:tx` string form.
+    pub const MAX_LEN: usize = 31;
+
+    /// The maximum index of a transaction in a block, a block of 4M weight units holds at most
+    /// 16,666 transactions of 240 weight units, the smallest possible.
+    pub const MAX_TX_INDEX: u32 = 16_666;
+
     pub fn rune(block: u64, tx: u32) -> Self {
         Self { block, tx }
     }
@@ -68,6 +80,22 @@ impl CoinId {
         Self { block: 0, tx: 0 }
     }
 
+    /// Returns an error unless the id is BTC or a plausible rune, i.e. etched by a non-coinbase
+    /// transaction in a block after genesis, except `1:0` hardcoded for `UNCOMMON•GOODS`.
+    /// This catches malformed ids, e.g. parsed from a mistyped `action_params`.
+    pub fn validate(&self) -> Result<(), String> {
+        if *self == Self::btc() || *self == Self::rune(1, 0) {
+            return Ok(());
+        }
+        if self.block == 0 || self.block > u32::MAX as u64 {
+            return Err(format!("Invalid CoinId {}: block out of range", self));
+        }
+        if self.tx == 0 || self.tx > Self::MAX_TX_INDEX {
+            return Err(format!("Invalid CoinId {}: tx out of range", self));
+        }
+        Ok(())
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = vec![];
         bytes.extend_from_slice(self.block.to_be_bytes().as_ref());
@@ -83,6 +111,44 @@ impl CoinId {
             tx: u32::from_be_bytes(tx),
         }
     }
+
+    /// Returns true if the rune was etched in a block of `range`. BTC is treated as block 0,
+    /// so it's only included by a range starting from 0.
+    pub fn in_block_range(&self, range: RangeInclusive<u64>) -> bool {
+        range.contains(&self.block)
+    }
+}
+
+/// A predicate on the coin ids, e.g. restricting the runes accepted by an exchange.
+///
+/// ```ignore
+/// let filter = CoinIdFilter::Except([CoinId::rune(840000, 3)].into());
+/// if !filter.matches(&coin.id) {
+///     return Err(format!("{} is not accepted", coin.id));
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum CoinIdFilter {
+    /// Matches every coin.
+    #[default]
+    Any,
+    /// Matches only the listed coins.
+    Only(BTreeSet<CoinId>),
+    /// Matches every coin except the listed ones.
+    Except(BTreeSet<CoinId>),
+    /// Matches the runes etched in the blocks of the range, see `CoinId::in_block_range`.
+    BlockRange(RangeInclusive<u64>),
+}
+
+impl CoinIdFilter {
+    pub fn matches(&self, id: &CoinId) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Only(ids) => ids.contains(id),
+            Self::Except(ids) => !ids.contains(id),
+            Self::BlockRange(range) => id.in_block_range(range.clone()),
+        }
+    }
 }
 
 impl FromStr for CoinId {
@@ -178,4 +244,44 @@ mod tests {
         let decoded = CoinId::from_bytes(&bytes);
         assert_eq!(coin_id, decoded);
     }
+
+    #[test]
+    fn test_validate() {
+        assert!(CoinId::btc().validate().is_ok());
+        assert!(CoinId::rune(1, 0).validate().is_ok());
+        assert!(CoinId::rune(840000, 846).validate().is_ok());
+        assert!(CoinId::rune(0, 846).validate().is_err());
+        assert!(CoinId::rune(840000, 0).validate().is_err());
+        assert!(
+            CoinId::rune(840000, CoinId::MAX_TX_INDEX + 1)
+                .validate()
+                .is_err()
+        );
+        assert!(CoinId::rune(u32::MAX as u64 + 1, 1).validate().is_err());
+
+        let max = CoinId::rune(u64::MAX, u32::MAX);
+        assert_eq!(max.to_bytes().len(), CoinId::BYTE_LEN);
+        assert_eq!(max.to_string().len(), CoinId::MAX_LEN);
+    }
+
+    #[test]
+    fn test_coin_id_filter() {
+        let rune = CoinId::rune(840000, 846);
+        assert!(rune.in_block_range(840000..=840000));
+        assert!(!rune.in_block_range(840001..=u64::MAX));
+        assert!(!CoinId::btc().in_block_range(1..=u64::MAX));
+        assert!(CoinId::btc().in_block_range(0..=840000));
+
+        let other = CoinId::rune(900000, 1);
+        assert!(CoinIdFilter::default().matches(&rune));
+        let listed = BTreeSet::from([rune]);
+        assert!(CoinIdFilter::Only(listed.clone()).matches(&rune));
+        assert!(!CoinIdFilter::Only(listed.clone()).matches(&other));
+        assert!(!CoinIdFilter::Except(listed.clone()).matches(&rune));
+        assert!(CoinIdFilter::Except(listed).matches(&other));
+        let recent = CoinIdFilter::BlockRange(850000..=u64::MAX);
+        assert!(recent.matches(&other));
+        assert!(!recent.matches(&rune));
+        assert!(!recent.matches(&CoinId::btc()));
+    }
 }
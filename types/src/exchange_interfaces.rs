@@ -44,9 +44,11 @@ pub struct ExecuteTxArgs {
 }
 
 impl ExecuteTxArgs {
+    /// Decodes the PSBT, the error tells whether the hex or the PSBT itself is malformed.
     pub fn psbt(&self) -> Result<bitcoin::Psbt, String> {
-        let raw = hex::decode(&self.psbt_hex).map_err(|_| "invalid psbt".to_string())?;
-        bitcoin::Psbt::deserialize(raw.as_slice()).map_err(|_| "invalid psbt".to_string())
+        let raw = hex::decode(&self.psbt_hex).map_err(|e| format!("invalid psbt hex: {}", e))?;
+        bitcoin::Psbt::deserialize(raw.as_slice())
+            .map_err(|e| format!("invalid psbt structure: {}", e))
     }
 }
 
@@ -90,3 +92,46 @@ impl Storable for NewBlockInfo {
 
     const BOUND: Bound = Bound::Unbounded;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_psbt_errors() {
+        let args = |psbt_hex: &str| ExecuteTxArgs {
+            psbt_hex: psbt_hex.to_string(),
+            txid: Txid::default(),
+            intention_set: IntentionSet {
+                initiator_address: String::new(),
+                tx_fee_in_sats: 0,
+                intentions: vec![],
+            },
+            intention_index: 0,
+            zero_confirmed_tx_queue_length: 0,
+            is_reapply: None,
+            invoke_caller_principal: None,
+        };
+        assert!(
+            args("zz")
+                .psbt()
+                .unwrap_err()
+                .starts_with("invalid psbt hex: ")
+        );
+        assert!(
+            args("00")
+                .psbt()
+                .unwrap_err()
+                .starts_with("invalid psbt structure: ")
+        );
+
+        let tx = bitcoin::Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        };
+        let psbt = bitcoin::Psbt::from_unsigned_tx(tx).unwrap();
+        assert_eq!(args(&psbt.serialize_hex()).psbt(), Ok(psbt));
+    }
+}
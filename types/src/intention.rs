@@ -2,7 +2,7 @@ use crate::{
     CoinBalance, CoinId, Utxo,
     bitcoin::{OutPoint, Txid},
 };
-use alloc::collections::BTreeSet;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::str::FromStr;
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
@@ -49,7 +49,7 @@ pub struct IntentionSet {
 }
 
 impl Intention {
-    pub fn pool_outpoints(&self) -> Result<Vec<OutPoint>, Box<dyn std::error::Error>> {
+    pub fn pool_outpoints(&self) -> Result<Vec<OutPoint>, Box<dyn core::error::Error>> {
         let outpoints: Vec<_> = self
             .pool_utxo_spent
             .iter()
@@ -113,6 +113,67 @@ impl IntentionSet {
         output_coins.into_iter().collect()
     }
 
+    /// Returns the net amount (received minus paid) of each coin for each address across all intentions.
+    /// A participant is balanced if all of its net amounts are zero.
+    pub fn net_coin_flow(&self) -> BTreeMap<String, BTreeMap<CoinId, i128>> {
+        let signed = |value: u128| value.min(i128::MAX as u128) as i128;
+        let mut flow: BTreeMap<String, BTreeMap<CoinId, i128>> = BTreeMap::new();
+        for intention in self.intentions.iter() {
+            for input_coin in intention.input_coins.iter() {
+                let net = flow
+                    .entry(input_coin.from.clone())
+                    .or_default()
+                    .entry(input_coin.coin.id)
+                    .or_default();
+                *net = net.saturating_sub(signed(input_coin.coin.value));
+            }
+            for output_coin in intention.output_coins.iter() {
+                let net = flow
+                    .entry(output_coin.to.clone())
+                    .or_default()
+                    .entry(output_coin.coin.id)
+                    .or_default();
+                *net = net.saturating_add(signed(output_coin.coin.value));
+            }
+        }
+        flow
+    }
+
+    /// Parses the intention set from its JSON form and validates it, see `validate`.
+    #[cfg(feature = "json")]
+    pub fn from_json(json: &str) -> Result<IntentionSet, String> {
+        let intention_set: IntentionSet =
+            serde_json::from_str(json).map_err(|e| format!("Invalid intention set: {}", e))?;
+        intention_set.validate()?;
+        Ok(intention_set)
+    }
+
+    /// Returns an error describing the first malformed field, i.e. an empty `exchange_id`,
+    /// a `pool_utxo_spent` not formatted as `txid:vout` or an invalid coin id, so the malformed
+    /// intentions are rejected up front instead of failing in the middle of an action.
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, intention) in self.intentions.iter().enumerate() {
+            if intention.exchange_id.is_empty() {
+                return Err(format!("Intention {}: empty exchange_id", i));
+            }
+            for outpoint in intention.pool_utxo_spent.iter() {
+                OutPoint::from_str(outpoint).map_err(|e| {
+                    format!("Intention {}: invalid outpoint {}: {}", i, outpoint, e)
+                })?;
+            }
+            let received = intention
+                .pool_utxo_received
+                .iter()
+                .flat_map(|utxo| utxo.coins.iter().map(|coin| coin.id));
+            for coin_id in intention.all_coin_ids().into_iter().chain(received) {
+                coin_id
+                    .validate()
+                    .map_err(|e| format!("Intention {}: {}", i, e))?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn all_coin_ids(&self) -> Vec<CoinId> {
         let mut coin_ids: BTreeSet<CoinId> = BTreeSet::new();
         for intention in self.intentions.iter() {
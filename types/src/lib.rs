@@ -4,6 +4,8 @@ use alloc::str::FromStr;
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "proptest")]
+mod arbitrary;
 mod coin_id;
 mod intention;
 mod pubkey;
@@ -13,8 +15,9 @@ pub mod exchange_interfaces;
 pub mod orchestrator_interfaces;
 
 pub use bitcoin;
-pub use coin_id::CoinId;
+pub use coin_id::{CoinId, CoinIdFilter};
 pub use exchange_interfaces::NewBlockInfo;
+#[cfg(feature = "ic-cdk")]
 pub use ic_cdk;
 pub use intention::*;
 pub use pubkey::Pubkey;
@@ -29,6 +32,16 @@ pub struct CoinBalance {
     pub value: u128,
 }
 
+impl CoinBalance {
+    /// Converts the BTC value to sats, returns an error if the coin isn't BTC or the value overflows `u64`.
+    pub fn as_sats(&self) -> Result<u64, String> {
+        if self.id != CoinId::btc() {
+            return Err(format!("{} is not BTC", self.id));
+        }
+        u64::try_from(self.value).map_err(|_| format!("{} sats overflows u64", self.value))
+    }
+}
+
 /// The CoinBalances struct is a collection of CoinBalance objects.
 #[derive(CandidType, Eq, PartialEq, Clone, Debug, Deserialize, Serialize, Default)]
 pub struct CoinBalances(Vec<CoinBalance>);
@@ -70,6 +83,26 @@ impl Utxo {
     pub fn outpoint(&self) -> String {
         format!("{}:{}", self.txid, self.vout)
     }
+
+    /// Returns the outpoint of the UTXO as `bitcoin::OutPoint`.
+    pub fn as_outpoint(&self) -> bitcoin::OutPoint {
+        bitcoin::OutPoint {
+            txid: self.txid.into(),
+            vout: self.vout,
+        }
+    }
+}
+
+/// Returns true if the UTXO is located at the outpoint.
+pub fn utxo_matches_outpoint(utxo: &Utxo, outpoint: &bitcoin::OutPoint) -> bool {
+    utxo.vout == outpoint.vout && Into::<bitcoin::Txid>::into(utxo.txid) == outpoint.txid
+}
+
+/// Finds the UTXO located at the outpoint, e.g. pairing the `Intention::pool_outpoints` with the pool UTXOs.
+pub fn find_utxo<'a>(utxos: &'a [Utxo], outpoint: &bitcoin::OutPoint) -> Option<&'a Utxo> {
+    utxos
+        .iter()
+        .find(|utxo| utxo_matches_outpoint(utxo, outpoint))
 }
 
 impl CoinBalances {
@@ -89,18 +122,26 @@ impl CoinBalances {
         self.0.iter()
     }
     //
+    /// Adds the coin to the balances, panics if the sum overflows `u128`.
+    /// Use `try_add_coin` to handle the overflow.
     pub fn add_coin(&mut self, coin: &CoinBalance) {
-        let mut found = false;
+        self.try_add_coin(coin).unwrap()
+    }
+    //
+    /// Adds the coin to the balances, returns an error and leaves the balances unchanged
+    /// if the sum overflows `u128`.
+    pub fn try_add_coin(&mut self, coin: &CoinBalance) -> Result<(), String> {
         for existing_coin in &mut self.0 {
             if existing_coin.id == coin.id {
-                existing_coin.value += coin.value;
-                found = true;
-                break;
+                existing_coin.value = existing_coin
+                    .value
+                    .checked_add(coin.value)
+                    .ok_or(format!("Balance of {} overflows", coin.id))?;
+                return Ok(());
             }
         }
-        if !found {
-            self.0.push(coin.clone());
-        }
+        self.0.push(coin.clone());
+        Ok(())
     }
     //
     pub fn subtract_coin(&mut self, coin: &CoinBalance) -> bool {
@@ -134,6 +175,141 @@ impl CoinBalances {
             self.add_coin(coin);
         }
     }
+    //
+    /// Returns the sum of both balances, or `None` if the balance of any coin overflows `u128`.
+    pub fn checked_add(&self, other: &CoinBalances) -> Option<CoinBalances> {
+        let mut sum = self.clone();
+        for coin in other.iter() {
+            sum.try_add_coin(coin).ok()?;
+        }
+        Some(sum)
+    }
+    //
+    /// Returns the balances minus `other`, or `None` if any coin of `other` exceeds its balance,
+    /// including the coins missing from `self`. Balances reaching zero are removed.
+    pub fn checked_sub(&self, other: &CoinBalances) -> Option<CoinBalances> {
+        let mut diff = self.clone();
+        for coin in other.iter().filter(|coin| coin.value > 0) {
+            if !diff.subtract_coin(coin) {
+                return None;
+            }
+        }
+        Some(diff)
+    }
+    //
+    /// Aggregates the coins of all the UTXOs into balances sorted by the coin id, zero balances
+    /// are omitted. The sats of the UTXOs are not included. Panics if a sum overflows `u128`.
+    pub fn from_utxos(utxos: &[Utxo]) -> Self {
+        let mut balances = Self::new();
+        for utxo in utxos.iter() {
+            balances.add_coins(&utxo.coins);
+        }
+        balances.remove_zero();
+        balances.0.sort();
+        balances
+    }
+    //
+    /// Keeps only the coins satisfying the predicate, e.g. pruning dust balances.
+    pub fn retain(&mut self, f: impl Fn(&CoinBalance) -> bool) {
+        self.0.retain(|coin| f(coin));
+    }
+    //
+    /// Removes the zero balances.
+    pub fn remove_zero(&mut self) {
+        self.retain(|coin| coin.value > 0);
+    }
+}
+
+impl CoinBalances {
+    fn encode_compact(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.0.len() * (CoinId::BYTE_LEN + 3));
+        for coin in self.0.iter() {
+            bytes.extend_from_slice(&coin.id.to_bytes());
+            let mut value = coin.value;
+            while value >> 7 > 0 {
+                bytes.push((value & 0x7f) as u8 | 0x80);
+                value >>= 7;
+            }
+            bytes.push(value as u8);
+        }
+        bytes
+    }
+
+    fn decode_compact(bytes: &[u8]) -> Result<Self, String> {
+        let mut balances = vec![];
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            if rest.len() < CoinId::BYTE_LEN {
+                return Err("Truncated coin id in CoinBalances".to_string());
+            }
+            let (id, tail) = rest.split_at(CoinId::BYTE_LEN);
+            let id = CoinId::from_bytes(id);
+            let mut value = 0u128;
+            let mut shift = 0;
+            let mut len = 0;
+            loop {
+                let byte = *tail
+                    .get(len)
+                    .ok_or("Truncated coin value in CoinBalances")?;
+                if shift > 126 || (shift == 126 && byte & 0x7c != 0) {
+                    return Err("Coin value in CoinBalances overflows u128".to_string());
+                }
+                value |= ((byte & 0x7f) as u128) << shift;
+                len += 1;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+                shift += 7;
+            }
+            balances.push(CoinBalance { id, value });
+            rest = &tail[len..];
+        }
+        Ok(Self(balances))
+    }
+}
+
+/// Each balance is stored as the 12 bytes of the coin id followed by the LEB128 value, so a rune
+/// balance usually takes 13 to 20 bytes instead of about 35 with the `block:tx` text and the `u128`
+/// written by bincode. Use `compact_coin_balances` to get the same encoding inside a pool state.
+impl ic_stable_structures::Storable for CoinBalances {
+    fn to_bytes(&self) -> alloc::borrow::Cow<'_, [u8]> {
+        alloc::borrow::Cow::Owned(self.encode_compact())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.encode_compact()
+    }
+
+    fn from_bytes(bytes: alloc::borrow::Cow<'_, [u8]>) -> Self {
+        Self::decode_compact(bytes.as_ref()).expect("failed to decode CoinBalances")
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+/// Serializes `CoinBalances` with the compact encoding of its `Storable`, for the fields of
+/// a pool state, e.g. `#[serde(with = "ree_types::compact_coin_balances")]`.
+///
+/// It changes the stored format of the field, the existing states must be migrated.
+pub mod compact_coin_balances {
+    use super::CoinBalances;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(balances: &CoinBalances, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&balances.encode_compact())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<CoinBalances, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let bytes = serde_bytes::ByteBuf::deserialize(deserializer)?;
+        CoinBalances::decode_compact(&bytes).map_err(serde::de::Error::custom)
+    }
 }
 
 #[cfg(test)]
@@ -382,4 +558,247 @@ mod tests {
 
         println!("Coin Balances: {:?}", balances);
     }
+
+    #[test]
+    fn test_coin_balances_from_utxos() {
+        let rune_a = CoinId::from_str("840106:129").unwrap();
+        let rune_b = CoinId::from_str("840000:3").unwrap();
+        let coin = |id: CoinId, value: u128| CoinBalance { id, value };
+        let utxo = |vout: u32, coins: Vec<CoinBalance>| Utxo {
+            txid: Txid::default(),
+            vout,
+            coins: CoinBalances(coins),
+            sats: 546,
+        };
+        let utxos = vec![
+            utxo(0, vec![coin(rune_a, 100), coin(rune_b, 0)]),
+            utxo(1, vec![]),
+            utxo(2, vec![coin(rune_b, 7), coin(rune_a, 20)]),
+        ];
+
+        let balances = CoinBalances::from_utxos(&utxos);
+        assert_eq!(balances.0, vec![coin(rune_b, 7), coin(rune_a, 120)]);
+        let mut reversed = utxos.clone();
+        reversed.reverse();
+        assert_eq!(CoinBalances::from_utxos(&reversed), balances);
+        assert!(CoinBalances::from_utxos(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_find_utxo() {
+        let txid =
+            Txid::from_str("4e3c4dc2b4c6b24c3e1d2d8ea6bdfa0e3b52bd1f7b4a35e6b02c3a1f2e5d6c7b")
+                .unwrap();
+        let utxos = vec![
+            Utxo::try_from(format!("{}:0", txid), CoinBalances::new(), 546).unwrap(),
+            Utxo::try_from(format!("{}:1", txid), CoinBalances::new(), 1000).unwrap(),
+        ];
+        let outpoint = utxos[1].as_outpoint();
+        assert_eq!(outpoint.to_string(), utxos[1].outpoint());
+        assert!(utxo_matches_outpoint(&utxos[1], &outpoint));
+        assert!(!utxo_matches_outpoint(&utxos[0], &outpoint));
+        assert_eq!(find_utxo(&utxos, &outpoint), Some(&utxos[1]));
+
+        let missing = bitcoin::OutPoint::new(txid.into(), 2);
+        assert_eq!(find_utxo(&utxos, &missing), None);
+    }
+
+    #[test]
+    fn test_coin_balance_as_sats() {
+        let btc = |value: u128| CoinBalance {
+            id: CoinId::btc(),
+            value,
+        };
+        assert_eq!(btc(546).as_sats(), Ok(546));
+        assert_eq!(btc(u64::MAX as u128).as_sats(), Ok(u64::MAX));
+        assert!(btc(u64::MAX as u128 + 1).as_sats().is_err());
+        let rune = CoinBalance {
+            id: CoinId::rune(840000, 846),
+            value: 1,
+        };
+        assert!(rune.as_sats().is_err());
+    }
+
+    #[test]
+    fn test_coin_balances_retain() {
+        let coin = |block: u64, value: u128| CoinBalance {
+            id: CoinId::rune(block, 1),
+            value,
+        };
+        let mut balances = CoinBalances(vec![coin(1, 0), coin(2, 5), coin(3, 0), coin(4, 1000)]);
+        balances.remove_zero();
+        assert_eq!(balances.0, vec![coin(2, 5), coin(4, 1000)]);
+
+        balances.retain(|coin| coin.value >= 10);
+        assert_eq!(balances.0, vec![coin(4, 1000)]);
+    }
+
+    #[test]
+    fn test_coin_balances_arithmetic() {
+        let rune = CoinId::rune(840000, 846);
+        let coin = |id: CoinId, value: u128| CoinBalance { id, value };
+        let before = CoinBalances(vec![coin(CoinId::btc(), 10_000), coin(rune, 500)]);
+        let spent = CoinBalances(vec![coin(rune, 500), coin(CoinId::btc(), 0)]);
+        let received = CoinBalances::single(coin(CoinId::btc(), 2_000));
+
+        let after = before
+            .checked_sub(&spent)
+            .and_then(|balances| balances.checked_add(&received))
+            .unwrap();
+        assert_eq!(after.0, vec![coin(CoinId::btc(), 12_000)]);
+        assert_eq!(before.value_of(&rune), 500);
+
+        assert_eq!(after.checked_sub(&spent), None);
+        assert_eq!(after.checked_sub(&after), Some(CoinBalances::new()));
+        assert_eq!(
+            after.checked_add(&CoinBalances::single(coin(CoinId::btc(), u128::MAX))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_compact_coin_balances() {
+        use ic_stable_structures::Storable;
+
+        let balances = CoinBalances(vec![
+            CoinBalance {
+                id: CoinId::btc(),
+                value: 0,
+            },
+            CoinBalance {
+                id: CoinId::from_str("840106:129").unwrap(),
+                value: 672_563,
+            },
+            CoinBalance {
+                id: CoinId::rune(u64::MAX, u32::MAX),
+                value: u128::MAX,
+            },
+        ]);
+        let bytes = balances.to_bytes();
+        assert_eq!(bytes.len(), 3 * CoinId::BYTE_LEN + 1 + 3 + 19);
+        assert!(bytes.len() < bincode::serialize(&balances).unwrap().len());
+        assert_eq!(CoinBalances::from_bytes(bytes), balances);
+        assert!(CoinBalances::from_bytes(CoinBalances::new().to_bytes()).is_empty());
+
+        let encoded = balances.encode_compact();
+        assert!(CoinBalances::decode_compact(&encoded[..CoinId::BYTE_LEN - 1]).is_err());
+        assert!(CoinBalances::decode_compact(&encoded[..encoded.len() - 1]).is_err());
+        let mut overflow = CoinId::btc().to_bytes();
+        overflow.extend_from_slice(&[0xff; 19]);
+        overflow.push(0x01);
+        assert!(CoinBalances::decode_compact(&overflow).is_err());
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct State {
+            #[serde(with = "compact_coin_balances")]
+            reserves: CoinBalances,
+        }
+        let state = State { reserves: balances };
+        let bytes = bincode::serialize(&state).unwrap();
+        assert_eq!(bincode::deserialize::<State>(&bytes).unwrap(), state);
+    }
+
+    #[test]
+    fn test_coin_balances_overflow() {
+        let mut balances = CoinBalances::single(CoinBalance {
+            id: CoinId::btc(),
+            value: u128::MAX,
+        });
+        let one = CoinBalance {
+            id: CoinId::btc(),
+            value: 1,
+        };
+        assert!(balances.try_add_coin(&one).is_err());
+        assert_eq!(balances.value_of(&CoinId::btc()), u128::MAX);
+        assert!(std::panic::catch_unwind(move || balances.add_coin(&one)).is_err());
+    }
+
+    #[test]
+    fn test_net_coin_flow() {
+        let rune = CoinId::from_str("840106:129").unwrap();
+        let coin = |id: CoinId, value: u128| CoinBalance { id, value };
+        let intention = |input_coins: Vec<InputCoin>, output_coins: Vec<OutputCoin>| Intention {
+            exchange_id: "RICH_SWAP".to_string(),
+            action: "swap".to_string(),
+            action_params: "".to_string(),
+            pool_address: "pool".to_string(),
+            nonce: 1,
+            pool_utxo_spent: vec![],
+            pool_utxo_received: vec![],
+            input_coins,
+            output_coins,
+        };
+        let set = IntentionSet {
+            initiator_address: "alice".to_string(),
+            tx_fee_in_sats: 0,
+            intentions: vec![
+                intention(
+                    vec![InputCoin {
+                        from: "alice".to_string(),
+                        coin: coin(CoinId::btc(), 1_000),
+                    }],
+                    vec![OutputCoin {
+                        to: "alice".to_string(),
+                        coin: coin(rune, 500),
+                    }],
+                ),
+                intention(
+                    vec![InputCoin {
+                        from: "alice".to_string(),
+                        coin: coin(rune, 500),
+                    }],
+                    vec![OutputCoin {
+                        to: "bob".to_string(),
+                        coin: coin(CoinId::btc(), 900),
+                    }],
+                ),
+            ],
+        };
+
+        let flow = set.net_coin_flow();
+        assert_eq!(flow.len(), 2);
+        assert_eq!(flow["alice"][&CoinId::btc()], -1_000);
+        assert_eq!(flow["alice"][&rune], 0);
+        assert_eq!(flow["bob"][&CoinId::btc()], 900);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_intention_set_from_json() {
+        let txid = "b2f1c1ab4c1ec1be8d8a3fc6c5c7e08ec25a1bdbfbbd7e3ea7c1e3c8d5ec1e0a";
+        let intention_set = |exchange_id: &str, outpoint: String, coin_id: &str| {
+            format!(
+                r#"{{"initiator_address":"alice","tx_fee_in_sats":360,"intentions":[{{
+                    "exchange_id":"{exchange_id}","action":"swap","action_params":"",
+                    "pool_address":"pool","nonce":1,"pool_utxo_spent":["{outpoint}"],
+                    "pool_utxo_received":[],"input_coins":[{{"from":"alice",
+                    "coin":{{"id":"{coin_id}","value":1000}}}}],"output_coins":[]}}]}}"#
+            )
+        };
+        let set = IntentionSet::from_json(&intention_set("RICH_SWAP", format!("{txid}:1"), "0:0"))
+            .unwrap();
+        assert_eq!(set.intentions[0].pool_outpoints().unwrap()[0].vout, 1);
+
+        let err = |json: String| IntentionSet::from_json(&json).unwrap_err();
+        assert!(err("{}".to_string()).starts_with("Invalid intention set"));
+        assert!(
+            err(intention_set("RICH_SWAP", format!("{txid}:1"), "btc"))
+                .starts_with("Invalid intention set")
+        );
+        assert_eq!(
+            err(intention_set("", format!("{txid}:1"), "0:0")),
+            "Intention 0: empty exchange_id"
+        );
+        assert!(
+            err(intention_set("RICH_SWAP", txid.to_string(), "0:0")).contains("invalid outpoint")
+        );
+        assert!(
+            err(intention_set("RICH_SWAP", format!("{txid}:x"), "0:0"))
+                .contains("invalid outpoint")
+        );
+        assert!(
+            err(intention_set("RICH_SWAP", format!("{txid}:1"), "840000:0"))
+                .contains("tx out of range")
+        );
+    }
 }
@@ -1,7 +1,6 @@
 use crate::IntentionSet;
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
 
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct RegisterExchangeArgs {
@@ -26,17 +25,19 @@ pub const TESTNET4_ORCHESTRATOR_CANISTER: &'static str = "hvyp5-5yaaa-aaaao-qjxh
 // mainnet orchestrator
 pub const ORCHESTRATOR_CANISTER: &'static str = "kqs64-paaaa-aaaar-qamza-cai";
 
+#[cfg(feature = "ic-cdk")]
 #[doc(hidden)]
 pub fn ensure_testnet4_orchestrator() -> Result<(), String> {
-    let o = Principal::from_str(TESTNET4_ORCHESTRATOR_CANISTER).expect("is valid principal; qed");
+    let o = Principal::from_text(TESTNET4_ORCHESTRATOR_CANISTER).expect("is valid principal; qed");
     (o == ic_cdk::api::msg_caller())
         .then(|| ())
         .ok_or("Access denied".to_string())
 }
 
+#[cfg(feature = "ic-cdk")]
 #[doc(hidden)]
 pub fn ensure_orchestrator() -> Result<(), String> {
-    let o = Principal::from_str(ORCHESTRATOR_CANISTER).expect("is valid principal; qed");
+    let o = Principal::from_text(ORCHESTRATOR_CANISTER).expect("is valid principal; qed");
     (o == ic_cdk::api::msg_caller())
         .then(|| ())
         .ok_or("Access denied".to_string())
@@ -21,6 +21,16 @@ impl Pubkey {
         &self.0
     }
 
+    /// Returns `true` if the key is deserialized from a x-only public key, i.e. prefixed by `0x00`.
+    pub fn is_x_only(&self) -> bool {
+        self.0[0] == 0x00
+    }
+
+    /// Returns the 32-bytes x-only serialization of the key.
+    pub fn to_x_only_bytes(&self) -> [u8; 32] {
+        self.0[1..].try_into().expect("The inner is 33 bytes")
+    }
+
     pub fn to_x_only_public_key(&self) -> bitcoin::XOnlyPublicKey {
         bitcoin::XOnlyPublicKey::from_slice(&self.0[1..]).expect("The inner is 33 bytes")
     }
@@ -151,4 +161,26 @@ mod tests {
                 .0
         );
     }
+
+    #[test]
+    fn test_x_only_accessors() {
+        let x = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        for prefix in ["02", "03"] {
+            let hex = format!("{}{}", prefix, x);
+            let pubkey = Pubkey::from_str(&hex).unwrap();
+            assert!(!pubkey.is_x_only());
+            assert_eq!(pubkey.to_string(), hex);
+            assert_eq!(hex::encode(pubkey.to_x_only_bytes()), x);
+            assert!(pubkey.to_public_key().is_ok());
+        }
+
+        let pubkey = Pubkey::from_str(x).unwrap();
+        assert!(pubkey.is_x_only());
+        assert_eq!(pubkey.to_string(), x);
+        assert_eq!(hex::encode(pubkey.to_x_only_bytes()), x);
+        assert_eq!(
+            pubkey.to_x_only_bytes(),
+            pubkey.to_x_only_public_key().serialize()
+        );
+    }
 }